@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+/// Stops running operation after `threshold` consecutive failures. After `cooldown` a single
+/// attempt is allowed (half-open state): success closes the circuit, failure opens it again.
+/// Other callers are refused while the attempt runs
+#[derive(Default)]
+pub struct CircuitBreaker {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// Start of the half-open attempt which hasn't reported its result yet
+    probe_started_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Checks whether operation may run now. In half-open state the first caller gets the
+    /// attempt, so it must report its result with `record_success` or `record_failure`
+    pub fn try_acquire(&mut self, cooldown: Duration, now: Instant) -> bool {
+        let Some(opened_at) = self.opened_at else {
+            return true;
+        };
+        if now.duration_since(opened_at) < cooldown {
+            return false;
+        }
+        // Attempt which never reported back, e.g. cancelled one, is abandoned after cooldown
+        if self
+            .probe_started_at
+            .is_some_and(|started_at| now.duration_since(started_at) < cooldown)
+        {
+            return false;
+        }
+        self.probe_started_at = Some(now);
+        true
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+        self.probe_started_at = None;
+    }
+
+    /// `now` is when the operation completed, so the cooldown doesn't include its duration
+    pub fn record_failure(&mut self, threshold: u32, now: Instant) {
+        self.consecutive_failures += 1;
+        self.probe_started_at = None;
+        if self.consecutive_failures >= threshold {
+            self.opened_at = Some(now);
+        }
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let cooldown = Duration::from_secs(60);
+    let now = Instant::now();
+    let mut breaker = CircuitBreaker::default();
+
+    breaker.record_failure(3, now);
+    breaker.record_failure(3, now);
+    assert!(breaker.try_acquire(cooldown, now));
+    breaker.record_failure(3, now);
+    assert!(!breaker.try_acquire(cooldown, now));
+    assert!(!breaker.try_acquire(cooldown, now + Duration::from_secs(59)));
+
+    // Half-open: failed attempt opens the circuit again
+    let now = now + cooldown;
+    assert!(breaker.try_acquire(cooldown, now));
+    breaker.record_failure(3, now);
+    assert!(!breaker.try_acquire(cooldown, now + Duration::from_secs(1)));
+
+    // Half-open: successful attempt closes the circuit
+    let now = now + cooldown;
+    assert!(breaker.try_acquire(cooldown, now));
+    breaker.record_success();
+    breaker.record_failure(3, now);
+    assert!(breaker.try_acquire(cooldown, now));
+}
+
+#[test]
+fn test_circuit_breaker_single_probe() {
+    let cooldown = Duration::from_secs(60);
+    let now = Instant::now();
+    let mut breaker = CircuitBreaker::default();
+    breaker.record_failure(1, now);
+
+    let now = now + cooldown;
+    assert!(breaker.try_acquire(cooldown, now));
+    assert!(!breaker.try_acquire(cooldown, now));
+    assert!(!breaker.try_acquire(cooldown, now + Duration::from_secs(59)));
+
+    // Attempt never completed
+    let now = now + cooldown;
+    assert!(breaker.try_acquire(cooldown, now));
+
+    // Slow attempt fails, cooldown starts when it completes
+    let now = now + Duration::from_secs(90);
+    breaker.record_failure(1, now);
+    assert!(!breaker.try_acquire(cooldown, now + Duration::from_secs(1)));
+    assert!(breaker.try_acquire(cooldown, now + cooldown));
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Result of operation along with the time it completed
+type Slot<T, E> = Arc<tokio::sync::OnceCell<(Result<T, E>, Instant)>>;
+
+/// Runs an operation once for concurrent callers with the same key, sharing its result.
+/// Successful result is also reused for `ttl` after completion, so callers retrying right
+/// after the first one get it too. Errors are shared only with concurrent callers, the next
+/// call runs operation again
+pub struct Coalescer<K, T, E> {
+    ttl: Duration,
+    slots: Mutex<HashMap<K, Slot<T, E>>>,
+}
+
+impl<K: Eq + Hash + Clone, T: Clone, E: Clone> Coalescer<K, T, E> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            slots: Default::default(),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<T, E>>,
+    {
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            let now = Instant::now();
+            slots.retain(|_, slot| {
+                slot.get()
+                    .is_none_or(|(_, completed)| now.duration_since(*completed) < self.ttl)
+            });
+            slots.entry(key.clone()).or_default().clone()
+        };
+        let (value, _) = slot
+            .get_or_init(|| async { (f().await, Instant::now()) })
+            .await;
+        if value.is_err() {
+            let mut slots = self.slots.lock().unwrap();
+            // Slot may have been replaced already by a call made after this one completed
+            if slots.get(&key).is_some_and(|v| Arc::ptr_eq(v, &slot)) {
+                slots.remove(&key);
+            }
+        }
+        value.clone()
+    }
+
+    /// Drops result of `key`, so the next call runs operation again
+    pub fn forget(&self, key: &K) {
+        self.slots.lock().unwrap().remove(key);
+    }
+}
+
+#[actix_web::test]
+async fn test_concurrent_calls_coalesced() {
+    let (ipset, log) = crate::ipset::fake_ipset("coalesce", "exit 0");
+    let ipset = crate::ipset::IPSet::with_command("acl", &ipset);
+    let coalescer = Coalescer::new(Duration::from_secs(60));
+    let register = |ip: &'static str| {
+        coalescer.run(ip, || async {
+            // Let the other call start before this one completes
+            tokio::task::yield_now().await;
+            ipset.add(ip, None).await.map_err(|err| err.to_string())
+        })
+    };
+
+    let (first, second) = tokio::join!(register("10.11.2.10"), register("10.11.2.10"));
+    assert!(first.is_ok() && second.is_ok());
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "add acl 10.11.2.10\n"
+    );
+
+    assert!(register("10.11.2.11").await.is_ok());
+    coalescer.forget(&"10.11.2.10");
+    assert!(register("10.11.2.10").await.is_ok());
+    assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 3);
+}
+
+#[actix_web::test]
+async fn test_results_expire() {
+    let coalescer = Coalescer::<_, _, ()>::new(Duration::ZERO);
+    assert_eq!(coalescer.run("key", || async { Ok(1) }).await, Ok(1));
+    assert_eq!(coalescer.run("key", || async { Ok(2) }).await, Ok(2));
+    assert_eq!(coalescer.slots.lock().unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn test_errors_not_reused() {
+    let coalescer = Coalescer::new(Duration::from_secs(60));
+    let run = |r: Result<u32, &'static str>| {
+        coalescer.run("key", move || async move {
+            tokio::task::yield_now().await;
+            r
+        })
+    };
+
+    let (first, second) = tokio::join!(run(Err("busy")), run(Ok(1)));
+    assert_eq!((first, second), (Err("busy"), Err("busy")));
+    assert!(coalescer.slots.lock().unwrap().is_empty());
+    assert_eq!(run(Ok(2)).await, Ok(2));
+    assert_eq!(run(Err("busy")).await, Ok(2));
+}
@@ -0,0 +1,377 @@
+use serde::Serialize;
+use slog_scope::warn;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+/// Maximum number of kept bytes of command stdout and stderr
+const MAX_OUTPUT_SIZE: usize = 4096;
+
+fn truncate_output(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    if output.len() <= MAX_OUTPUT_SIZE {
+        return output.to_string();
+    }
+    let mut end = MAX_OUTPUT_SIZE;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!(
+        "{}... ({} bytes truncated)",
+        &output[..end],
+        output.len() - end
+    )
+}
+
+/// Default limit of captured bytes of command stdout and stderr each
+pub const DEFAULT_MAX_CAPTURED_OUTPUT: usize = 1024 * 1024;
+
+/// Reads at most `max_size` bytes of command output, draining and dropping the rest so the
+/// command isn't blocked on a full pipe
+async fn read_capped<R>(reader: Option<R>, max_size: usize, name: &str) -> std::io::Result<Vec<u8>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let Some(mut reader) = reader else {
+        return Ok(Vec::new());
+    };
+    let mut output = Vec::new();
+    (&mut reader)
+        .take(max_size as u64)
+        .read_to_end(&mut output)
+        .await?;
+    let dropped = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+    if dropped > 0 {
+        warn!("Command {name} exceeded {max_size} bytes, {dropped} bytes dropped");
+        output.extend_from_slice(format!("... ({dropped} bytes truncated)").as_bytes());
+    }
+    Ok(output)
+}
+
+/// Compiles `command_allowlist` patterns, each matching whole command
+pub fn allowlist_regex(patterns: &[String]) -> Result<regex::RegexSet, regex::Error> {
+    // Compile patterns separately first, so unbalanced one can't escape anchoring
+    for pattern in patterns {
+        regex::Regex::new(pattern)?;
+    }
+    regex::RegexSet::new(patterns.iter().map(|v| format!("^(?:{v})$")))
+}
+
+/// Whether `command` has unquoted command separators, redirects or substitutions, which
+/// would run something besides the allowlisted command
+fn has_shell_operators(command: &str) -> bool {
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                chars.next();
+            }
+            (Some('"'), '"') => quote = None,
+            (_, '$' | '`') => return true,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ';' | '&' | '|' | '<' | '>' | '(' | ')' | '\n') => return true,
+            (None, _) => {}
+        }
+    }
+    quote.is_some()
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CommandRecord {
+    pub command: String,
+    /// `None` if the command was killed by signal or failed to start
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+/// Last invocations of external commands, kept for diagnostics. Oldest records are evicted
+/// once `capacity` is reached, capacity 0 disables the log
+#[derive(Clone)]
+pub struct CommandLog {
+    capacity: usize,
+    max_captured_output: usize,
+    /// Only matching commands may run if set
+    allowlist: Option<regex::RegexSet>,
+    records: Arc<Mutex<VecDeque<CommandRecord>>>,
+}
+
+impl Default for CommandLog {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl CommandLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            max_captured_output: DEFAULT_MAX_CAPTURED_OUTPUT,
+            allowlist: None,
+            records: Default::default(),
+        }
+    }
+
+    /// Changes capacity keeping records, so clones made before share them still
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn with_max_captured_output(mut self, max_captured_output: usize) -> Self {
+        self.max_captured_output = max_captured_output;
+        self
+    }
+
+    /// Restricts commands to those matching any of `patterns`. Unrestricted if `None`
+    pub fn with_allowlist(mut self, patterns: Option<&[String]>) -> anyhow::Result<Self> {
+        self.allowlist = patterns.map(allowlist_regex).transpose()?;
+        Ok(self)
+    }
+
+    /// Same as `Command::output`, but captures at most `max_captured_output` bytes of stdout
+    /// and stderr each, marking truncated output. `command_line` is the command as configured,
+    /// it is checked against allowlist and recorded to the log along with the output
+    pub async fn run(
+        &self,
+        command_line: &str,
+        command: &mut tokio::process::Command,
+    ) -> std::io::Result<std::process::Output> {
+        self.run_with_input(command_line, command, None).await
+    }
+
+    /// Same as `run`, passing `input` to command stdin
+    pub async fn run_with_stdin(
+        &self,
+        command_line: &str,
+        command: &mut tokio::process::Command,
+        input: &[u8],
+    ) -> std::io::Result<std::process::Output> {
+        self.run_with_input(command_line, command, Some(input))
+            .await
+    }
+
+    async fn run_with_input(
+        &self,
+        command_line: &str,
+        command: &mut tokio::process::Command,
+        input: Option<&[u8]>,
+    ) -> std::io::Result<std::process::Output> {
+        let output = match self.check_allowed(command_line) {
+            Ok(()) => self.capture(command, input).await,
+            Err(err) => Err(err),
+        };
+        self.record(command_line, &output);
+        output
+    }
+
+    fn check_allowed(&self, command_line: &str) -> std::io::Result<()> {
+        let command = command_line.trim();
+        match &self.allowlist {
+            Some(allowlist) if has_shell_operators(command) || !allowlist.is_match(command) => {
+                warn!("Refusing to run command not in command_allowlist: {command_line}");
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "command is not in command_allowlist",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn capture(
+        &self,
+        command: &mut tokio::process::Command,
+        input: Option<&[u8]>,
+    ) -> std::io::Result<std::process::Output> {
+        use tokio::io::AsyncWriteExt;
+
+        let name = command.as_std().get_program().to_string_lossy().to_string();
+        let stdin = match input {
+            Some(_) => std::process::Stdio::piped(),
+            None => std::process::Stdio::null(),
+        };
+        let mut child = command
+            .stdin(stdin)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        let (stdin, stdout, stderr) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take());
+        let write_stdin = async {
+            if let (Some(mut stdin), Some(input)) = (stdin, input) {
+                stdin.write_all(input).await?;
+            }
+            Ok(())
+        };
+        let (stdout, stderr, (), status) = tokio::try_join!(
+            read_capped(stdout, self.max_captured_output, &name),
+            read_capped(stderr, self.max_captured_output, &name),
+            write_stdin,
+            child.wait(),
+        )?;
+        Ok(std::process::Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    pub fn record(&self, command: &str, output: &std::io::Result<std::process::Output>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let (exit_code, stdout, stderr) = match output {
+            Ok(output) => (
+                output.status.code(),
+                truncate_output(&output.stdout),
+                truncate_output(&output.stderr),
+            ),
+            Err(err) => (None, String::new(), format!("Failed to run command: {err}")),
+        };
+
+        let mut records = self.records.lock().unwrap();
+        while records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(CommandRecord {
+            command: command.to_string(),
+            exit_code,
+            stdout,
+            stderr,
+            time: chrono::Utc::now(),
+        });
+    }
+
+    /// Returns records from oldest to newest
+    pub fn records(&self) -> Vec<CommandRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+fn test_output(code: i32, stdout: &str) -> std::io::Result<std::process::Output> {
+    use std::os::unix::process::ExitStatusExt;
+
+    Ok(std::process::Output {
+        status: std::process::ExitStatus::from_raw(code << 8),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: vec![],
+    })
+}
+
+#[test]
+fn test_eviction() {
+    let log = CommandLog::new(2);
+    log.record("first", &test_output(0, "1"));
+    log.record("second", &test_output(1, "2"));
+    log.record("third", &test_output(0, "3"));
+
+    let records = log.records();
+    let commands = records
+        .iter()
+        .map(|v| v.command.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(commands, ["second", "third"]);
+    assert_eq!(records[0].exit_code, Some(1));
+    assert_eq!(records[1].stdout, "3");
+
+    let log = CommandLog::new(0);
+    log.record("first", &test_output(0, "1"));
+    assert!(log.records().is_empty());
+}
+
+#[test]
+fn test_output_truncated() {
+    let log = CommandLog::new(1);
+    log.record("big", &test_output(0, &"ы".repeat(MAX_OUTPUT_SIZE)));
+    assert!(log.records()[0]
+        .stdout
+        .ends_with(&format!("... ({} bytes truncated)", MAX_OUTPUT_SIZE)));
+}
+
+#[actix_web::test]
+async fn test_captured_output_capped() {
+    let log = CommandLog::new(0).with_max_captured_output(10);
+    let command = "head -c 100000 /dev/zero | tr '\\0' x; echo err >&2";
+    let output = log
+        .run(
+            command,
+            tokio::process::Command::new("sh").args(["-c", command]),
+        )
+        .await
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "xxxxxxxxxx... (99990 bytes truncated)"
+    );
+    assert_eq!(output.stderr, b"err\n");
+}
+
+#[actix_web::test]
+async fn test_allowlist() {
+    let log = CommandLog::new(10)
+        .with_allowlist(Some(&["echo allowed( .*)?".to_string()]))
+        .unwrap();
+    let log = &log;
+    let run = |command: &'static str| async move {
+        log.run(
+            command,
+            tokio::process::Command::new("sh").args(["-c", command]),
+        )
+        .await
+    };
+
+    assert_eq!(run("echo allowed").await.unwrap().stdout, b"allowed\n");
+    let err = run("echo not allowed").await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert!(run("touch /tmp/x; echo allowed").await.is_err());
+    assert!(run("echo allowed; touch /tmp/x").await.is_err());
+    assert!(run("echo allowed $(touch /tmp/x)").await.is_err());
+    assert_eq!(
+        run("echo allowed 'a; b' \"c|d\"").await.unwrap().stdout,
+        b"allowed a; b c|d\n"
+    );
+
+    let records = log.records();
+    assert_eq!(records.len(), 6);
+    assert_eq!(records[1].command, "echo not allowed");
+    assert_eq!(
+        records[1].stderr,
+        "Failed to run command: command is not in command_allowlist"
+    );
+
+    assert!(CommandLog::default()
+        .with_allowlist(Some(&["(".to_string()]))
+        .is_err());
+    assert!(CommandLog::default()
+        .with_allowlist(Some(&["echo)|(.*".to_string()]))
+        .is_err());
+}
+
+#[test]
+fn test_has_shell_operators() {
+    assert!(!has_shell_operators("/usr/local/bin/speedtest --json"));
+    assert!(!has_shell_operators(
+        r#"ssh ratzek-services@10.11.1.1 '/interface disable lte1; delay 10'"#
+    ));
+    assert!(!has_shell_operators(
+        r#"ssh host '/interface lte at-chat lte1 input="AT+CUSD=1,\"*100#\",15"'"#
+    ));
+    assert!(!has_shell_operators(r"echo a\;b"));
+    assert!(has_shell_operators("ssh host 'uptime'; rm -rf /"));
+    assert!(has_shell_operators("ssh host uptime > /tmp/out"));
+    assert!(has_shell_operators("ssh host \"$(cat /etc/passwd)\""));
+    assert!(has_shell_operators("ssh host 'uptime"));
+    assert!(has_shell_operators("ssh host uptime\nrm -rf /"));
+}
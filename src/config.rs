@@ -1,8 +1,11 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, net::IpAddr};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, Serialize, Deserialize, clap::ValueEnum)]
 pub enum LogLevel {
     Critical,
     Error,
@@ -12,6 +15,19 @@ pub enum LogLevel {
     Trace,
 }
 
+impl LogLevel {
+    /// Same level as `RUST_LOG` directive of env logger
+    pub fn filter_directive(&self) -> &'static str {
+        match self {
+            LogLevel::Critical | LogLevel::Error => "error",
+            LogLevel::Warning => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
 impl From<LogLevel> for slog::Level {
     fn from(level: LogLevel) -> Self {
         match level {
@@ -25,16 +41,217 @@ fn from(level: LogLevel) -> Self {
     }
 }
 
+/// Unit of `bytes_unlimited_limit`, binary multiples of a byte
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum LimitUnit {
+    #[default]
+    Bytes,
+    KiB,
+    MiB,
+    GiB,
+}
+
+impl LimitUnit {
+    pub fn to_bytes(self, value: usize) -> usize {
+        let multiplier: usize = match self {
+            LimitUnit::Bytes => 1,
+            LimitUnit::KiB => 1 << 10,
+            LimitUnit::MiB => 1 << 20,
+            LimitUnit::GiB => 1 << 30,
+        };
+        value.saturating_mul(multiplier)
+    }
+}
+
+/// Settings of a single client MAC, overriding global ones
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MacOverride {
+    /// In `limit_unit`, same as global `bytes_unlimited_limit`
+    #[serde(default)]
+    pub bytes_unlimited_limit: Option<usize>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SpeedTest {
     pub speedtest_cli_path: std::path::PathBuf,
+    /// Alternative CLI with the same output format, run if `speedtest_cli_path` fails
+    #[serde(default)]
+    pub fallback_speedtest_cli_path: Option<std::path::PathBuf>,
     pub crontab: String,
+    /// Numeric ID of speedtest server. Picked automatically by CLI if not set
+    #[serde(default)]
+    pub server_id: Option<String>,
+    /// Source IP to measure specific uplink
+    #[serde(default)]
+    pub source_ip: Option<IpAddr>,
+    /// Attempts to run speedtest, the interval doubles after each failed one
+    #[serde(default = "default_speedtest_retry_count")]
+    pub retry_count: u32,
+    #[serde(default = "default_speedtest_retry_interval", with = "humantime_serde")]
+    pub retry_interval: std::time::Duration,
+}
+
+fn default_acl_size_alert_ratio() -> f64 {
+    0.9
+}
+
+fn default_max_command_output() -> usize {
+    crate::command_log::DEFAULT_MAX_CAPTURED_OUTPUT
+}
+
+fn default_speedtest_retry_count() -> u32 {
+    1
+}
+
+fn default_speedtest_retry_interval() -> std::time::Duration {
+    std::time::Duration::from_secs(30)
+}
+
+impl SpeedTest {
+    fn validate(&self) -> Result<()> {
+        if let Some(server_id) = &self.server_id {
+            if server_id.is_empty() || !server_id.chars().all(|v| v.is_ascii_digit()) {
+                anyhow::bail!("speedtest.server_id must be numeric, got {:?}", server_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// (De)serializes regex as its pattern, so invalid patterns fail config loading
+mod regex_serde {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        regex: &regex::Regex,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(regex.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<regex::Regex, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        regex::Regex::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Rule assigning device category to DHCP leases with matching `vendor_class_identifier`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceCategory {
+    #[serde(with = "regex_serde")]
+    pub pattern: regex::Regex,
+    pub category: String,
+}
+
+/// Daily time window, wraps over midnight if `end` is before `start`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TimeWindow {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Ping {
     pub server: IpAddr,
     pub crontab: String,
+    /// Source IP of ICMP socket
+    #[serde(default)]
+    pub source_ip: Option<IpAddr>,
+    /// Interface to bind ICMP socket to
+    #[serde(default)]
+    pub bind_interface: Option<String>,
+    /// URL requested to check availability if ICMP socket can't be created due to missing
+    /// permissions. Any successful response means network is available
+    #[serde(default)]
+    pub http_fallback_url: Option<String>,
+    /// Number of consecutive failed runs before network is reported unavailable
+    #[serde(default = "default_ping_threshold")]
+    pub failure_threshold: u32,
+    /// Number of consecutive successful runs before network is reported available again
+    #[serde(default = "default_ping_threshold")]
+    pub success_threshold: u32,
+    /// Run when network becomes unavailable, e.g. to restart modem. Commands with shell
+    /// syntax are run by `Config::shell()`
+    #[serde(default)]
+    pub on_internet_down_command: Option<String>,
+    /// `on_internet_down_command` is killed if it runs longer
+    #[serde(default = "default_on_internet_down_timeout", with = "humantime_serde")]
+    pub on_internet_down_timeout: std::time::Duration,
+}
+
+fn default_ping_threshold() -> u32 {
+    1
+}
+
+fn default_on_internet_down_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(60)
+}
+
+fn default_client_ip_header() -> String {
+    "x-real-ip".to_string()
+}
+
+fn default_ipset_concurrency() -> usize {
+    crate::ipset::DEFAULT_CONCURRENCY_LIMIT
+}
+
+fn default_http_keep_alive() -> std::time::Duration {
+    std::time::Duration::from_secs(5)
+}
+
+fn default_http_client_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(5)
+}
+
+fn default_metrics_prefix() -> String {
+    "ratzek".to_string()
+}
+
+/// Checks name against Prometheus metric name rules: `[a-zA-Z_:][a-zA-Z0-9_:]*`
+fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' || first == ':' => {}
+        _ => return false,
+    }
+    chars.all(|v| v.is_ascii_alphanumeric() || v == '_' || v == ':')
+}
+
+/// Labels the backend sets on its own metrics, static ones would clash with them
+const RESERVED_LABEL_NAMES: [&str; 5] = ["set", "mac", "endpoint", "status", "le"];
+
+/// Label names starting with `__` are reserved for Prometheus itself
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {}
+        _ => return false,
+    }
+    !name.starts_with("__") && chars.all(|v| v.is_ascii_alphanumeric() || v == '_')
+}
+
+fn default_blacklist_message() -> String {
+    "client is blacklisted".to_string()
+}
+
+fn default_expired_status_window() -> std::time::Duration {
+    std::time::Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_client_history_limit() -> usize {
+    20
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -43,13 +260,68 @@ pub struct Config {
     pub ipset_shaper_name: String,
     pub ipset_acl_name: String,
     pub ipset_no_shape_name: String,
+    /// `maxelem` of ACL ipset, and of its IPv6 counterpart if configured. Registrations fail
+    /// once the set is full, so an alert is sent when it is `acl_size_alert_ratio` full
+    #[serde(default)]
+    pub max_acl_size: Option<usize>,
+    #[serde(default = "default_acl_size_alert_ratio")]
+    pub acl_size_alert_ratio: f64,
+    /// Sets for IPv6 clients, IPv4 ones are used if not set
+    #[serde(default)]
+    pub ipset_acl_name_v6: Option<String>,
+    #[serde(default)]
+    pub ipset_shaper_name_v6: Option<String>,
+    #[serde(default)]
+    pub ipset_no_shape_name_v6: Option<String>,
+    /// host:port to listen on. Bare port or `[::]:port` listens dual-stack, accepting both
+    /// IPv4 and IPv6 clients, while `0.0.0.0:port` accepts IPv4 clients only
     pub http_listen: String,
+    /// How long idle keep-alive connection is kept open, 0 disables keep-alive. Portal polls
+    /// are short and infrequent, so long keep-alive only ties up connections on small routers
+    #[serde(with = "humantime_serde", default = "default_http_keep_alive")]
+    pub http_keep_alive: std::time::Duration,
+    /// Time for client to send request headers before connection is closed with 408
+    #[serde(with = "humantime_serde", default = "default_http_client_timeout")]
+    pub http_client_timeout: std::time::Duration,
+    /// Traffic a client may use before being shaped, in `limit_unit`. Compared against
+    /// `bytes` counter of shaper ipset entry, so the set must be created with `counters`
     pub bytes_unlimited_limit: usize,
+    #[serde(default)]
+    pub limit_unit: LimitUnit,
+    /// Per client settings keyed by MAC, e.g. higher limit for VIP clients
+    #[serde(default)]
+    pub mac_overrides: HashMap<String, MacOverride>,
     pub dhcpd_leases: std::path::PathBuf,
+    /// Categories of DHCP leases by vendor class, first matching rule wins
+    #[serde(default)]
+    pub device_categories: Vec<DeviceCategory>,
     #[serde(default)]
     pub blacklisted_macs: Vec<String>,
+    /// Message of blacklisted registration response. `{contact}` is replaced with
+    /// `support_contact`
+    #[serde(default = "default_blacklist_message")]
+    pub blacklist_message: String,
+    /// Contact for users to reach support, e.g. phone number
+    #[serde(default)]
+    pub support_contact: Option<String>,
     #[serde(default)]
     pub no_shaping_ips: HashSet<String>,
+    /// Add `no_shaping_ips` clients to ACL and no_shape sets as soon as they request status,
+    /// without explicit registration
+    #[serde(default)]
+    pub auto_register_whitelist: bool,
+    /// Refuse registrations with 503 `upstream_unavailable` while ping reports wide network
+    /// down. Registrations are allowed while network state is unknown
+    #[serde(default)]
+    pub refuse_register_when_down: bool,
+    /// Schedule of re-adding `no_shaping_ips` to ACL and no_shape sets with fresh timeout,
+    /// so they stay whitelisted without polling. Disabled if not set
+    #[serde(default)]
+    pub whitelist_refresh_crontab: Option<String>,
+    /// Ranges of static IPs which clients without DHCP lease are accepted from. Such clients
+    /// are identified by IP only
+    #[serde(default)]
+    pub allow_unknown_static: Vec<ipnet::IpNet>,
     pub no_shaping_timeout: u64,
     pub shaping_timeout: u64,
     pub speedtest: SpeedTest,
@@ -59,20 +331,690 @@ pub struct Config {
     #[serde(default)]
     pub mobile_provider: Option<crate::mobile_provider::MobileProvider>,
     pub persistent_state_path: std::path::PathBuf,
+    /// Number of registrations kept per client MAC
+    #[serde(default = "default_client_history_limit")]
+    pub client_history_limit: usize,
+    /// Registrations older than this are dropped from client history by maintenance job
+    #[serde(default, with = "humantime_serde")]
+    pub client_history_max_age: Option<std::time::Duration>,
+    /// Clients registered within this window but missing from ACL are reported as `Expired`
+    /// rather than `Inactive`, unless they deregistered themselves. Only MAC clients have
+    /// registration history
+    #[serde(with = "humantime_serde", default = "default_expired_status_window")]
+    pub expired_status_window: std::time::Duration,
+    /// Schedule of persistent state cleanup, pruning client history and telegram queue.
+    /// Disabled if not set
+    #[serde(default)]
+    pub maintenance_crontab: Option<String>,
+    /// Additional ipsets which members are counted in metrics
+    #[serde(default)]
+    pub extra_metric_ipsets: Vec<String>,
+    /// Log request and response bodies at debug level
+    #[serde(default)]
+    pub debug_log_bodies: bool,
+    /// IANA timezone for user-facing timestamps. System local timezone if not set
+    #[serde(default)]
+    pub timezone: Option<chrono_tz::Tz>,
+    /// Create ipsets at startup if they don't exist
+    #[serde(default)]
+    pub auto_create_ipsets: Option<crate::ipset::AutoCreate>,
+    /// Header with real client IP set by reverse proxy
+    #[serde(default = "default_client_ip_header")]
+    pub client_ip_header: String,
+    /// Proxies allowed to set `client_ip_header`. Header is trusted from any peer if empty
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Key for admin endpoints passed in `X-Api-Key` header. Admin endpoints are disabled if not set
+    #[serde(default)]
+    pub admin_api_key: Option<String>,
+    /// File with admin API key. Takes precedence over `admin_api_key`
+    #[serde(default)]
+    pub admin_api_key_file: Option<std::path::PathBuf>,
+    /// Count abandoned DHCP leases as free in summary and metrics. They are still reported
+    /// separately as abandoned
+    #[serde(default)]
+    pub abandoned_as_free: bool,
+    /// Backup persistent state on every write
+    #[serde(default)]
+    pub persistent_state_backups: Option<crate::persistent_state::Backups>,
+    /// Write persistent state gzipped, e.g. to save flash storage. Both compressed and plain
+    /// files are read regardless
+    #[serde(default)]
+    pub persistent_state_compress: bool,
+    /// Serve only status and metrics, never modifying ipsets. Allows running a secondary
+    /// instance alongside the primary one
+    #[serde(default)]
+    pub read_only: bool,
+    /// Number of last external command invocations kept for `/api/v1/debug/commands`.
+    /// Disabled if 0
+    #[serde(default)]
+    pub command_log_size: usize,
+    /// Maximum captured bytes of stdout and stderr each of external commands, except
+    /// ipset. Extra output is dropped to bound memory use
+    #[serde(default = "default_max_command_output")]
+    pub max_command_output: usize,
+    /// Regexes of commands allowed to run, matched against whole commands as configured, e.g.
+    /// `speedtest_cli_path` with its arguments. Commands with unquoted `;`, `|`, redirects or
+    /// substitutions are refused when set. Any command may run if not set. ipset is not
+    /// restricted
+    #[serde(default)]
+    pub command_allowlist: Option<Vec<String>>,
+    /// Time windows, in `timezone`, during which MAC clients are registered to no_shape
+    /// ipset instead of shaper. Entries still expire after `shaping_timeout`
+    #[serde(default)]
+    pub no_shape_windows: Vec<TimeWindow>,
+    /// Command run in background after new client registration, not on refresh. `{ip}` and
+    /// `{mac}` are substituted. Commands with shell syntax are run by `shell()`, the rest are
+    /// executed directly
+    #[serde(default)]
+    pub on_register_command: Option<String>,
+    /// Maximum number of `ipset` processes running at once. Applied only on restart
+    #[serde(default = "default_ipset_concurrency")]
+    pub ipset_concurrency: usize,
+    /// Prepended to names of all Prometheus metrics
+    #[serde(default = "default_metrics_prefix")]
+    pub metrics_prefix: String,
+    /// Static labels added to all Prometheus metrics, e.g. `site: archa-1` to tell boxes
+    /// apart when they are scraped into one Prometheus. Labels of the backend's own metrics,
+    /// like `set` or `endpoint`, can't be used
+    #[serde(default)]
+    pub metrics_labels: std::collections::BTreeMap<String, String>,
+    /// File the config was read from, used to reload it
+    #[serde(skip)]
+    pub config_path: Option<String>,
+}
+
+/// Names of ACL, shaper and no_shape ipsets of a single address family
+#[derive(Debug, PartialEq)]
+pub struct IPSetNames<'a> {
+    pub acl: &'a str,
+    pub shaper: &'a str,
+    pub no_shape: &'a str,
+}
+
+/// Converts time to configured timezone, or to system local one if not set
+pub fn to_local_time<T: chrono::TimeZone>(
+    time: &chrono::DateTime<T>,
+    timezone: Option<chrono_tz::Tz>,
+) -> chrono::DateTime<chrono::FixedOffset> {
+    match timezone {
+        Some(timezone) => time.with_timezone(&timezone).fixed_offset(),
+        None => time.with_timezone(&chrono::Local).fixed_offset(),
+    }
+}
+
+/// Config path which means reading config from stdin
+pub const STDIN_PATH: &str = "-";
+
+/// Prefix of environment variables overriding config values. Nested keys are separated by
+/// `__`, e.g. `ALA_ARCHA_HTTP_BACKEND__PING__SERVER=8.8.8.8`. Values are parsed as YAML, or
+/// taken as plain strings if that doesn't fit the key
+pub const ENV_PREFIX: &str = "ALA_ARCHA_HTTP_BACKEND__";
+
+const REDACTED: &str = "<redacted>";
+
+/// Config overrides from environment as key paths and raw values, sorted by path
+fn env_overrides(env: impl IntoIterator<Item = (String, String)>) -> Vec<(Vec<String>, String)> {
+    let mut overrides = env
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let path = name.strip_prefix(ENV_PREFIX)?;
+            let path = path.split("__").map(str::to_lowercase).collect::<Vec<_>>();
+            Some((path, value))
+        })
+        .collect::<Vec<_>>();
+    overrides.sort_by(|a, b| a.0.cmp(&b.0));
+    overrides
+}
+
+/// Sets value at `path`, creating missing mappings on the way
+fn apply_env_override(
+    config: &mut serde_yaml::Value,
+    path: &[String],
+    value: serde_yaml::Value,
+) -> Result<()> {
+    let Some((key, parents)) = path.split_last() else {
+        anyhow::bail!("Empty config key");
+    };
+    let mut target = config;
+    for parent in parents {
+        target = target
+            .as_mapping_mut()
+            .with_context(|| format!("{parent:?} parent is not a mapping"))?
+            .entry(parent.as_str().into())
+            .or_insert_with(|| serde_yaml::Mapping::new().into());
+    }
+    target
+        .as_mapping_mut()
+        .with_context(|| format!("{key:?} parent is not a mapping"))?
+        .insert(key.as_str().into(), value);
+    Ok(())
+}
+
+/// Reads secret (token, API key) mounted as a file, e.g. systemd credential or Kubernetes secret
+pub fn read_secret_file(path: &std::path::Path) -> Result<String> {
+    let secret = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secret file {:?}", path))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
 }
 
 impl Config {
     fn validate(&self) -> Result<()> {
+        self.speedtest.validate()?;
+        if self.ipset_concurrency == 0 {
+            anyhow::bail!("ipset_concurrency must be positive");
+        }
+        if let Some(command_allowlist) = &self.command_allowlist {
+            crate::command_log::allowlist_regex(command_allowlist)
+                .with_context(|| "Invalid command_allowlist pattern")?;
+        }
+        if self.max_acl_size == Some(0) {
+            anyhow::bail!("max_acl_size must be positive");
+        }
+        if !(self.acl_size_alert_ratio > 0.0 && self.acl_size_alert_ratio <= 1.0) {
+            anyhow::bail!(
+                "acl_size_alert_ratio must be in (0, 1], got {}",
+                self.acl_size_alert_ratio
+            );
+        }
+        if let Some(mobile_provider) = &self.mobile_provider {
+            for chat in &mobile_provider.telegram_chat_ids {
+                chat.validate()
+                    .with_context(|| "Invalid mobile_provider.telegram_chat_ids")?;
+            }
+        }
+        if !is_valid_metric_name(&self.metrics_prefix) {
+            anyhow::bail!(
+                "metrics_prefix must match [a-zA-Z_:][a-zA-Z0-9_:]*, got {:?}",
+                self.metrics_prefix
+            );
+        }
+        if let Some(name) = self.metrics_labels.keys().find(|v| !is_valid_label_name(v)) {
+            anyhow::bail!(
+                "metrics_labels names must match [a-zA-Z_][a-zA-Z0-9_]* and not start with __, \
+                 got {:?}",
+                name
+            );
+        }
+        if let Some(name) = self
+            .metrics_labels
+            .keys()
+            .find(|v| RESERVED_LABEL_NAMES.contains(&v.as_str()))
+        {
+            anyhow::bail!(
+                "metrics_labels name {:?} is reserved, it is set by the backend itself",
+                name
+            );
+        }
+        Ok(())
+    }
+
+    fn normalize(&mut self) {
+        if let Some(mobile_provider) = &mut self.mobile_provider {
+            for chat in &mut mobile_provider.telegram_chat_ids {
+                chat.normalize();
+            }
+        }
+    }
+
+    fn load_secrets(&mut self) -> Result<()> {
+        if let Some(path) = &self.admin_api_key_file {
+            self.admin_api_key = Some(read_secret_file(path)?);
+        }
+        if let Some(telegram) = &mut self.telegram {
+            telegram.load_secrets()?;
+        }
         Ok(())
     }
 
+    /// Reads config from `file`, or from stdin if it is `-`. Config read from stdin has no
+    /// `config_path`, so it can't be reloaded
     pub fn read(file: &str) -> Result<Self> {
-        let config = std::fs::read_to_string(file)
+        Self::read_with_env(file, std::env::vars())
+    }
+
+    /// Same as `read`, overriding config with `env` instead of process environment
+    pub fn read_with_env(
+        file: &str,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        if file == STDIN_PATH {
+            return Self::from_reader(std::io::stdin().lock(), "config from stdin", env);
+        }
+        let reader = std::fs::File::open(file)
             .with_context(|| format!("Failed to load config file {:?}", file))?;
-        let config: Self = serde_yaml::from_str(&config)
-            .with_context(|| format!("Failed to parse config file {:?}", file))?;
+        let mut config = Self::from_reader(reader, &format!("config file {:?}", file), env)?;
+        config.config_path = Some(file.to_string());
+        Ok(config)
+    }
+
+    /// Parses and validates config, `source` describes where it comes from in errors
+    fn from_reader(
+        mut reader: impl std::io::Read,
+        source: &str,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let mut config = String::new();
+        reader
+            .read_to_string(&mut config)
+            .with_context(|| format!("Failed to load {source}"))?;
+        Self::parse(&config, source, env)
+    }
+
+    /// Same as `from_reader`, overlaying `env` variables prefixed with `ENV_PREFIX`
+    fn parse(
+        config: &str,
+        source: &str,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let overrides = env_overrides(env);
+        let mut config: Self = if overrides.is_empty() {
+            // Parsing string directly keeps error locations
+            serde_yaml::from_str(config).with_context(|| format!("Failed to parse {source}"))?
+        } else {
+            let mut value: serde_yaml::Value = serde_yaml::from_str(config)
+                .with_context(|| format!("Failed to parse {source}"))?;
+            for (path, raw) in overrides {
+                let context = || format!("Failed to apply {ENV_PREFIX}{}", path.join("__"));
+                // Values which aren't valid YAML, e.g. with `: `, are taken as plain strings
+                let parsed = serde_yaml::from_str(&raw)
+                    .unwrap_or_else(|_| serde_yaml::Value::String(raw.clone()));
+                let mut overridden = value.clone();
+                apply_env_override(&mut overridden, &path, parsed).with_context(context)?;
+                // YAML type may not fit the key, e.g. `0123` is a number, but API keys are
+                // strings. Plain string is taken then, if it fits
+                if serde_yaml::from_value::<Self>(overridden.clone()).is_err() {
+                    let mut as_string = value.clone();
+                    apply_env_override(&mut as_string, &path, raw.into()).with_context(context)?;
+                    if serde_yaml::from_value::<Self>(as_string.clone()).is_ok() {
+                        overridden = as_string;
+                    }
+                }
+                value = overridden;
+            }
+            serde_yaml::from_value(value)
+                .with_context(|| format!("Failed to parse {source} with environment overrides"))?
+        };
 
+        config.normalize();
+        config.load_secrets()?;
         config.validate()?;
         Ok(config)
     }
+
+    /// Copy of config safe to show, with secrets replaced
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if config.admin_api_key.is_some() {
+            config.admin_api_key = Some(REDACTED.to_string());
+        }
+        if let Some(telegram) = &mut config.telegram {
+            telegram.bot_token = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// Ipsets for address family of `ip`. Sets without IPv6 counterpart configured, as well
+    /// as unparseable IPs, get IPv4 ones
+    pub fn ipset_names(&self, ip: &str) -> IPSetNames<'_> {
+        let is_v6 = ip.parse::<IpAddr>().is_ok_and(|v| v.is_ipv6());
+        IPSetNames {
+            acl: self
+                .ipset_acl_name_v6
+                .as_deref()
+                .filter(|_| is_v6)
+                .unwrap_or(&self.ipset_acl_name),
+            shaper: self
+                .ipset_shaper_name_v6
+                .as_deref()
+                .filter(|_| is_v6)
+                .unwrap_or(&self.ipset_shaper_name),
+            no_shape: self
+                .ipset_no_shape_name_v6
+                .as_deref()
+                .filter(|_| is_v6)
+                .unwrap_or(&self.ipset_no_shape_name),
+        }
+    }
+
+    /// ACL ipset followed by its IPv6 counterpart, if configured
+    pub fn acl_ipset_names(&self) -> Vec<&str> {
+        std::iter::once(self.ipset_acl_name.as_str())
+            .chain(self.ipset_acl_name_v6.as_deref())
+            .collect()
+    }
+
+    /// Shaper ipset followed by its IPv6 counterpart, if configured
+    pub fn shaper_ipset_names(&self) -> Vec<&str> {
+        std::iter::once(self.ipset_shaper_name.as_str())
+            .chain(self.ipset_shaper_name_v6.as_deref())
+            .collect()
+    }
+
+    /// No-shape ipset followed by its IPv6 counterpart, if configured
+    pub fn no_shape_ipset_names(&self) -> Vec<&str> {
+        std::iter::once(self.ipset_no_shape_name.as_str())
+            .chain(self.ipset_no_shape_name_v6.as_deref())
+            .collect()
+    }
+
+    /// Shell for commands with shell syntax: `mobile_provider.shell`, or its default if the
+    /// section isn't set
+    pub fn shell(&self) -> std::path::PathBuf {
+        self.mobile_provider
+            .as_ref()
+            .map_or_else(crate::mobile_provider::default_shell, |mobile_provider| {
+                mobile_provider.shell.clone()
+            })
+    }
+
+    /// Fraction of `max_acl_size` taken by `acl_size` entries, if the limit is set
+    pub fn acl_capacity_used_ratio(&self, acl_size: usize) -> Option<f64> {
+        self.max_acl_size
+            .map(|max_acl_size| acl_size as f64 / max_acl_size as f64)
+    }
+
+    /// `blacklist_message` with contact substituted, which is "administrator" if not set
+    pub fn blacklist_message(&self) -> String {
+        self.blacklist_message.replace(
+            "{contact}",
+            self.support_contact.as_deref().unwrap_or("administrator"),
+        )
+    }
+
+    /// Category of the first rule matching `vendor_class_identifier`
+    pub fn device_category(&self, vendor_class_identifier: &str) -> Option<&str> {
+        self.device_categories
+            .iter()
+            .find(|v| v.pattern.is_match(vendor_class_identifier))
+            .map(|v| v.category.as_str())
+    }
+
+    /// Retention of persistent state collections
+    pub fn retention(&self) -> crate::persistent_state::Retention {
+        crate::persistent_state::Retention {
+            client_history_limit: self.client_history_limit,
+            client_history_max_age: self.client_history_max_age,
+            telegram_message_timeout: self.telegram.as_ref().map(|v| v.message_timeout),
+        }
+    }
+
+    /// `bytes_unlimited_limit` converted to bytes
+    pub fn unlimited_limit_bytes(&self) -> usize {
+        self.limit_unit.to_bytes(self.bytes_unlimited_limit)
+    }
+
+    /// Same as `unlimited_limit_bytes`, but honours `mac_overrides` of the client
+    pub fn client_unlimited_limit_bytes(&self, mac: &str) -> usize {
+        self.mac_overrides
+            .iter()
+            .find(|(v, _)| v.eq_ignore_ascii_case(mac))
+            .and_then(|(_, v)| v.bytes_unlimited_limit)
+            .map(|v| self.limit_unit.to_bytes(v))
+            .unwrap_or_else(|| self.unlimited_limit_bytes())
+    }
+
+    /// Checks whether time falls into one of `no_shape_windows`
+    pub fn is_no_shape_window<T: chrono::TimeZone>(&self, time: &chrono::DateTime<T>) -> bool {
+        let time = to_local_time(time, self.timezone).time();
+        self.no_shape_windows.iter().any(|v| v.contains(time))
+    }
+}
+
+#[cfg(test)]
+pub fn test_config() -> Config {
+    serde_yaml::from_str(
+        r#"
+log_level: Info
+ipset_shaper_name: shaper
+ipset_acl_name: acl
+ipset_no_shape_name: no_shape
+http_listen: 127.0.0.1:8888
+bytes_unlimited_limit: 5000000
+dhcpd_leases: tests/fixtures/dhcpd.leases
+no_shaping_timeout: 3600
+shaping_timeout: 1800
+ping:
+  server: 1.1.1.1
+  crontab: "0 * * * * *"
+speedtest:
+  speedtest_cli_path: /usr/local/bin/speedtest
+  crontab: "0 15 */8 * * *"
+persistent_state_path: /nonexistent/ala-archa-http-backend.state
+"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_to_local_time() {
+    let time = chrono::DateTime::parse_from_rfc3339("2024-01-04T12:00:00Z").unwrap();
+    let timezone: chrono_tz::Tz = serde_yaml::from_str("Asia/Bishkek").unwrap();
+    assert_eq!(
+        to_local_time(&time, Some(timezone))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        "2024-01-04 18:00:00"
+    );
+}
+
+#[test]
+fn test_speedtest_server_id_validation() {
+    let mut config = test_config();
+    config.speedtest.server_id = Some("12345".to_string());
+    assert!(config.validate().is_ok());
+    config.speedtest.server_id = Some("12a45".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_metrics_prefix_validation() {
+    let mut config = test_config();
+    assert_eq!(config.metrics_prefix, "ratzek");
+    for prefix in ["ala_archa", "_ratzek", "site:ratzek2"] {
+        config.metrics_prefix = prefix.to_string();
+        assert!(config.validate().is_ok(), "{prefix}");
+    }
+    for prefix in ["", "2ratzek", "ala-archa", "ratzek "] {
+        config.metrics_prefix = prefix.to_string();
+        assert!(config.validate().is_err(), "{prefix}");
+    }
+}
+
+#[test]
+fn test_metrics_labels_validation() {
+    let mut config = test_config();
+    for name in ["site", "_instance", "site_2"] {
+        config.metrics_labels = [(name.to_string(), "archa-1".to_string())].into();
+        assert!(config.validate().is_ok(), "{name}");
+    }
+    for name in [
+        "",
+        "2site",
+        "site-name",
+        "site:name",
+        "__name__",
+        "set",
+        "mac",
+        "endpoint",
+        "status",
+        "le",
+    ] {
+        config.metrics_labels = [(name.to_string(), "archa-1".to_string())].into();
+        assert!(config.validate().is_err(), "{name}");
+    }
+}
+
+#[test]
+fn test_time_window() {
+    let window: TimeWindow = serde_yaml::from_str("{start: \"22:00\", end: \"07:30\"}").unwrap();
+    let time = |v: &str| v.parse::<chrono::NaiveTime>().unwrap();
+    assert!(window.contains(time("22:00")));
+    assert!(window.contains(time("03:00")));
+    assert!(!window.contains(time("07:30")));
+    assert!(!window.contains(time("12:00")));
+
+    let window = TimeWindow {
+        start: time("01:00"),
+        end: time("05:00"),
+    };
+    assert!(window.contains(time("01:00")));
+    assert!(!window.contains(time("05:00")));
+    assert!(!window.contains(time("23:00")));
+}
+
+#[test]
+fn test_limit_unit_conversion() {
+    let mut config = test_config();
+    config.bytes_unlimited_limit = 5;
+    for (unit, bytes) in [
+        ("Bytes", 5),
+        ("KiB", 5 * 1024),
+        ("MiB", 5 * 1024 * 1024),
+        ("GiB", 5 * 1024 * 1024 * 1024),
+    ] {
+        config.limit_unit = serde_yaml::from_str(unit).unwrap();
+        assert_eq!(config.unlimited_limit_bytes(), bytes, "{unit}");
+    }
+
+    assert_eq!(test_config().unlimited_limit_bytes(), 5000000);
+    assert_eq!(LimitUnit::GiB.to_bytes(usize::MAX), usize::MAX);
+}
+
+#[test]
+fn test_http_timeouts() {
+    let config = test_config();
+    assert_eq!(config.http_keep_alive, std::time::Duration::from_secs(5));
+    assert_eq!(
+        config.http_client_timeout,
+        std::time::Duration::from_secs(5)
+    );
+
+    let mut config = serde_yaml::to_value(test_config()).unwrap();
+    config["http_keep_alive"] = "75s".into();
+    config["http_client_timeout"] = "2s 500ms".into();
+    let config: Config = serde_yaml::from_value(config).unwrap();
+    assert_eq!(config.http_keep_alive, std::time::Duration::from_secs(75));
+    assert_eq!(
+        config.http_client_timeout,
+        std::time::Duration::from_millis(2500)
+    );
+}
+
+#[test]
+fn test_ipset_names() {
+    let mut config = test_config();
+    let v4 = IPSetNames {
+        acl: "acl",
+        shaper: "shaper",
+        no_shape: "no_shape",
+    };
+    assert_eq!(config.ipset_names("10.11.2.10"), v4);
+    assert_eq!(config.ipset_names("2001:db8::10"), v4);
+    assert_eq!(config.acl_ipset_names(), ["acl"]);
+
+    config.ipset_acl_name_v6 = Some("acl6".to_string());
+    config.ipset_shaper_name_v6 = Some("shaper6".to_string());
+    assert_eq!(config.ipset_names("10.11.2.10"), v4);
+    assert_eq!(
+        config.ipset_names("2001:db8::10"),
+        IPSetNames {
+            acl: "acl6",
+            shaper: "shaper6",
+            no_shape: "no_shape",
+        }
+    );
+    assert_eq!(config.acl_ipset_names(), ["acl", "acl6"]);
+    assert_eq!(config.shaper_ipset_names(), ["shaper", "shaper6"]);
+    assert_eq!(config.no_shape_ipset_names(), ["no_shape"]);
+}
+
+#[test]
+fn test_config_from_reader() {
+    // Same as `dump-config` output piped into `-c -`
+    let dumped = serde_yaml::to_string(&test_config()).unwrap();
+    let config = Config::from_reader(dumped.as_bytes(), "config from stdin", []).unwrap();
+    assert_eq!(config.ipset_acl_name, "acl");
+    assert!(config.config_path.is_none());
+
+    let err = match Config::from_reader("ping: [".as_bytes(), "config from stdin", []) {
+        Ok(_) => panic!("invalid config parsed"),
+        Err(err) => format!("{err:#}"),
+    };
+    assert!(
+        err.starts_with("Failed to parse config from stdin"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_env_overrides() {
+    let dumped = serde_yaml::to_string(&test_config()).unwrap();
+    let env = [
+        ("ALA_ARCHA_HTTP_BACKEND__PING__SERVER", "8.8.8.8"),
+        ("ALA_ARCHA_HTTP_BACKEND__IPSET_CONCURRENCY", "8"),
+        ("ALA_ARCHA_HTTP_BACKEND__ADMIN_API_KEY", "secret"),
+        ("ALA_ARCHA_HTTP_BACKEND_OTHER", "ignored"),
+        ("PATH", "/bin"),
+    ]
+    .map(|(name, value)| (name.to_string(), value.to_string()));
+    let config = Config::parse(&dumped, "config", env).unwrap();
+    assert_eq!(config.admin_api_key.as_deref(), Some("secret"));
+
+    let env = [("ALA_ARCHA_HTTP_BACKEND__ADMIN_API_KEY", "0123")]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+    let numeric_key = Config::parse(&dumped, "config", env).unwrap();
+    assert_eq!(numeric_key.admin_api_key.as_deref(), Some("0123"));
+
+    let mut expected = test_config();
+    expected.ping.server = "8.8.8.8".parse().unwrap();
+    expected.ipset_concurrency = 8;
+    expected.admin_api_key = Some("<redacted>".to_string());
+    assert_eq!(
+        serde_yaml::to_string(&config.redacted()).unwrap(),
+        serde_yaml::to_string(&expected).unwrap()
+    );
+
+    let env = [("ALA_ARCHA_HTTP_BACKEND__HTTP_LISTEN__PORT", "80")]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+    assert!(Config::parse(&dumped, "config", env).is_err());
+}
+
+#[test]
+fn test_device_category() {
+    let mut config = test_config();
+    config.device_categories = serde_yaml::from_str(
+        r#"
+- pattern: "^android-dhcp"
+  category: Android
+- pattern: "^MSFT"
+  category: Windows
+- pattern: "(?i)^udhcp"
+  category: IoT
+- pattern: "."
+  category: Other
+"#,
+    )
+    .unwrap();
+
+    for (vendor_class, category) in [
+        ("android-dhcp-13", "Android"),
+        ("MSFT 5.0", "Windows"),
+        ("udhcp 1.30.1", "IoT"),
+        ("UDHCP 1.36.1", "IoT"),
+        ("dhcpcd-9.4.1:Linux-6.1.0:armv7l:BCM2835", "Other"),
+    ] {
+        assert_eq!(
+            config.device_category(vendor_class),
+            Some(category),
+            "{vendor_class}"
+        );
+    }
+
+    config.device_categories.pop();
+    assert_eq!(config.device_category("dhcpcd-9.4.1"), None);
+
+    assert!(
+        serde_yaml::from_str::<Vec<DeviceCategory>>("- {pattern: '(', category: Broken}").is_err()
+    );
 }
@@ -1,4 +1,6 @@
 use anyhow::{anyhow, Result};
+use dhcpd_parser::leases::{BindingState, Lease};
+use serde::Serialize;
 
 pub struct Dhcp;
 
@@ -11,12 +13,130 @@ pub fn read(leases: &std::path::Path) -> Result<dhcpd_parser::leases::Leases> {
         Ok(leases.leases)
     }
 
-    pub fn of_ip(leases: &std::path::Path, ip: &str) -> Result<dhcpd_parser::leases::Lease> {
+    /// Returns `None` if there is no lease of `ip`
+    pub fn of_ip(
+        leases: &std::path::Path,
+        ip: &str,
+    ) -> Result<Option<dhcpd_parser::leases::Lease>> {
         use dhcpd_parser::parser::LeasesMethods;
-        Self::read(leases)?
+        Ok(Self::read(leases)?
             .all()
             .into_iter()
-            .find(|lease| lease.ip == ip)
-            .ok_or_else(|| anyhow!("DHCP lease not found"))
+            .find(|lease| lease.ip == ip))
     }
 }
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct LeasesSummary {
+    pub active: usize,
+    pub free: usize,
+    pub abandoned: usize,
+    pub total: usize,
+}
+
+impl LeasesSummary {
+    /// Counts leases by binding state. With `abandoned_as_free` abandoned leases are also
+    /// counted as free, `abandoned` is reported either way
+    pub fn count(leases: &[Lease], abandoned_as_free: bool) -> Self {
+        let mut summary = Self {
+            total: leases.len(),
+            ..Default::default()
+        };
+        for lease in leases {
+            if lease.binding_state == BindingState::Active {
+                summary.active += 1;
+            } else if lease.binding_state == BindingState::Free {
+                summary.free += 1;
+            } else if lease.binding_state == BindingState::Abandoned {
+                summary.abandoned += 1;
+                if abandoned_as_free {
+                    summary.free += 1;
+                }
+            }
+        }
+        summary
+    }
+}
+
+/// Parses a lease date as rendered by `dhcpd_parser` (`<weekday> YYYY/MM/DD HH:MM:SS`).
+/// dhcpd stores lease dates in UTC.
+pub fn parse_lease_date(date: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let mut parts = date.split_whitespace().rev();
+    let time = parts.next()?;
+    let date = parts.next()?;
+
+    let date = date
+        .split('/')
+        .map(|v| v.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let time = time
+        .split(':')
+        .map(|v| v.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match (date.as_slice(), time.as_slice()) {
+        ([year, month, day], [hour, minute, second]) => {
+            chrono::NaiveDate::from_ymd_opt(*year as i32, *month, *day)
+                .and_then(|v| v.and_hms_opt(*hour, *minute, *second))
+                .map(|v| v.and_utc())
+        }
+        _ => None,
+    }
+}
+
+/// Seconds left until lease end date relative to `now`; negative if already expired
+pub fn seconds_until(date: Option<&str>, now: chrono::DateTime<chrono::Utc>) -> Option<i64> {
+    date.and_then(parse_lease_date)
+        .map(|v| (v - now).num_seconds())
+}
+
+#[cfg(test)]
+fn fixture_leases() -> Vec<Lease> {
+    use dhcpd_parser::parser::LeasesMethods;
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/dhcpd.leases");
+    Dhcp::read(&path).unwrap().all()
+}
+
+#[test]
+fn test_leases_summary() {
+    assert_eq!(
+        LeasesSummary::count(&fixture_leases(), false),
+        LeasesSummary {
+            active: 2,
+            free: 1,
+            abandoned: 1,
+            total: 4,
+        }
+    );
+    assert_eq!(
+        LeasesSummary::count(&fixture_leases(), true),
+        LeasesSummary {
+            active: 2,
+            free: 2,
+            abandoned: 1,
+            total: 4,
+        }
+    );
+}
+
+#[test]
+fn test_seconds_until_future() {
+    let now = parse_lease_date("4 2024/01/04 12:00:00").unwrap();
+    assert_eq!(
+        seconds_until(Some("4 2024/01/04 12:30:00"), now),
+        Some(1800)
+    );
+}
+
+#[test]
+fn test_seconds_until_past() {
+    let now = parse_lease_date("4 2024/01/04 12:00:00").unwrap();
+    assert_eq!(seconds_until(Some("4 2024/01/04 11:59:00"), now), Some(-60));
+}
+
+#[test]
+fn test_seconds_until_missing() {
+    let now = chrono::Utc::now();
+    assert_eq!(seconds_until(None, now), None);
+    assert_eq!(seconds_until(Some("never"), now), None);
+    assert_eq!(seconds_until(Some("4 2024/13/45 12:00:00"), now), None);
+}
@@ -1,36 +1,97 @@
 use std::{future::Future, sync::Arc};
 
 use actix_web::{
-    get,
-    http::{header::ContentType, StatusCode},
+    delete, get,
+    http::StatusCode,
     post,
-    web::Data,
+    web::{Data, Json, Query},
     HttpRequest, HttpResponse,
 };
 use derive_more::{Display, Error};
 use dhcpd_parser::parser::LeasesMethods;
-use serde::Serialize;
-use slog_scope::{error, info};
+use serde::{Deserialize, Serialize};
+use slog_scope::{error, info, warn};
 use tokio::sync::Mutex;
 
 use crate::state::State;
 
-#[derive(Debug, Display, Error)]
-enum APIError {
+/// Errors returned to clients as JSON with stable `code`, which captive portal relies on
+#[derive(Clone, Debug, Display, Error)]
+pub(crate) enum APIError {
     #[display(fmt = "internal error")]
     InternalError,
+    #[display(fmt = "unauthorized")]
+    Unauthorized,
+    #[display(fmt = "service is under maintenance, please try again later")]
+    Maintenance,
+    #[display(fmt = "registration partially failed, client is in ACL only")]
+    PartialRegistration,
+    #[display(fmt = "instance is read-only")]
+    ReadOnly,
+    #[display(fmt = "bad request: {}", _0)]
+    BadRequest(#[error(not(source))] String),
+    #[display(fmt = "not found")]
+    NotFound,
+    /// Client IP has no DHCP lease or lease has no MAC
+    #[display(fmt = "client is not connected to the network")]
+    NotOnNetwork,
+    /// Message is configured with `blacklist_message`
+    #[display(fmt = "{}", _0)]
+    Blacklisted(#[error(not(source))] String),
+    /// Wide network is down and `refuse_register_when_down` is set
+    #[display(fmt = "upstream unavailable")]
+    UpstreamUnavailable,
+    /// Part of the portal contract, not emitted by the backend yet
+    #[allow(dead_code)]
+    #[display(fmt = "too many requests, please try again later")]
+    RateLimited,
+}
+
+#[derive(Serialize)]
+struct APIErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+impl APIError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::InternalError => "backend_error",
+            Self::Unauthorized => "unauthorized",
+            Self::Maintenance => "maintenance",
+            Self::PartialRegistration => "partial_registration",
+            Self::ReadOnly => "read_only",
+            Self::BadRequest(_) => "bad_request",
+            Self::NotFound => "not_found",
+            Self::NotOnNetwork => "not_on_network",
+            Self::Blacklisted(_) => "blacklisted",
+            Self::UpstreamUnavailable => "upstream_unavailable",
+            Self::RateLimited => "rate_limited",
+        }
+    }
 }
 
 impl actix_web::error::ResponseError for APIError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .body(self.to_string())
+        HttpResponse::build(self.status_code()).json(APIErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+        })
     }
 
     fn status_code(&self) -> StatusCode {
         match *self {
             Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+            Self::PartialRegistration => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ReadOnly => StatusCode::FORBIDDEN,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::NotOnNetwork => StatusCode::FORBIDDEN,
+            Self::Blacklisted(_) => StatusCode::FORBIDDEN,
+            Self::UpstreamUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 }
@@ -41,11 +102,16 @@ struct ClientConnectionInfo {
     pub bytes_unlimited_limit: usize,
     pub shaper_reset_secs: u64,
     pub connection_forget_secs: u64,
+    /// Client has used up `bytes_unlimited_limit` and is throttled now
+    pub is_currently_shaped: bool,
 }
 
 #[derive(Serialize)]
 enum InternetConnectionStatus {
+    /// Client hasn't registered within `expired_status_window`
     Inactive,
+    /// Client registered within `expired_status_window`, but its ACL entry is gone
+    Expired,
     Connected(ClientConnectionInfo),
     ClientBlacklisted,
 }
@@ -54,6 +120,8 @@ enum InternetConnectionStatus {
 enum Client {
     Whitelist,
     Mac(String),
+    /// Static IP without DHCP lease, see `allow_unknown_static`
+    Static,
 }
 
 #[derive(Serialize)]
@@ -61,13 +129,125 @@ struct ServiceInfo {
     pub internet_connection_status: InternetConnectionStatus,
     pub internet_clients_connected: usize,
     pub is_internet_available: bool,
+    pub is_maintenance: bool,
+    /// Suggested delay before the next status poll, see `poll_after_secs`
+    pub poll_after_secs: u64,
+}
+
+/// Bounds of `ServiceInfo::poll_after_secs`
+const MIN_POLL_AFTER_SECS: u64 = 5;
+const MAX_POLL_AFTER_SECS: u64 = 300;
+
+/// Poll interval for client whose ACL entry expires in `acl_timeout`: a tenth of the
+/// remaining time, clamped to `MIN_POLL_AFTER_SECS..=MAX_POLL_AFTER_SECS`, so the portal
+/// polls rarely while plenty of time remains and notices expiry within 10% of the timeout.
+/// Clients without ACL entry poll at minimal interval as they are about to register, and
+/// entries without timeout never expire, so they poll at maximal one
+fn poll_after_secs(status: &InternetConnectionStatus, acl_timeout: Option<u64>) -> u64 {
+    match (status, acl_timeout) {
+        (InternetConnectionStatus::Connected(_), Some(remaining)) => {
+            (remaining / 10).clamp(MIN_POLL_AFTER_SECS, MAX_POLL_AFTER_SECS)
+        }
+        (InternetConnectionStatus::Connected(_), None) => MAX_POLL_AFTER_SECS,
+        _ => MIN_POLL_AFTER_SECS,
+    }
+}
+
+/// Admin endpoints require `X-Api-Key` header matching `admin_api_key`.
+/// They are disabled if the key is not configured.
+fn check_admin(req: &HttpRequest, config: &crate::config::Config) -> Result<(), APIError> {
+    let api_key = req.headers().get("x-api-key").and_then(|v| v.to_str().ok());
+    match (&config.admin_api_key, api_key) {
+        (Some(expected), Some(api_key))
+            if crate::util::constant_time_eq(expected.as_bytes(), api_key.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => {
+            warn!("Unauthorized request to admin endpoint {}", req.uri());
+            Err(APIError::Unauthorized)
+        }
+    }
+}
+
+/// Refuses ipset modifications on `read_only` instances
+fn check_writable(config: &crate::config::Config) -> Result<(), APIError> {
+    if config.read_only {
+        warn!("Refusing to modify ipsets in read-only mode");
+        return Err(APIError::ReadOnly);
+    }
+    Ok(())
+}
+
+fn check_registration_allowed(
+    config: &crate::config::Config,
+    persistent_state: &crate::persistent_state::PersistentState,
+) -> Result<(), APIError> {
+    if persistent_state.maintenance {
+        info!("Registration refused due to maintenance");
+        return Err(APIError::Maintenance);
+    }
+    if config.refuse_register_when_down && persistent_state.is_wide_network_available == Some(false)
+    {
+        info!("Registration refused as wide network is unavailable");
+        return Err(APIError::UpstreamUnavailable);
+    }
+    Ok(())
+}
+
+fn client_ip(req: &HttpRequest, config: &crate::config::Config) -> Option<String> {
+    let peer_ip = req.peer_addr().map(|v| v.ip());
+    let is_trusted_proxy = config.trusted_proxies.is_empty()
+        || peer_ip.is_some_and(|v| config.trusted_proxies.contains(&v));
+    let header_ip = if is_trusted_proxy {
+        req.headers()
+            .get(config.client_ip_header.as_str())
+            .and_then(|v| v.to_str().ok().map(|v| v.trim().to_string()))
+    } else {
+        None
+    };
+
+    header_ip.or_else(|| peer_ip.map(|v| v.to_string()))
 }
 
-fn client_ip(req: &HttpRequest) -> Option<String> {
-    req.headers()
-        .get("x-real-ip")
-        .and_then(|v| v.to_str().ok().map(|v| v.to_string()))
-        .or_else(|| req.peer_addr().map(|v| v.ip().to_string()))
+/// Identifies client by its IP: no_shape IPs are whitelisted, others are looked up in DHCP
+/// leases by MAC. IPs without lease in `allow_unknown_static` ranges are identified by IP only
+fn resolve_client(config: &crate::config::Config, client_ip: &str) -> Result<Client, APIError> {
+    if config.no_shaping_ips.contains(client_ip) {
+        info!("Client is in no_shape list");
+        return Ok(Client::Whitelist);
+    }
+
+    let is_allowed_static = client_ip
+        .parse::<std::net::IpAddr>()
+        .is_ok_and(|ip| config.allow_unknown_static.iter().any(|v| v.contains(&ip)));
+
+    let lease = match crate::dhcp::Dhcp::of_ip(&config.dhcpd_leases, client_ip) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("{}", err);
+            return Err(APIError::InternalError);
+        }
+    };
+
+    match lease {
+        Some(lease) => match lease.hardware {
+            Some(v) => Ok(Client::Mac(v.mac.to_lowercase())),
+            None if is_allowed_static => Ok(Client::Static),
+            None => {
+                error!("Client's MAC not defined in DHCP leases file");
+                Err(APIError::NotOnNetwork)
+            }
+        },
+        None if is_allowed_static => {
+            info!("Client with static IP has no DHCP lease");
+            Ok(Client::Static)
+        }
+        None => {
+            warn!("DHCP lease not found");
+            Err(APIError::NotOnNetwork)
+        }
+    }
 }
 
 async fn with_client<CB, Fut>(
@@ -79,7 +259,7 @@ async fn with_client<CB, Fut>(
     CB: FnOnce(String, Client) -> Fut,
     Fut: Future<Output = Result<String, APIError>>,
 {
-    let client_ip = match client_ip(req) {
+    let client_ip = match client_ip(req, state.lock().await.config()) {
         Some(v) => v,
         None => {
             error!("Unable to get client IP");
@@ -89,32 +269,12 @@ async fn with_client<CB, Fut>(
 
     info!("Request from {}: {}", client_ip, req.uri());
 
-    let is_no_shape = {
-        let state = state.lock().await;
-        state.config().no_shaping_ips.contains(&client_ip)
-    };
-    if is_no_shape {
-        info!("Client is in no_shape list");
-        slog_scope::logger().new(slog::slog_o!("client_ip" => client_ip.clone()));
-        return cb(client_ip, Client::Whitelist).await;
-    }
-
-    let dhcp_lease = {
-        let state = state.lock().await;
-        match crate::dhcp::Dhcp::of_ip(&state.config().dhcpd_leases, &client_ip) {
-            Ok(v) => v,
-            Err(err) => {
-                error!("{}", err);
-                return Err(APIError::InternalError);
-            }
-        }
-    };
-
-    let client_mac = match dhcp_lease.hardware {
-        Some(v) => v.mac.to_lowercase(),
-        None => {
-            error!("Client's MAC not defined in DHCP leases file");
-            return Err(APIError::InternalError);
+    let client = resolve_client(state.lock().await.config(), &client_ip)?;
+    let client_mac = match &client {
+        Client::Mac(mac) => mac.clone(),
+        Client::Whitelist | Client::Static => {
+            slog_scope::logger().new(slog::slog_o!("client_ip" => client_ip.clone()));
+            return cb(client_ip, client).await;
         }
     };
 
@@ -122,11 +282,142 @@ async fn with_client<CB, Fut>(
         &slog_scope::logger().new(
             slog::slog_o!("client_ip" => client_ip.clone(), "client_mac" => client_mac.clone()),
         ),
-        || cb(client_ip, Client::Mac(client_mac)),
+        || cb(client_ip, client),
     )
     .await
 }
 
+fn is_blacklisted(config: &crate::config::Config, client: &Client) -> bool {
+    match client {
+        Client::Mac(mac) => config
+            .blacklisted_macs
+            .iter()
+            .map(|v| v.to_lowercase())
+            .any(|v| v == *mac),
+        Client::Whitelist | Client::Static => false,
+    }
+}
+
+/// Same as `is_blacklisted`, but also logs and counts the attempt
+fn check_blacklisted(state: &State, client: &Client) -> bool {
+    if !is_blacklisted(state.config(), client) {
+        return false;
+    }
+    if let Client::Mac(mac) = client {
+        warn!("Blacklisted client attempted to access service");
+        state.counters().inc_blacklisted_attempts(mac);
+    }
+    true
+}
+
+/// Logs failed ipset operation and counts it in `ipset_errors`
+fn ipset_error(state: &State, message: &str, err: impl std::fmt::Display) -> APIError {
+    error!("{message}: {err}");
+    state.counters().inc_ipset_errors();
+    APIError::InternalError
+}
+
+/// Whether MAC client registered within `expired_status_window` and didn't deregister since.
+/// Other clients have no registration history
+fn is_recently_registered(
+    config: &crate::config::Config,
+    client: &Client,
+    persistent_state: &crate::persistent_state::PersistentState,
+    now: chrono::DateTime<chrono::Utc>,
+) -> bool {
+    let Client::Mac(mac) = client else {
+        return false;
+    };
+    persistent_state
+        .active_registration(mac)
+        .is_some_and(|last| {
+            (now - last).to_std().unwrap_or_default() < config.expired_status_window
+        })
+}
+
+fn build_service_info(
+    client_ip: &str,
+    client: &Client,
+    acl_entries: &[crate::ipset::Entry],
+    shaper_entries: &[crate::ipset::Entry],
+    config: &crate::config::Config,
+    persistent_state: &crate::persistent_state::PersistentState,
+) -> ServiceInfo {
+    let acl_info = acl_entries.iter().find(|v| v.ip == client_ip);
+    let internet_connection_status = if is_blacklisted(config, client) {
+        InternetConnectionStatus::ClientBlacklisted
+    } else if let Some(acl_info) = acl_info {
+        let shaper_info = shaper_entries.iter().find(|v| v.ip == client_ip);
+
+        let bytes_sent = shaper_info.and_then(|v| v.bytes).unwrap_or_default();
+        let bytes_unlimited_limit = match client {
+            Client::Mac(mac) => config.client_unlimited_limit_bytes(mac),
+            Client::Whitelist | Client::Static => config.unlimited_limit_bytes(),
+        };
+        InternetConnectionStatus::Connected(ClientConnectionInfo {
+            bytes_sent,
+            bytes_unlimited_limit,
+            is_currently_shaped: bytes_sent >= bytes_unlimited_limit,
+            shaper_reset_secs: shaper_info
+                .and_then(|v| v.timeout.map(|v| v.as_secs()))
+                .unwrap_or_default(),
+            connection_forget_secs: acl_info.timeout.map(|v| v.as_secs()).unwrap_or_default(),
+        })
+    } else if is_recently_registered(config, client, persistent_state, chrono::Utc::now()) {
+        InternetConnectionStatus::Expired
+    } else {
+        InternetConnectionStatus::Inactive
+    };
+
+    let acl_timeout = acl_info.and_then(|v| v.timeout).map(|v| v.as_secs());
+    ServiceInfo {
+        internet_clients_connected: shaper_entries.len(),
+        poll_after_secs: poll_after_secs(&internet_connection_status, acl_timeout),
+        internet_connection_status,
+        is_internet_available: persistent_state.is_wide_network_available.unwrap_or(false),
+        is_maintenance: persistent_state.maintenance,
+    }
+}
+
+/// Adds whitelisted client to ACL and no_shape sets on status request, if it isn't in ACL
+/// yet and `auto_register_whitelist` is set. Skipped when registrations are refused, e.g.
+/// during maintenance. Returns whether client was registered
+async fn auto_register_whitelisted(
+    config: &crate::config::Config,
+    persistent_state: &crate::persistent_state::PersistentState,
+    client: &Client,
+    client_ip: &str,
+    ipset_acl: &crate::ipset::IPSet,
+    ipset_no_shape: &crate::ipset::IPSet,
+) -> Result<bool, APIError> {
+    if !matches!(client, Client::Whitelist) || !config.auto_register_whitelist || config.read_only {
+        return Ok(false);
+    }
+    if check_registration_allowed(config, persistent_state).is_err() {
+        return Ok(false);
+    }
+
+    match ipset_acl.test(client_ip).await {
+        Ok(true) => Ok(false),
+        Ok(false) => {
+            info!("Automatically registering whitelisted client");
+            add_client_to_ipsets(
+                ipset_acl,
+                ipset_no_shape,
+                client_ip,
+                Some(config.no_shaping_timeout),
+                false,
+            )
+            .await?;
+            Ok(true)
+        }
+        Err(err) => {
+            error!("Unable to test ipset membership: {}", err);
+            Err(APIError::InternalError)
+        }
+    }
+}
+
 #[get("/api/v1/client")]
 async fn client_get(state: Data<Arc<Mutex<State>>>, req: HttpRequest) -> Result<String, APIError> {
     with_client(
@@ -135,342 +426,2034 @@ async fn client_get(state: Data<Arc<Mutex<State>>>, req: HttpRequest) -> Result<
         |client_ip: String, client: Client| async move {
             info!("Client requested service info");
             let state = state.lock().await;
+            check_blacklisted(&state, &client);
+            let ipset_names = state.config().ipset_names(&client_ip);
 
-            let ipset_shaper = crate::ipset::IPSet::new(&state.config().ipset_shaper_name);
-            let shaper_entries = match ipset_shaper.entries() {
-                Ok(v) => v,
-                Err(err) => {
-                    error!("Unable to get ipset list: {}", err);
-                    return Err(APIError::InternalError);
-                }
-            };
+            let ipset_shaper = crate::ipset::IPSet::new(ipset_names.shaper);
+            let shaper_entries = ipset_shaper
+                .entries()
+                .await
+                .map_err(|err| ipset_error(&state, "Unable to get ipset list", err))?;
 
-            if let Client::Mac(client_mac) = client {
-                if state
-                    .config()
-                    .blacklisted_macs
-                    .iter()
-                    .map(|v| v.to_lowercase())
-                    .any(|v| v == client_mac)
-                {
-                    let resp = ServiceInfo {
-                        internet_clients_connected: shaper_entries.len(),
-                        internet_connection_status: InternetConnectionStatus::ClientBlacklisted,
-                        is_internet_available: state
-                            .persistent_state()
-                            .await
-                            .is_wide_network_available
-                            .unwrap_or(false),
-                    };
-                    return Ok(serde_json::ser::to_string(&resp).unwrap());
-                }
-            }
+            let persistent_state = state.persistent_state().await;
+            let ipset_acl = crate::ipset::IPSet::new(ipset_names.acl);
+            auto_register_whitelisted(
+                state.config(),
+                &persistent_state,
+                &client,
+                &client_ip,
+                &ipset_acl,
+                &crate::ipset::IPSet::new(ipset_names.no_shape),
+            )
+            .await?;
 
-            let ipset_acl = crate::ipset::IPSet::new(&state.config().ipset_acl_name);
-            let acl_entries = match ipset_acl.entries() {
-                Ok(v) => v,
+            // ACL is listed only to get client's timeout, which is pointless if it isn't there
+            let acl_entries = match ipset_acl.test(&client_ip).await {
+                Ok(true) => ipset_acl
+                    .entries()
+                    .await
+                    .map_err(|err| ipset_error(&state, "Unable to get ipset list", err))?,
+                Ok(false) => Vec::new(),
                 Err(err) => {
-                    error!("Unable to get ipset list: {}", err);
-                    return Err(APIError::InternalError);
+                    return Err(ipset_error(&state, "Unable to test ipset membership", err));
                 }
             };
 
-            let acl_info = acl_entries.iter().find(|v| v.ip == client_ip);
-            let internet_connection_status = if let Some(acl_info) = acl_info {
-                let shaper_info = shaper_entries.iter().find(|v| v.ip == client_ip);
-
-                InternetConnectionStatus::Connected(ClientConnectionInfo {
-                    bytes_sent: shaper_info.and_then(|v| v.bytes).unwrap_or_default(),
-                    bytes_unlimited_limit: state.config().bytes_unlimited_limit,
-                    shaper_reset_secs: shaper_info
-                        .and_then(|v| v.timeout.map(|v| v.as_secs()))
-                        .unwrap_or_default(),
-                    connection_forget_secs: acl_info
-                        .timeout
-                        .map(|v| v.as_secs())
-                        .unwrap_or_default(),
-                })
-            } else {
-                InternetConnectionStatus::Inactive
-            };
-
-            let resp = ServiceInfo {
-                internet_clients_connected: shaper_entries.len(),
-                internet_connection_status,
-                is_internet_available: state
-                    .persistent_state()
-                    .await
-                    .is_wide_network_available
-                    .unwrap_or(false),
-            };
+            let resp = build_service_info(
+                &client_ip,
+                &client,
+                &acl_entries,
+                &shaper_entries,
+                state.config(),
+                &persistent_state,
+            );
             Ok(serde_json::ser::to_string(&resp).unwrap())
         },
     )
     .await
 }
 
+#[derive(Deserialize)]
+struct RegisterQuery {
+    /// Reset timeouts of already registered client instead of failing
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// Adds entry to `ipset`, or on refresh resets timeout of the existing one
+async fn add_to_ipset(
+    ipset: &crate::ipset::IPSet,
+    client_ip: &str,
+    timeout: Option<u64>,
+    refresh: bool,
+) -> anyhow::Result<()> {
+    if refresh {
+        ipset.refresh(client_ip, timeout).await
+    } else {
+        ipset.add(client_ip, timeout).await
+    }
+}
+
+/// Adds client to ACL and then to `ipset`. If the latter fails, fresh ACL entry is removed,
+/// so that client isn't left half-registered. On refresh ACL entry may predate the request,
+/// so it is kept
+async fn add_client_to_ipsets(
+    ipset_acl: &crate::ipset::IPSet,
+    ipset: &crate::ipset::IPSet,
+    client_ip: &str,
+    timeout: Option<u64>,
+    refresh: bool,
+) -> Result<(), APIError> {
+    info!("Adding {client_ip} to ACL ipset");
+    if let Err(err) = add_to_ipset(ipset_acl, client_ip, timeout, refresh).await {
+        error!("Unable to add client to ACL ipset: {}", err);
+        return Err(APIError::InternalError);
+    }
+
+    info!("Adding {client_ip} to {} ipset", ipset.name());
+    if let Err(err) = add_to_ipset(ipset, client_ip, timeout, refresh).await {
+        error!("Unable to add client to {:?} ipset: {}", ipset.name(), err);
+        if refresh {
+            return Err(APIError::InternalError);
+        }
+        return match ipset_acl.del(client_ip).await {
+            Ok(_) => {
+                info!("Removed {client_ip} from ACL ipset after failed registration");
+                Err(APIError::InternalError)
+            }
+            Err(err) => {
+                error!("Unable to roll back ACL registration of {client_ip}: {err}");
+                Err(APIError::PartialRegistration)
+            }
+        };
+    }
+
+    Ok(())
+}
+
+/// Picks ipset and entry timeout for client registration. MAC clients go to no_shape
+/// ipset during `no_shape_windows`, keeping shaping timeout so they are shaped again soon
+/// after the window ends
+fn registration_ipset<'a, T: chrono::TimeZone>(
+    config: &'a crate::config::Config,
+    client_ip: &str,
+    client: &Client,
+    now: &chrono::DateTime<T>,
+) -> (&'a str, u64) {
+    let names = config.ipset_names(client_ip);
+    match client {
+        Client::Whitelist => (names.no_shape, config.no_shaping_timeout),
+        Client::Mac(_) | Client::Static if config.is_no_shape_window(now) => {
+            (names.no_shape, config.shaping_timeout)
+        }
+        Client::Mac(_) | Client::Static => (names.shaper, config.shaping_timeout),
+    }
+}
+
+/// Window in which repeated registrations of the same client reuse result of the first one
+pub const REGISTRATION_DEDUP_WINDOW: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Registration results by client IP and `refresh` flag
+pub(crate) type Registrations = crate::coalesce::Coalescer<(String, bool), String, APIError>;
+
 #[post("/api/v1/client")]
 async fn client_register(
     state: Data<Arc<Mutex<State>>>,
+    query: Query<RegisterQuery>,
     req: HttpRequest,
 ) -> Result<String, APIError> {
+    check_writable(state.lock().await.config())?;
+    let refresh = query.refresh;
     with_client(
         state.clone(),
         &req,
         |client_ip: String, client: Client| async move {
-            info!("Client requested registration");
+            info!("Client requested registration (refresh = {refresh})");
 
-            let state = state.lock().await;
-
-            let ipset_acl = crate::ipset::IPSet::new(&state.config().ipset_acl_name);
-
-            let (ipset_shaper, ipset_name, timeout) = match client {
-                Client::Whitelist => {
-                    let ipset_no_shape =
-                        crate::ipset::IPSet::new(&state.config().ipset_no_shape_name);
-                    (
-                        ipset_no_shape,
-                        "no_shape",
-                        Some(state.config().no_shaping_timeout),
-                    )
-                }
-                Client::Mac(mac) => {
-                    if state
-                        .config()
-                        .blacklisted_macs
-                        .iter()
-                        .map(|v| v.to_lowercase())
-                        .any(|v| v == mac)
-                    {
-                        error!("Blacklisted client attempted to register");
-                        return Err(APIError::InternalError);
-                    }
-                    let ipset_shaper = crate::ipset::IPSet::new(&state.config().ipset_shaper_name);
-                    (ipset_shaper, "shaper", Some(state.config().shaping_timeout))
-                }
+            let registrations = {
+                let state = state.lock().await;
+                // Checked before reusing result of recent registration too
+                check_registration_allowed(state.config(), &state.persistent_state().await)?;
+                state.registrations()
             };
+            let key = (client_ip.clone(), refresh);
+            registrations
+                .run(key, || {
+                    register_client(state, client_ip, client, refresh, crate::ipset::IPSet::new)
+                })
+                .await
+        },
+    )
+    .await
+}
 
-            info!("Adding {client_ip} to ACL ipset");
-            if let Err(err) = ipset_acl.add(&client_ip, timeout) {
-                error!("Unable to add client to ACL ipset: {}", err);
-                return Err(APIError::InternalError);
-            }
+/// Registration itself, run once for concurrent identical requests. `new_set` makes ipset
+/// of given name
+async fn register_client(
+    state: Data<Arc<Mutex<State>>>,
+    client_ip: String,
+    client: Client,
+    refresh: bool,
+    new_set: impl Fn(&str) -> crate::ipset::IPSet,
+) -> Result<String, APIError> {
+    let state = state.lock().await;
+
+    let ipset_acl = new_set(state.config().ipset_names(&client_ip).acl);
+
+    if check_blacklisted(&state, &client) {
+        return Err(APIError::Blacklisted(state.config().blacklist_message()));
+    }
+    let (ipset_name, timeout) =
+        registration_ipset(state.config(), &client_ip, &client, &chrono::Utc::now());
+    let ipset = new_set(ipset_name);
 
-            info!("Adding {client_ip} to {ipset_name} ipset");
-            if let Err(err) = ipset_shaper.add(&client_ip, timeout) {
-                error!("Unable to add client to {:?} ipset: {}", ipset_name, err);
-                return Err(APIError::InternalError);
+    add_client_to_ipsets(&ipset_acl, &ipset, &client_ip, Some(timeout), refresh)
+        .await
+        .inspect_err(|_| state.counters().inc_ipset_errors())?;
+    if state.config().max_acl_size.is_some() {
+        match ipset_acl.count().await {
+            Ok(count) => {
+                state.spawn_acl_size_check(count);
             }
+            Err(err) => error!("Unable to count ACL entries: {err}"),
+        }
+    }
+
+    if refresh {
+        return Ok(String::new());
+    }
+    state.counters().inc_registrations();
+
+    if let Client::Mac(mac) = &client {
+        if let Err(err) = state.record_client_session(mac).await {
+            error!("Unable to record client session: {err}");
+        }
+    }
 
-            Ok(String::new())
+    let mac = match &client {
+        Client::Mac(mac) => mac.as_str(),
+        Client::Whitelist | Client::Static => "",
+    };
+    state.spawn_on_register(&client_ip, mac);
+
+    Ok(String::new())
+}
+
+#[delete("/api/v1/client")]
+async fn client_deregister(
+    state: Data<Arc<Mutex<State>>>,
+    req: HttpRequest,
+) -> Result<String, APIError> {
+    check_writable(state.lock().await.config())?;
+    with_client(
+        state.clone(),
+        &req,
+        |client_ip: String, client| async move {
+            info!("Client requested deregistration");
+
+            let state = state.lock().await;
+            let registrations = state.registrations();
+            for refresh in [false, true] {
+                registrations.forget(&(client_ip.clone(), refresh));
+            }
+            match state.remove_client_everywhere(&client_ip).await {
+                Ok(removed_from) => {
+                    state.counters().inc_deregistrations();
+                    if let Client::Mac(mac) = &client {
+                        if let Err(err) = state.record_client_deregistration(mac).await {
+                            error!("Unable to record client deregistration: {err}");
+                        }
+                    }
+                    Ok(serde_json::ser::to_string(&removed_from).unwrap())
+                }
+                Err(err) => Err(ipset_error(
+                    &state,
+                    "Unable to remove client from ipsets",
+                    err,
+                )),
+            }
         },
     )
     .await
 }
 
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    enabled: bool,
+}
+
+#[post("/api/v1/maintenance")]
+async fn maintenance(
+    state: Data<Arc<Mutex<State>>>,
+    req: HttpRequest,
+    body: Json<MaintenanceRequest>,
+) -> Result<String, APIError> {
+    let state = state.lock().await;
+    check_admin(&req, state.config())?;
+
+    info!("Setting maintenance mode to {}", body.enabled);
+    if let Err(err) = state.set_maintenance(body.enabled).await {
+        error!("Unable to update maintenance mode: {err}");
+        return Err(APIError::InternalError);
+    }
+    Ok(String::new())
+}
+
 #[derive(Serialize)]
-struct DhcpRecord {
-    pub ip: String,
-    pub mac: Option<String>,
-    pub hostname: Option<String>,
-    pub client_hostname: Option<String>,
-    pub vendor_class_identifier: Option<String>,
-    pub starts: Option<String>,
-    pub ends: Option<String>,
-    pub acl: Option<crate::ipset::Entry>,
-    pub shaper: Option<crate::ipset::Entry>,
+struct ReloadResult {
+    reloaded: bool,
+    error: Option<String>,
 }
 
-#[get("/api/v1/dhcp")]
-async fn dhcp_leases(state: Data<Arc<Mutex<State>>>) -> Result<String, APIError> {
-    info!("Client requested DHCP leases");
+#[post("/api/v1/reload")]
+async fn reload(
+    state: Data<Arc<Mutex<State>>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    let mut state = state.lock().await;
+    check_admin(&req, state.config())?;
+
+    info!("Reloading config by API request");
+    Ok(match state.reload_config() {
+        Ok(()) => HttpResponse::Ok().json(ReloadResult {
+            reloaded: true,
+            error: None,
+        }),
+        Err(err) => {
+            warn!("Failed to reload config: {err:#}");
+            HttpResponse::UnprocessableEntity().json(ReloadResult {
+                reloaded: false,
+                error: Some(format!("{err:#}")),
+            })
+        }
+    })
+}
+
+#[get("/api/v1/debug/commands")]
+async fn debug_commands(
+    state: Data<Arc<Mutex<State>>>,
+    req: HttpRequest,
+) -> Result<Json<Vec<crate::command_log::CommandRecord>>, APIError> {
+    let state = state.lock().await;
+    check_admin(&req, state.config())?;
+    Ok(Json(state.command_log().records()))
+}
+
+#[get("/api/v1/telegram/queue")]
+async fn telegram_queue(
+    state: Data<Arc<Mutex<State>>>,
+    req: HttpRequest,
+) -> Result<Json<Vec<crate::persistent_state::TelegramMessage>>, APIError> {
     let state = state.lock().await;
+    check_admin(&req, state.config())?;
+    Ok(Json(state.persistent_state().await.telegram_queue))
+}
 
-    let ipset_acl = crate::ipset::IPSet::new(&state.config().ipset_acl_name);
-    let ipset_shaper = crate::ipset::IPSet::new(&state.config().ipset_acl_name);
+#[derive(Deserialize)]
+struct IPSetMemberQuery {
+    ip: String,
+}
 
-    let mut leases = Vec::new();
-    for lease in crate::dhcp::Dhcp::read(&state.config().dhcpd_leases)
-        .map_err(|_| APIError::InternalError)?
-        .all()
-    {
-        let record = DhcpRecord {
-            mac: lease.hardware.map(|v| v.mac),
-            hostname: lease.hostname,
-            client_hostname: lease.client_hostname,
-            vendor_class_identifier: lease.vendor_class_identifier,
-            starts: lease.dates.starts.map(|v| v.to_string()),
-            ends: lease.dates.ends.map(|v| v.to_string()),
-            acl: ipset_acl
-                .entries()
-                .map_err(|_| APIError::InternalError)?
-                .into_iter()
-                .find(|acl| acl.ip == lease.ip),
-            shaper: ipset_shaper
-                .entries()
-                .map_err(|_| APIError::InternalError)?
-                .into_iter()
-                .find(|acl| acl.ip == lease.ip),
-            ip: lease.ip,
-        };
+#[get("/api/v1/ipset/member")]
+async fn ipset_member(
+    state: Data<Arc<Mutex<State>>>,
+    query: Query<IPSetMemberQuery>,
+    req: HttpRequest,
+) -> Result<Json<Vec<crate::ipset::Membership>>, APIError> {
+    let state = state.lock().await;
+    check_admin(&req, state.config())?;
 
-        leases.push(record)
+    if query.ip.parse::<std::net::IpAddr>().is_err() {
+        return Err(APIError::BadRequest(format!("invalid IP {:?}", query.ip)));
     }
+    info!("Looking up ipsets membership of {}", query.ip);
 
-    Ok(serde_json::ser::to_string(&leases).unwrap())
+    let names = state.config().ipset_names(&query.ip);
+    let sets = [names.acl, names.shaper, names.no_shape].map(crate::ipset::IPSet::new);
+    match crate::ipset::IPSet::find_everywhere(&sets, &query.ip).await {
+        Ok(memberships) => Ok(Json(memberships)),
+        Err(err) => {
+            error!("Unable to get ipsets entries: {err}");
+            Err(APIError::InternalError)
+        }
+    }
 }
 
-#[get("/metrics")]
-async fn prometheus_exporter(state: Data<Arc<Mutex<State>>>) -> Result<String, APIError> {
-    use prometheus_exporter_base::prelude::*;
+#[derive(Deserialize)]
+struct IPSetFlushQuery {
+    set: String,
+}
 
-    info!("Client requested prometheus exporter data");
+#[derive(Serialize)]
+struct IPSetFlushResult {
+    set: String,
+    /// Number of entries in the set before flush, unknown if the set couldn't be listed
+    removed: Option<usize>,
+}
 
+#[delete("/api/v1/ipset/flush")]
+async fn ipset_flush(
+    state: Data<Arc<Mutex<State>>>,
+    query: Query<IPSetFlushQuery>,
+    req: HttpRequest,
+) -> Result<Json<IPSetFlushResult>, APIError> {
     let state = state.lock().await;
+    check_admin(&req, state.config())?;
+    check_writable(state.config())?;
 
-    let ipset_acl = crate::ipset::IPSet::new(&state.config().ipset_acl_name);
-    let ipset_shaper = crate::ipset::IPSet::new(&state.config().ipset_shaper_name);
-
-    let persistent_state = state.persistent_state().await;
-
-    let mut metrics = Vec::new();
-    metrics.push(
-        PrometheusMetric::build()
-            .with_name("ratzek_internet_available")
-            .with_metric_type(MetricType::Gauge)
-            .with_help("Flag of wide internet availability")
-            .build()
-            .render_and_append_instance(
-                &PrometheusInstance::new()
-                    .with_value(persistent_state.is_wide_network_available.unwrap_or(false) as i8),
-            )
-            .render(),
-    );
-
-    if let Some(speedtest_result) = persistent_state.speedtest {
-        metrics.push(
-            PrometheusMetric::build()
-                .with_name("ratzek_speedtest_download")
-                .with_metric_type(MetricType::Gauge)
-                .with_help("Speedtest download speed")
-                .build()
-                .render_and_append_instance(
-                    &PrometheusInstance::new().with_value(speedtest_result.download),
-                )
-                .render(),
-        );
-        metrics.push(
-            PrometheusMetric::build()
-                .with_name("ratzek_speedtest_upload")
-                .with_metric_type(MetricType::Gauge)
-                .with_help("Speedtest upload speed")
-                .build()
-                .render_and_append_instance(
-                    &PrometheusInstance::new().with_value(speedtest_result.upload),
-                )
-                .render(),
-        );
-        metrics.push(
-            PrometheusMetric::build()
-                .with_name("ratzek_speedtest_ping")
-                .with_metric_type(MetricType::Gauge)
-                .with_help("Speedtest ping speed")
-                .build()
-                .render_and_append_instance(
-                    &PrometheusInstance::new().with_value(speedtest_result.ping),
-                )
-                .render(),
-        );
+    // Only sets managed by the backend may be flushed, not arbitrary system ones
+    let config = state.config();
+    let is_configured = [
+        Some(&config.ipset_acl_name),
+        Some(&config.ipset_shaper_name),
+        Some(&config.ipset_no_shape_name),
+        config.ipset_acl_name_v6.as_ref(),
+        config.ipset_shaper_name_v6.as_ref(),
+        config.ipset_no_shape_name_v6.as_ref(),
+    ]
+    .contains(&Some(&query.set));
+    if !is_configured {
+        return Err(APIError::BadRequest(format!(
+            "ipset {:?} is not configured",
+            query.set
+        )));
     }
 
-    if let Some(balance) = persistent_state.balance {
-        metrics.push(
-            PrometheusMetric::build()
-                .with_name("ratzek_isp_balance")
-                .with_metric_type(MetricType::Gauge)
-                .with_help("ISP balance")
-                .build()
-                .render_and_append_instance(&PrometheusInstance::new().with_value(balance))
-                .render(),
-        );
+    let ipset = crate::ipset::IPSet::new(&query.set);
+    let removed = match ipset.entries().await {
+        Ok(entries) => Some(entries.len()),
+        Err(err) => {
+            warn!(
+                "Unable to count {} ipset entries before flush: {err}",
+                query.set
+            );
+            None
+        }
+    };
+    info!("Flushing ipset {}", query.set);
+    if let Err(err) = ipset.flush().await {
+        error!("Unable to flush {} ipset: {err}", query.set);
+        return Err(APIError::InternalError);
     }
+    Ok(Json(IPSetFlushResult {
+        set: query.set.clone(),
+        removed,
+    }))
+}
 
-    if let Some(last_tariff_update) = persistent_state.last_tariff_update {
-        metrics.push(
-            PrometheusMetric::build()
-                .with_name("ratzek_last_tariff_update")
-                .with_metric_type(MetricType::Gauge)
-                .with_help("Last tariff update")
-                .build()
-                .render_and_append_instance(
-                    &PrometheusInstance::new()
-                        .with_value((last_tariff_update - chrono::Utc::now()).num_seconds()),
-                )
-                .render(),
-        );
-    }
+/// Maximum entry timeout supported by ipset, in seconds
+const MAX_IPSET_TIMEOUT: u64 = 2147483;
 
-    metrics.push(
-        PrometheusMetric::build()
-            .with_name("ratzek_clients_in_acl")
-            .with_metric_type(MetricType::Gauge)
-            .with_help("Number of clients in ACL")
-            .build()
-            .render_and_append_instance(
-                &PrometheusInstance::new().with_value(
-                    ipset_acl
-                        .entries()
-                        .map_err(|err| {
-                            error!("failed to get ACL entries: {}", err);
-                            APIError::InternalError
-                        })?
-                        .len(),
-                ),
-            )
-            .render(),
-    );
-    metrics.push(
-        PrometheusMetric::build()
-            .with_name("ratzek_clients_in_shaper")
-            .with_metric_type(MetricType::Gauge)
-            .with_help("Number of clients in shaper")
-            .build()
-            .render_and_append_instance(
-                &PrometheusInstance::new().with_value(
-                    ipset_shaper
-                        .entries()
-                        .map_err(|err| {
-                            error!("failed to get shaper entries: {}", err);
-                            APIError::InternalError
-                        })?
-                        .len(),
-                ),
-            )
-            .render(),
-    );
+#[derive(Deserialize)]
+struct WhitelistRequest {
+    ip: std::net::IpAddr,
+    #[serde(with = "humantime_serde")]
+    duration: std::time::Duration,
+}
 
-    let leases = crate::dhcp::Dhcp::read(&state.config().dhcpd_leases)
-        .map_err(|_| APIError::InternalError)?
-        .all();
+#[derive(Serialize)]
+struct WhitelistResult {
+    ip: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
 
-    for (name, state) in [
-        ("free", dhcpd_parser::leases::BindingState::Free),
-        ("active", dhcpd_parser::leases::BindingState::Active),
-        ("abandoned", dhcpd_parser::leases::BindingState::Abandoned),
-    ] {
-        metrics.push(
-            PrometheusMetric::build()
-                .with_name(&format!("ratzek_dhcp_leases_{}", name))
-                .with_metric_type(MetricType::Gauge)
-                .with_help(&format!("Number of {} DHCP leases", name))
-                .build()
-                .render_and_append_instance(
-                    &PrometheusInstance::new()
-                        .with_value(leases.iter().filter(|v| v.binding_state == state).count()),
-                )
-                .render(),
-        )
+/// Adds `ip` to ACL and no_shape ipset for `duration`, resetting timeouts if it is already
+/// there. Returns the timeout in seconds. Zero timeout would make entries permanent, so it
+/// is refused
+async fn whitelist_ip(
+    ipset_acl: &crate::ipset::IPSet,
+    ipset_no_shape: &crate::ipset::IPSet,
+    ip: &str,
+    duration: std::time::Duration,
+) -> Result<u64, APIError> {
+    let timeout = duration.as_secs();
+    if !(1..=MAX_IPSET_TIMEOUT).contains(&timeout) {
+        return Err(APIError::BadRequest(format!(
+            "duration must be from 1s to {MAX_IPSET_TIMEOUT}s"
+        )));
     }
+    info!("Temporarily whitelisting {ip} for {timeout}s");
+    add_client_to_ipsets(ipset_acl, ipset_no_shape, ip, Some(timeout), true).await?;
+    Ok(timeout)
+}
+
+/// Grants unshaped access to IP until ipset entries expire. Nothing is persisted
+#[post("/api/v1/whitelist")]
+async fn whitelist(
+    state: Data<Arc<Mutex<State>>>,
+    req: HttpRequest,
+    body: Json<WhitelistRequest>,
+) -> Result<Json<WhitelistResult>, APIError> {
+    let state = state.lock().await;
+    check_admin(&req, state.config())?;
+    check_writable(state.config())?;
+
+    let ip = body.ip.to_string();
+    let names = state.config().ipset_names(&ip);
+    let timeout = whitelist_ip(
+        &crate::ipset::IPSet::new(names.acl),
+        &crate::ipset::IPSet::new(names.no_shape),
+        &ip,
+        body.duration,
+    )
+    .await
+    .inspect_err(|err| {
+        if matches!(err, APIError::InternalError | APIError::PartialRegistration) {
+            state.counters().inc_ipset_errors();
+        }
+    })?;
+    Ok(Json(WhitelistResult {
+        ip,
+        expires_at: chrono::Utc::now() + chrono::TimeDelta::seconds(timeout as i64),
+    }))
+}
+
+#[derive(Deserialize)]
+struct ClientHistoryQuery {
+    mac: String,
+}
+
+#[get("/api/v1/client/history")]
+async fn client_history(
+    state: Data<Arc<Mutex<State>>>,
+    req: HttpRequest,
+    query: Query<ClientHistoryQuery>,
+) -> Result<String, APIError> {
+    let state = state.lock().await;
+    check_admin(&req, state.config())?;
+    info!("Client history requested for {}", query.mac);
+
+    let timezone = state.config().timezone;
+    let history = state
+        .persistent_state()
+        .await
+        .client_history(&query.mac)
+        .iter()
+        .map(|v| crate::config::to_local_time(v, timezone))
+        .collect::<Vec<_>>();
+    Ok(serde_json::ser::to_string(&history).unwrap())
+}
+
+#[derive(Serialize)]
+struct DhcpRecord {
+    pub ip: String,
+    pub mac: Option<String>,
+    pub hostname: Option<String>,
+    pub client_hostname: Option<String>,
+    pub vendor_class_identifier: Option<String>,
+    /// See `device_categories`
+    pub category: Option<String>,
+    pub starts: Option<String>,
+    pub ends: Option<String>,
+    pub ends_in_secs: Option<i64>,
+    pub acl: Option<crate::ipset::Entry>,
+    pub shaper: Option<crate::ipset::Entry>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum DhcpSort {
+    #[default]
+    Ip,
+    Hostname,
+    Ends,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum DhcpFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+struct DhcpQuery {
+    #[serde(default)]
+    sort: DhcpSort,
+    #[serde(default)]
+    order: SortOrder,
+    /// Comma-separated list of returned fields, all fields if missing
+    fields: Option<String>,
+    #[serde(default)]
+    format: DhcpFormat,
+}
+
+/// Names of `DhcpRecord` fields, which can be requested with `fields` parameter
+const DHCP_RECORD_FIELDS: &[&str] = &[
+    "ip",
+    "mac",
+    "hostname",
+    "client_hostname",
+    "vendor_class_identifier",
+    "category",
+    "starts",
+    "ends",
+    "ends_in_secs",
+    "acl",
+    "shaper",
+];
+
+/// Parses `fields` parameter, all fields if missing
+fn dhcp_fields(fields: Option<&str>) -> Vec<&str> {
+    match fields {
+        Some(fields) => fields.split(',').map(str::trim).collect(),
+        None => DHCP_RECORD_FIELDS.to_vec(),
+    }
+}
+
+/// Keeps only requested fields of each record
+fn project_dhcp_records(
+    records: &[DhcpRecord],
+    fields: Option<&str>,
+) -> Result<Vec<serde_json::Value>, APIError> {
+    let fields = dhcp_fields(fields);
+    if let Some(unknown) = fields.iter().find(|v| !DHCP_RECORD_FIELDS.contains(v)) {
+        return Err(APIError::BadRequest(format!("unknown field {unknown:?}")));
+    }
+
+    Ok(records
+        .iter()
+        .map(|record| {
+            let mut value = serde_json::to_value(record).unwrap();
+            if let Some(object) = value.as_object_mut() {
+                object.retain(|key, _| fields.contains(&key.as_str()));
+            }
+            value
+        })
+        .collect())
+}
+
+/// Records without countdowns, which change every second, so ETag changes only with data.
+/// Clients revalidating with it may keep stale `ends_in_secs` and ipset entry timeouts
+fn dhcp_records_etag_data(records: &[serde_json::Value]) -> String {
+    let mut records = records.to_vec();
+    for object in records.iter_mut().filter_map(|v| v.as_object_mut()) {
+        object.remove("ends_in_secs");
+        for set in ["acl", "shaper"] {
+            if let Some(entry) = object.get_mut(set).and_then(|v| v.as_object_mut()) {
+                entry.remove("timeout");
+            }
+        }
+    }
+    serde_json::to_string(&records).unwrap()
+}
+
+/// Quotes CSV field if it contains separators, quotes or line breaks
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Prefixes text which spreadsheets would take for a formula with `'`. Hostnames are set by
+/// clients, so they may contain anything
+fn escape_csv_formula(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{value}").into()
+    } else {
+        value.into()
+    }
+}
+
+/// Renders projected records as CSV with header row. Missing values are left empty,
+/// ipset entries are written as JSON
+fn dhcp_records_to_csv(records: &[serde_json::Value], fields: Option<&str>) -> String {
+    let fields = dhcp_fields(fields);
+
+    let mut csv = fields.join(",") + "\n";
+    for record in records {
+        let row = fields
+            .iter()
+            .map(|field| match &record[field] {
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::String(v) => csv_field(&escape_csv_formula(v)),
+                v => csv_field(&v.to_string()),
+            })
+            .collect::<Vec<_>>();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Compares optional values keeping missing ones at the end regardless of order
+fn cmp_missing_last<T: Ord>(a: Option<T>, b: Option<T>, order: SortOrder) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    match (a, b) {
+        (Some(a), Some(b)) => match order {
+            SortOrder::Asc => a.cmp(&b),
+            SortOrder::Desc => b.cmp(&a),
+        },
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+fn sort_dhcp_records(records: &mut [DhcpRecord], sort: DhcpSort, order: SortOrder) {
+    match sort {
+        DhcpSort::Ip => records.sort_by(|a, b| {
+            cmp_missing_last(
+                a.ip.parse::<std::net::IpAddr>().ok(),
+                b.ip.parse::<std::net::IpAddr>().ok(),
+                order,
+            )
+        }),
+        DhcpSort::Hostname => records.sort_by(|a, b| {
+            cmp_missing_last(
+                a.hostname.as_ref().or(a.client_hostname.as_ref()),
+                b.hostname.as_ref().or(b.client_hostname.as_ref()),
+                order,
+            )
+        }),
+        DhcpSort::Ends => {
+            records.sort_by(|a, b| cmp_missing_last(a.ends_in_secs, b.ends_in_secs, order))
+        }
+    }
+}
+
+fn build_dhcp_record(
+    lease: dhcpd_parser::leases::Lease,
+    acl_entries: &[crate::ipset::Entry],
+    shaper_entries: &[crate::ipset::Entry],
+    config: &crate::config::Config,
+    now: chrono::DateTime<chrono::Utc>,
+) -> DhcpRecord {
+    let ends = lease.dates.ends.map(|v| v.to_string());
+    DhcpRecord {
+        category: lease
+            .vendor_class_identifier
+            .as_deref()
+            .and_then(|v| config.device_category(v))
+            .map(str::to_string),
+        mac: lease.hardware.map(|v| v.mac),
+        hostname: lease.hostname,
+        client_hostname: lease.client_hostname,
+        vendor_class_identifier: lease.vendor_class_identifier,
+        starts: lease.dates.starts.map(|v| v.to_string()),
+        ends_in_secs: crate::dhcp::seconds_until(ends.as_deref(), now),
+        ends,
+        acl: acl_entries.iter().find(|v| v.ip == lease.ip).cloned(),
+        shaper: shaper_entries.iter().find(|v| v.ip == lease.ip).cloned(),
+        ip: lease.ip,
+    }
+}
+
+/// Looks up lease of `ip` along with its ipsets membership. Sets are listed only if the
+/// lease exists
+async fn find_dhcp_record(
+    config: &crate::config::Config,
+    ip: &str,
+    ipset_acl: &crate::ipset::IPSet,
+    ipset_shaper: &crate::ipset::IPSet,
+) -> anyhow::Result<Option<DhcpRecord>> {
+    let Some(lease) = crate::dhcp::Dhcp::read(&config.dhcpd_leases)?
+        .all()
+        .into_iter()
+        .find(|lease| lease.ip == ip)
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(build_dhcp_record(
+        lease,
+        &ipset_acl.entries().await?,
+        &ipset_shaper.entries().await?,
+        config,
+        chrono::Utc::now(),
+    )))
+}
+
+#[get("/api/v1/dhcp/{ip}")]
+async fn dhcp_lease(
+    state: Data<Arc<Mutex<State>>>,
+    ip: actix_web::web::Path<String>,
+) -> Result<Json<DhcpRecord>, APIError> {
+    info!("Client requested DHCP lease of {ip}");
+    if ip.parse::<std::net::IpAddr>().is_err() {
+        return Err(APIError::BadRequest(format!(
+            "invalid IP {:?}",
+            ip.as_str()
+        )));
+    }
+    let state = state.lock().await;
+
+    let names = state.config().ipset_names(&ip);
+    let ipset_acl = crate::ipset::IPSet::new(names.acl);
+    let ipset_shaper = crate::ipset::IPSet::new(names.shaper);
+    match find_dhcp_record(state.config(), &ip, &ipset_acl, &ipset_shaper).await {
+        Ok(Some(record)) => Ok(Json(record)),
+        Ok(None) => Err(APIError::NotFound),
+        Err(err) => {
+            error!("Unable to look up DHCP lease: {err}");
+            Err(APIError::InternalError)
+        }
+    }
+}
+
+#[get("/api/v1/dhcp")]
+async fn dhcp_leases(
+    state: Data<Arc<Mutex<State>>>,
+    query: Query<DhcpQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    info!("Client requested DHCP leases");
+    let mut state = state.lock().await;
+
+    let sets = |names: Vec<&str>| {
+        names
+            .into_iter()
+            .map(crate::ipset::IPSet::new)
+            .collect::<Vec<_>>()
+    };
+    let acl_entries = crate::ipset::IPSet::entries_of(&sets(state.config().acl_ipset_names()))
+        .await
+        .map_err(|_| APIError::InternalError)?;
+    let shaper_entries =
+        crate::ipset::IPSet::entries_of(&sets(state.config().shaper_ipset_names()))
+            .await
+            .map_err(|_| APIError::InternalError)?;
+
+    let now = chrono::Utc::now();
+    let mut leases = crate::dhcp::Dhcp::read(&state.config().dhcpd_leases)
+        .map_err(|_| APIError::InternalError)?
+        .all()
+        .into_iter()
+        .map(|lease| build_dhcp_record(lease, &acl_entries, &shaper_entries, state.config(), now))
+        .collect::<Vec<_>>();
+
+    sort_dhcp_records(&mut leases, query.sort, query.order);
+    let leases = project_dhcp_records(&leases, query.fields.as_deref())?;
+
+    if let DhcpFormat::Csv = query.format {
+        return Ok(HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .body(dhcp_records_to_csv(&leases, query.fields.as_deref())));
+    }
+
+    let body = serde_json::ser::to_string(&leases).unwrap();
+    Ok(state.response_cache().respond(
+        format!("dhcp?{}", req.query_string()),
+        &req,
+        dhcp_records_etag_data(&leases),
+        body,
+    ))
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct ClientCounts {
+    acl: usize,
+    shaper: usize,
+    no_shape: usize,
+}
+
+/// Everything the dashboard shows, in one response
+#[derive(Serialize)]
+struct Status {
+    is_internet_available: Option<bool>,
+    speedtest: Option<crate::speedtest::SpeedTestReport>,
+    balance: Option<f64>,
+    clients: ClientCounts,
+}
+
+fn build_status(
+    persistent_state: crate::persistent_state::PersistentState,
+    clients: ClientCounts,
+) -> Status {
+    Status {
+        is_internet_available: persistent_state.is_wide_network_available,
+        speedtest: persistent_state.speedtest.map(Into::into),
+        balance: persistent_state.balance,
+        clients,
+    }
+}
+
+#[get("/api/v1/status")]
+async fn service_status(state: Data<Arc<Mutex<State>>>) -> Result<Json<Status>, APIError> {
+    info!("Client requested status");
+    let state = state.lock().await;
+
+    // Sets of both address families are counted together
+    let count = |names: Vec<&str>| {
+        let sets = names
+            .into_iter()
+            .map(crate::ipset::IPSet::new)
+            .collect::<Vec<_>>();
+        async move {
+            crate::ipset::IPSet::entries_of(&sets)
+                .await
+                .map(|entries| entries.len())
+                .map_err(|err| {
+                    error!("Unable to get ipset entries: {err}");
+                    APIError::InternalError
+                })
+        }
+    };
+    let clients = ClientCounts {
+        acl: count(state.config().acl_ipset_names()).await?,
+        shaper: count(state.config().shaper_ipset_names()).await?,
+        no_shape: count(state.config().no_shape_ipset_names()).await?,
+    };
+
+    Ok(Json(build_status(state.persistent_state().await, clients)))
+}
+
+#[get("/api/v1/leases/summary")]
+async fn leases_summary(state: Data<Arc<Mutex<State>>>) -> Result<String, APIError> {
+    info!("Client requested DHCP leases summary");
+    let state = state.lock().await;
+
+    let leases = crate::dhcp::Dhcp::read(&state.config().dhcpd_leases)
+        .map_err(|_| APIError::InternalError)?
+        .all();
+
+    Ok(
+        serde_json::ser::to_string(&crate::dhcp::LeasesSummary::count(
+            &leases,
+            state.config().abandoned_as_free,
+        ))
+        .unwrap(),
+    )
+}
+
+#[get("/metrics")]
+async fn prometheus_exporter(state: Data<Arc<Mutex<State>>>) -> Result<String, APIError> {
+    info!("Client requested prometheus exporter data");
+
+    let state = state.lock().await;
+
+    let metrics = crate::metrics::Metrics::collect(&state)
+        .await
+        .map_err(|err| {
+            error!("Failed to collect metrics: {:#}", err);
+            APIError::InternalError
+        })?;
+
+    Ok(metrics.render())
+}
+
+#[get("/readyz")]
+async fn readyz(state: Data<Arc<Mutex<State>>>) -> HttpResponse {
+    let (config, telegram_reachability) = {
+        let state = state.lock().await;
+        (state.config().clone(), state.telegram_reachability())
+    };
+    let readiness = crate::readiness::Readiness::check(&config, &telegram_reachability).await;
+    if !readiness.ready {
+        warn!("Service is not ready");
+    }
+
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    HttpResponse::build(status).json(readiness)
+}
+
+#[cfg(test)]
+fn test_persistent_state(is_internet_available: bool) -> crate::persistent_state::PersistentState {
+    crate::persistent_state::PersistentState {
+        is_wide_network_available: Some(is_internet_available),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+fn test_entry(ip: &str, timeout: u64, bytes: Option<usize>) -> crate::ipset::Entry {
+    crate::ipset::Entry {
+        ip: ip.to_string(),
+        timeout: Some(std::time::Duration::from_secs(timeout)),
+        bytes,
+        nomatch: false,
+    }
+}
+
+#[test]
+fn test_poll_after_secs_scales_with_timeout() {
+    let config = crate::config::test_config();
+    let client = Client::Mac("00:11:22:33:44:55".to_string());
+    let poll_after = |acl_timeout| {
+        let acl = [test_entry("10.11.2.10", acl_timeout, None)];
+        build_service_info(
+            "10.11.2.10",
+            &client,
+            &acl,
+            &[],
+            &config,
+            &test_persistent_state(true),
+        )
+        .poll_after_secs
+    };
+
+    assert_eq!(poll_after(20), MIN_POLL_AFTER_SECS);
+    assert_eq!(poll_after(600), 60);
+    assert_eq!(poll_after(1800), 180);
+    assert_eq!(poll_after(36000), MAX_POLL_AFTER_SECS);
+    let intervals = [60, 300, 900, 1800, 2400].map(poll_after);
+    assert!(intervals.windows(2).all(|v| v[0] < v[1]), "{intervals:?}");
+
+    let inactive = build_service_info(
+        "10.11.2.10",
+        &client,
+        &[],
+        &[],
+        &config,
+        &test_persistent_state(true),
+    );
+    assert_eq!(inactive.poll_after_secs, MIN_POLL_AFTER_SECS);
+}
+
+#[test]
+fn test_build_service_info_connected() {
+    let config = crate::config::test_config();
+    let acl = [test_entry("10.11.2.10", 600, None)];
+    let shaper = [
+        test_entry("10.11.2.10", 300, Some(1000)),
+        test_entry("10.11.2.11", 300, Some(2000)),
+    ];
+
+    let info = build_service_info(
+        "10.11.2.10",
+        &Client::Mac("00:11:22:33:44:55".to_string()),
+        &acl,
+        &shaper,
+        &config,
+        &test_persistent_state(true),
+    );
+
+    assert_eq!(info.internet_clients_connected, 2);
+    assert!(info.is_internet_available);
+    assert_eq!(info.poll_after_secs, 60);
+    match info.internet_connection_status {
+        InternetConnectionStatus::Connected(info) => {
+            assert_eq!(info.bytes_sent, 1000);
+            assert_eq!(info.bytes_unlimited_limit, config.unlimited_limit_bytes());
+            assert_eq!(info.shaper_reset_secs, 300);
+            assert_eq!(info.connection_forget_secs, 600);
+            assert!(!info.is_currently_shaped);
+        }
+        _ => panic!("Client must be connected"),
+    }
+}
+
+#[test]
+fn test_build_service_info_mac_override() {
+    let mut config = crate::config::test_config();
+    config.mac_overrides.insert(
+        "AA:BB:CC:DD:EE:FF".to_string(),
+        crate::config::MacOverride {
+            bytes_unlimited_limit: Some(20000000),
+        },
+    );
+    let acl = [test_entry("10.11.2.10", 600, None)];
+    let shaper = [test_entry("10.11.2.10", 300, Some(10000000))];
+
+    for (mac, limit, is_shaped) in [
+        ("aa:bb:cc:dd:ee:ff", 20000000, false),
+        ("00:11:22:33:44:55", 5000000, true),
+    ] {
+        let info = build_service_info(
+            "10.11.2.10",
+            &Client::Mac(mac.to_string()),
+            &acl,
+            &shaper,
+            &config,
+            &test_persistent_state(true),
+        );
+        match info.internet_connection_status {
+            InternetConnectionStatus::Connected(info) => {
+                assert_eq!(info.bytes_unlimited_limit, limit, "{mac}");
+                assert_eq!(info.is_currently_shaped, is_shaped, "{mac}");
+            }
+            _ => panic!("Client must be connected"),
+        }
+    }
+}
+
+#[test]
+fn test_build_service_info_shaped() {
+    let config = crate::config::test_config();
+    let acl = [test_entry("10.11.2.10", 600, None)];
+
+    for (bytes, is_shaped) in [
+        (config.unlimited_limit_bytes() - 1, false),
+        (config.unlimited_limit_bytes(), true),
+        (config.unlimited_limit_bytes() + 1, true),
+    ] {
+        let shaper = [test_entry("10.11.2.10", 300, Some(bytes))];
+        let info = build_service_info(
+            "10.11.2.10",
+            &Client::Mac("00:11:22:33:44:55".to_string()),
+            &acl,
+            &shaper,
+            &config,
+            &test_persistent_state(true),
+        );
+        match info.internet_connection_status {
+            InternetConnectionStatus::Connected(info) => {
+                assert_eq!(info.is_currently_shaped, is_shaped, "bytes = {bytes}")
+            }
+            _ => panic!("Client must be connected"),
+        }
+    }
+}
+
+#[test]
+fn test_build_service_info_inactive() {
+    let config = crate::config::test_config();
+    let acl = [test_entry("10.11.2.11", 600, None)];
+
+    let info = build_service_info(
+        "10.11.2.10",
+        &Client::Mac("00:11:22:33:44:55".to_string()),
+        &acl,
+        &[],
+        &config,
+        &test_persistent_state(false),
+    );
+
+    assert!(matches!(
+        info.internet_connection_status,
+        InternetConnectionStatus::Inactive
+    ));
+}
+
+#[test]
+fn test_build_service_info_expired() {
+    let config = crate::config::test_config();
+    let now = chrono::Utc::now();
+    let mut persistent_state = test_persistent_state(true);
+    persistent_state.record_client_session(
+        "00:11:22:33:44:55",
+        now - chrono::TimeDelta::hours(1),
+        5,
+    );
+    persistent_state.record_client_session(
+        "00:11:22:33:44:66",
+        now - chrono::TimeDelta::days(2),
+        5,
+    );
+    // Deregistered explicitly, so its entry didn't expire
+    persistent_state.record_client_session(
+        "00:11:22:33:44:88",
+        now - chrono::TimeDelta::hours(2),
+        5,
+    );
+    persistent_state
+        .record_client_deregistration("00:11:22:33:44:88", now - chrono::TimeDelta::hours(1));
+
+    for (mac, expired) in [
+        ("00:11:22:33:44:55", true),
+        ("00:11:22:33:44:66", false),
+        ("00:11:22:33:44:77", false),
+        ("00:11:22:33:44:88", false),
+    ] {
+        let info = build_service_info(
+            "10.11.2.10",
+            &Client::Mac(mac.to_string()),
+            &[],
+            &[],
+            &config,
+            &persistent_state,
+        );
+        if expired {
+            assert!(
+                matches!(
+                    info.internet_connection_status,
+                    InternetConnectionStatus::Expired
+                ),
+                "{mac}"
+            );
+        } else {
+            assert!(
+                matches!(
+                    info.internet_connection_status,
+                    InternetConnectionStatus::Inactive
+                ),
+                "{mac}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_build_service_info_blacklisted() {
+    let mut config = crate::config::test_config();
+    config.blacklisted_macs = vec!["00:11:22:33:44:55".to_string()];
+    let acl = [test_entry("10.11.2.10", 600, None)];
+
+    let info = build_service_info(
+        "10.11.2.10",
+        &Client::Mac("00:11:22:33:44:55".to_string()),
+        &acl,
+        &[],
+        &config,
+        &test_persistent_state(true),
+    );
+
+    assert!(matches!(
+        info.internet_connection_status,
+        InternetConnectionStatus::ClientBlacklisted
+    ));
+}
+
+#[test]
+fn test_refuse_register_when_down() {
+    let mut config = crate::config::test_config();
+    let down = test_persistent_state(false);
+    let up = test_persistent_state(true);
+    assert!(check_registration_allowed(&config, &down).is_ok());
+
+    config.refuse_register_when_down = true;
+    let err = check_registration_allowed(&config, &down).unwrap_err();
+    assert!(matches!(err, APIError::UpstreamUnavailable));
+    assert_eq!(
+        actix_web::error::ResponseError::status_code(&err),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(err.code(), "upstream_unavailable");
+    assert!(check_registration_allowed(&config, &up).is_ok());
+    // Network state is unknown until the first ping
+    assert!(check_registration_allowed(&config, &Default::default()).is_ok());
+}
+
+#[test]
+fn test_maintenance() {
+    let config = crate::config::test_config();
+    let persistent_state = crate::persistent_state::PersistentState {
+        maintenance: true,
+        ..Default::default()
+    };
+
+    assert!(matches!(
+        check_registration_allowed(&config, &persistent_state),
+        Err(APIError::Maintenance)
+    ));
+    assert!(check_registration_allowed(&config, &Default::default()).is_ok());
+
+    let acl = [test_entry("10.11.2.10", 600, None)];
+    let info = build_service_info(
+        "10.11.2.10",
+        &Client::Mac("00:11:22:33:44:55".to_string()),
+        &acl,
+        &[],
+        &config,
+        &persistent_state,
+    );
+    assert!(info.is_maintenance);
+    assert!(matches!(
+        info.internet_connection_status,
+        InternetConnectionStatus::Connected(_)
+    ));
+}
+
+#[test]
+fn test_check_admin() {
+    use actix_web::test::TestRequest;
+
+    let mut config = crate::config::test_config();
+    let req = TestRequest::default()
+        .insert_header(("x-api-key", "secret"))
+        .to_http_request();
+    assert!(check_admin(&req, &config).is_err());
+
+    config.admin_api_key = Some("secret".to_string());
+    assert!(check_admin(&req, &config).is_ok());
+    assert!(check_admin(&TestRequest::default().to_http_request(), &config).is_err());
+}
+
+#[test]
+fn test_client_ip_custom_header() {
+    use actix_web::test::TestRequest;
+
+    let mut config = crate::config::test_config();
+    config.client_ip_header = "cf-connecting-ip".to_string();
+    let req = TestRequest::default()
+        .peer_addr("10.11.1.1:12345".parse().unwrap())
+        .insert_header(("cf-connecting-ip", "10.11.2.10"))
+        .insert_header(("x-real-ip", "10.11.2.20"))
+        .to_http_request();
+    assert_eq!(client_ip(&req, &config).as_deref(), Some("10.11.2.10"));
+
+    config.trusted_proxies = vec!["10.11.1.2".parse().unwrap()];
+    assert_eq!(client_ip(&req, &config).as_deref(), Some("10.11.1.1"));
+
+    config.trusted_proxies = vec!["10.11.1.1".parse().unwrap()];
+    assert_eq!(client_ip(&req, &config).as_deref(), Some("10.11.2.10"));
+}
+
+#[cfg(test)]
+fn test_dhcp_record(ip: &str, ends_in_secs: Option<i64>) -> DhcpRecord {
+    DhcpRecord {
+        ip: ip.to_string(),
+        mac: None,
+        hostname: None,
+        client_hostname: None,
+        vendor_class_identifier: None,
+        category: None,
+        starts: None,
+        ends: None,
+        ends_in_secs,
+        acl: None,
+        shaper: None,
+    }
+}
+
+#[test]
+fn test_sort_dhcp_records_by_ip() {
+    let mut records = vec![
+        test_dhcp_record("10.11.2.100", None),
+        test_dhcp_record("10.11.2.9", None),
+        test_dhcp_record("10.11.2.10", None),
+    ];
+
+    sort_dhcp_records(&mut records, DhcpSort::Ip, SortOrder::Asc);
+    let ips = records.iter().map(|v| v.ip.as_str()).collect::<Vec<_>>();
+    assert_eq!(ips, ["10.11.2.9", "10.11.2.10", "10.11.2.100"]);
+
+    sort_dhcp_records(&mut records, DhcpSort::Ip, SortOrder::Desc);
+    let ips = records.iter().map(|v| v.ip.as_str()).collect::<Vec<_>>();
+    assert_eq!(ips, ["10.11.2.100", "10.11.2.10", "10.11.2.9"]);
+}
+
+#[test]
+fn test_sort_dhcp_records_by_ends() {
+    let mut records = vec![
+        test_dhcp_record("10.11.2.1", Some(600)),
+        test_dhcp_record("10.11.2.2", None),
+        test_dhcp_record("10.11.2.3", Some(-60)),
+    ];
+
+    sort_dhcp_records(&mut records, DhcpSort::Ends, SortOrder::Asc);
+    let ips = records.iter().map(|v| v.ip.as_str()).collect::<Vec<_>>();
+    assert_eq!(ips, ["10.11.2.3", "10.11.2.1", "10.11.2.2"]);
+
+    sort_dhcp_records(&mut records, DhcpSort::Ends, SortOrder::Desc);
+    let ips = records.iter().map(|v| v.ip.as_str()).collect::<Vec<_>>();
+    assert_eq!(ips, ["10.11.2.1", "10.11.2.3", "10.11.2.2"]);
+}
+
+#[test]
+fn test_project_dhcp_records() {
+    let records = [test_dhcp_record("10.11.2.1", Some(600))];
+
+    let projected = project_dhcp_records(&records, Some("ip,ends_in_secs")).unwrap();
+    assert_eq!(
+        projected,
+        [serde_json::json!({"ip": "10.11.2.1", "ends_in_secs": 600})]
+    );
+
+    let all = project_dhcp_records(&records, None).unwrap();
+    let keys = all[0]
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut expected = DHCP_RECORD_FIELDS.to_vec();
+    expected.sort();
+    assert_eq!(keys, expected);
+
+    assert!(matches!(
+        project_dhcp_records(&records, Some("ip,password")),
+        Err(APIError::BadRequest(_))
+    ));
+}
+
+#[test]
+fn test_dhcp_records_etag_data() {
+    let record = |mac: &str, ends_in_secs: i64, timeout: u64| {
+        serde_json::json!({
+            "ip": "10.11.2.1",
+            "mac": mac,
+            "ends_in_secs": ends_in_secs,
+            "acl": {"ip": "10.11.2.1", "timeout": {"secs": timeout, "nanos": 0}},
+        })
+    };
+    let data = dhcp_records_etag_data(&[record("aa:bb:cc:dd:ee:ff", 600, 3600)]);
+    assert_eq!(
+        data,
+        dhcp_records_etag_data(&[record("aa:bb:cc:dd:ee:ff", 599, 3599)])
+    );
+    assert_ne!(
+        data,
+        dhcp_records_etag_data(&[record("aa:bb:cc:dd:ee:00", 600, 3600)])
+    );
+}
+
+#[actix_web::test]
+async fn test_read_only_refuses_mutations() {
+    use actix_web::{test, App};
+
+    let mut config = crate::config::test_config();
+    config.read_only = true;
+    let state = State::new(&config).await.unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(state))
+            .service(client_register)
+            .service(client_deregister),
+    )
+    .await;
+
+    for req in [test::TestRequest::post(), test::TestRequest::delete()] {
+        let resp = test::call_service(&app, req.uri("/api/v1/client").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
+
+#[test]
+fn test_build_status() {
+    let persistent_state = crate::persistent_state::PersistentState {
+        is_wide_network_available: Some(true),
+        speedtest: Some(crate::speedtest::SpeedTest {
+            download: 25_000_000.0,
+            upload: 5_125_000.0,
+            ping: 20.0,
+            tool: None,
+        }),
+        balance: Some(548.08),
+        ..Default::default()
+    };
+    let status = build_status(
+        persistent_state,
+        ClientCounts {
+            acl: 3,
+            shaper: 2,
+            no_shape: 1,
+        },
+    );
+
+    assert_eq!(
+        serde_json::to_value(&status).unwrap(),
+        serde_json::json!({
+            "is_internet_available": true,
+            "speedtest": {
+                "download": 25_000_000.0,
+                "upload": 5_125_000.0,
+                "ping": 20.0,
+                "download_mbps": 25.0,
+                "upload_mbps": 5.13,
+            },
+            "balance": 548.08,
+            "clients": {"acl": 3, "shaper": 2, "no_shape": 1},
+        })
+    );
+}
+
+#[actix_web::test]
+async fn test_add_client_rolls_back_acl() {
+    let (ipset, log) = crate::ipset::fake_ipset(
+        "register-rollback",
+        r#"[ "$1" = "add" ] && [ "$2" = "shaper" ] && exit 1
+exit 0"#,
+    );
+    let acl = crate::ipset::IPSet::with_command("acl", &ipset);
+    let shaper = crate::ipset::IPSet::with_command("shaper", &ipset);
+
+    assert!(matches!(
+        add_client_to_ipsets(&acl, &shaper, "10.11.2.10", Some(1800), false).await,
+        Err(APIError::InternalError)
+    ));
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "add acl 10.11.2.10 timeout 1800\nadd shaper 10.11.2.10 timeout 1800\ndel acl 10.11.2.10\n"
+    );
+
+    let (ipset, _) = crate::ipset::fake_ipset(
+        "register-partial",
+        r#"[ "$2" = "shaper" ] && exit 1
+[ "$1" = "del" ] && { echo "ipset v7.15: Kernel error" >&2; exit 1; }
+exit 0"#,
+    );
+    let acl = crate::ipset::IPSet::with_command("acl", &ipset);
+    let shaper = crate::ipset::IPSet::with_command("shaper", &ipset);
+    assert!(matches!(
+        add_client_to_ipsets(&acl, &shaper, "10.11.2.10", Some(1800), false).await,
+        Err(APIError::PartialRegistration)
+    ));
+}
+
+#[test]
+fn test_registration_ipset_no_shape_window() {
+    let mut config = crate::config::test_config();
+    config.timezone = Some("Asia/Bishkek".parse().unwrap());
+    config.no_shape_windows = vec![crate::config::TimeWindow {
+        start: "22:00".parse().unwrap(),
+        end: "07:00".parse().unwrap(),
+    }];
+    let client = Client::Mac("aa:bb:cc:dd:ee:ff".to_string());
+
+    // 23:00 in Asia/Bishkek
+    let night = chrono::DateTime::parse_from_rfc3339("2024-01-04T17:00:00Z").unwrap();
+    assert_eq!(
+        registration_ipset(&config, "10.11.2.10", &client, &night),
+        ("no_shape", config.shaping_timeout)
+    );
+
+    // 12:00 in Asia/Bishkek
+    let day = chrono::DateTime::parse_from_rfc3339("2024-01-04T06:00:00Z").unwrap();
+    assert_eq!(
+        registration_ipset(&config, "10.11.2.10", &client, &day),
+        ("shaper", config.shaping_timeout)
+    );
+    assert_eq!(
+        registration_ipset(&config, "10.11.2.10", &Client::Whitelist, &day),
+        ("no_shape", config.no_shaping_timeout)
+    );
+
+    config.no_shape_windows.clear();
+    assert_eq!(
+        registration_ipset(&config, "10.11.2.10", &client, &night),
+        ("shaper", config.shaping_timeout)
+    );
+}
+
+#[actix_web::test]
+async fn test_ipset_flush_rejects_unconfigured_set() {
+    use actix_web::{test, App};
+
+    let mut config = crate::config::test_config();
+    config.admin_api_key = Some("secret".to_string());
+    let state = State::new(&config).await.unwrap();
+    let app = test::init_service(App::new().app_data(Data::new(state)).service(ipset_flush)).await;
+
+    let req = test::TestRequest::delete()
+        .uri("/api/v1/ipset/flush?set=docker")
+        .insert_header(("x-api-key", "secret"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::delete()
+        .uri("/api/v1/ipset/flush?set=acl")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_concurrent_registrations() {
+    let state = State::new(&crate::config::test_config()).await.unwrap();
+    let registrations = state.lock().await.registrations();
+    let (ipset, log) = crate::ipset::fake_ipset("concurrent-registrations", "exit 0");
+    let (failing_ipset, _) = crate::ipset::fake_ipset("concurrent-registrations-failure", "exit 1");
+    let register = |ip: &'static str, ipset: &std::path::PathBuf| {
+        let state = Data::new(state.clone());
+        let ipset = ipset.clone();
+        registrations.run((ip.to_string(), false), move || {
+            register_client(state, ip.to_string(), Client::Static, false, move |name| {
+                crate::ipset::IPSet::with_command(name, &ipset)
+            })
+        })
+    };
+    // State is held until both calls start, so the second one waits for the first
+    let register_concurrently = |ip, ipset| {
+        let (state, register) = (&state, &register);
+        async move {
+            let guard = state.lock().await;
+            let release = async {
+                tokio::task::yield_now().await;
+                drop(guard);
+            };
+            let (r, ()) = tokio::join!(
+                async { tokio::join!(register(ip, ipset), register(ip, ipset)) },
+                release
+            );
+            r
+        }
+    };
+    let ipset_errors = || async { state.lock().await.counters().snapshot().ipset_errors };
+
+    let (first, second) = register_concurrently("10.11.2.10", &ipset).await;
+    assert!(first.is_ok() && second.is_ok());
+    let log = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        log.lines().filter(|v| v.starts_with("add acl ")).count(),
+        1,
+        "{log}"
+    );
+
+    let (first, second) = register_concurrently("10.11.2.11", &failing_ipset).await;
+    assert!(matches!(first, Err(APIError::InternalError)));
+    assert!(matches!(second, Err(APIError::InternalError)));
+    assert_eq!(ipset_errors().await, 1);
+
+    // Failure isn't reused by the next call
+    assert!(register("10.11.2.11", &failing_ipset).await.is_err());
+    assert_eq!(ipset_errors().await, 2);
+}
+
+#[actix_web::test]
+async fn test_client_history_requires_admin() {
+    use actix_web::{test, App};
+
+    let mut config = crate::config::test_config();
+    config.admin_api_key = Some("secret".to_string());
+    let state = State::new(&config).await.unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(state))
+            .service(client_history),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/client/history?mac=00:11:22:33:44:55")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/client/history?mac=00:11:22:33:44:55")
+        .insert_header(("x-api-key", "secret"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_telegram_queue() {
+    use actix_web::{test, App};
+
+    let path = crate::persistent_state::TempStatePath::new("telegram-queue");
+    std::fs::write(
+        &path,
+        r#"
+is_wide_network_available: null
+speedtest: null
+last_tariff_update: null
+balance: null
+telegram_queue:
+  - chat_id: "123456789"
+    text: Low balance
+    timestamp: 2024-01-04T18:00:00+06:00
+"#,
+    )
+    .unwrap();
+    let mut config = crate::config::test_config();
+    config.admin_api_key = Some("secret".to_string());
+    config.persistent_state_path = path.to_path_buf();
+    let state = State::new(&config).await.unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(state))
+            .service(telegram_queue),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/telegram/queue")
+        .insert_header(("x-api-key", "secret"))
+        .to_request();
+    let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(queue.as_array().unwrap().len(), 1);
+    assert_eq!(queue[0]["chat_id"], "123456789");
+    assert_eq!(queue[0]["text"], "Low balance");
+    assert!(queue[0]["timestamp"].is_string());
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/telegram/queue")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn test_resolve_client_static() {
+    let mut config = crate::config::test_config();
+    config.allow_unknown_static = vec!["10.11.3.0/24".parse().unwrap()];
+
+    assert!(matches!(
+        resolve_client(&config, "10.11.2.10"),
+        Ok(Client::Mac(mac)) if mac == "00:11:22:33:44:55"
+    ));
+    assert!(matches!(
+        resolve_client(&config, "10.11.3.15"),
+        Ok(Client::Static)
+    ));
+    assert!(matches!(
+        resolve_client(&config, "10.11.4.15"),
+        Err(APIError::NotOnNetwork)
+    ));
+
+    config.allow_unknown_static.clear();
+    assert!(resolve_client(&config, "10.11.3.15").is_err());
+}
+
+#[test]
+fn test_dhcp_records_to_csv() {
+    let mut record = test_dhcp_record("10.11.2.10", Some(60));
+    record.hostname = Some("office, \"main\" pc".to_string());
+    let records = project_dhcp_records(&[record], None).unwrap();
+
+    let csv = dhcp_records_to_csv(&records, None);
+    let lines = csv.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[0],
+        "ip,mac,hostname,client_hostname,vendor_class_identifier,category,starts,ends,ends_in_secs,acl,shaper"
+    );
+    assert!(
+        lines[1].starts_with("10.11.2.10,,\"office, \"\"main\"\" pc\","),
+        "{}",
+        lines[1]
+    );
+    assert!(lines[1].ends_with(",60,,"), "{}", lines[1]);
+
+    let records = project_dhcp_records(
+        &[test_dhcp_record("10.11.2.10", None)],
+        Some("ends_in_secs,ip"),
+    )
+    .unwrap();
+    assert_eq!(
+        dhcp_records_to_csv(&records, Some("ends_in_secs,ip")),
+        "ends_in_secs,ip\n,10.11.2.10\n"
+    );
+
+    let mut record = test_dhcp_record("10.11.2.10", Some(-5));
+    record.hostname = Some("=HYPERLINK(\"http://evil\",\"pc\")".to_string());
+    record.client_hostname = Some("@SUM(1+1)".to_string());
+    let records =
+        project_dhcp_records(&[record], Some("hostname,client_hostname,ends_in_secs")).unwrap();
+    assert_eq!(
+        dhcp_records_to_csv(&records, Some("hostname,client_hostname,ends_in_secs")),
+        "hostname,client_hostname,ends_in_secs\n\
+         \"'=HYPERLINK(\"\"http://evil\"\",\"\"pc\"\")\",'@SUM(1+1),-5\n"
+    );
+}
+
+#[actix_web::test]
+async fn test_find_dhcp_record() {
+    let (ipset, _) = crate::ipset::fake_ipset(
+        "dhcp-record",
+        r#"case "$2" in
+acl) printf "add acl 10.11.2.10 timeout 600\n" ;;
+shaper) printf "add shaper 10.11.2.10 timeout 300 bytes 1000\n" ;;
+esac"#,
+    );
+    let acl = crate::ipset::IPSet::with_command("acl", &ipset);
+    let shaper = crate::ipset::IPSet::with_command("shaper", &ipset);
+    let config = crate::config::test_config();
+
+    let record = find_dhcp_record(&config, "10.11.2.10", &acl, &shaper)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(record.mac.as_deref(), Some("00:11:22:33:44:55"));
+    assert_eq!(
+        record.acl.unwrap().timeout,
+        Some(std::time::Duration::from_secs(600))
+    );
+    assert_eq!(record.shaper.unwrap().bytes, Some(1000));
+
+    let record = find_dhcp_record(&config, "10.11.2.9", &acl, &shaper)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(record.acl.is_none());
+    assert!(record.shaper.is_none());
+
+    assert!(find_dhcp_record(&config, "10.11.9.9", &acl, &shaper)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_web::test]
+async fn test_dhcp_lease_not_found() {
+    use actix_web::{test, App};
+
+    let state = State::new(&crate::config::test_config()).await.unwrap();
+    let app = test::init_service(App::new().app_data(Data::new(state)).service(dhcp_lease)).await;
+
+    for (uri, status) in [
+        ("/api/v1/dhcp/10.11.9.9", StatusCode::NOT_FOUND),
+        ("/api/v1/dhcp/not-an-ip", StatusCode::BAD_REQUEST),
+    ] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(resp.status(), status, "{uri}");
+    }
+}
+
+#[test]
+fn test_registration_ipset_v6() {
+    let mut config = crate::config::test_config();
+    config.ipset_shaper_name_v6 = Some("shaper6".to_string());
+    let client = Client::Mac("aa:bb:cc:dd:ee:ff".to_string());
+    let now = chrono::Utc::now();
+
+    assert_eq!(
+        registration_ipset(&config, "10.11.2.10", &client, &now).0,
+        "shaper"
+    );
+    assert_eq!(
+        registration_ipset(&config, "2001:db8::10", &client, &now).0,
+        "shaper6"
+    );
+    // No IPv6 no_shape set configured, IPv4 one is used
+    assert_eq!(
+        registration_ipset(&config, "2001:db8::10", &Client::Whitelist, &now).0,
+        "no_shape"
+    );
+}
+
+#[actix_web::test]
+async fn test_auto_register_whitelisted() {
+    let (ipset, log) = crate::ipset::fake_ipset(
+        "auto-register-whitelist",
+        r#"[ "$1" = test ] && echo "Warning: $3 is NOT in set $2." >&2 && exit 1
+exit 0"#,
+    );
+    let acl = crate::ipset::IPSet::with_command("acl", &ipset);
+    let no_shape = crate::ipset::IPSet::with_command("no_shape", &ipset);
+    let mut config = crate::config::test_config();
+    config.auto_register_whitelist = true;
+    let persistent_state = test_persistent_state(true);
+
+    assert!(auto_register_whitelisted(
+        &config,
+        &persistent_state,
+        &Client::Whitelist,
+        "10.11.2.5",
+        &acl,
+        &no_shape
+    )
+    .await
+    .unwrap());
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "test acl 10.11.2.5\nadd acl 10.11.2.5 timeout 3600\nadd no_shape 10.11.2.5 timeout 3600\n"
+    );
+
+    // Only whitelisted clients are registered, and only if enabled
+    std::fs::remove_file(&log).unwrap();
+    let client = Client::Mac("00:11:22:33:44:55".to_string());
+    assert!(!auto_register_whitelisted(
+        &config,
+        &persistent_state,
+        &client,
+        "10.11.2.10",
+        &acl,
+        &no_shape
+    )
+    .await
+    .unwrap());
+    config.auto_register_whitelist = false;
+    assert!(!auto_register_whitelisted(
+        &config,
+        &persistent_state,
+        &Client::Whitelist,
+        "10.11.2.5",
+        &acl,
+        &no_shape
+    )
+    .await
+    .unwrap());
+    assert!(!log.exists());
+
+    // Nor when registrations are refused
+    config.auto_register_whitelist = true;
+    let in_maintenance = crate::persistent_state::PersistentState {
+        maintenance: true,
+        ..test_persistent_state(true)
+    };
+    config.refuse_register_when_down = true;
+    for persistent_state in [in_maintenance, test_persistent_state(false)] {
+        assert!(!auto_register_whitelisted(
+            &config,
+            &persistent_state,
+            &Client::Whitelist,
+            "10.11.2.5",
+            &acl,
+            &no_shape
+        )
+        .await
+        .unwrap());
+    }
+    assert!(!log.exists());
+}
+
+#[test]
+fn test_api_error_codes() {
+    for (err, status, code) in [
+        (
+            APIError::NotOnNetwork,
+            StatusCode::FORBIDDEN,
+            "not_on_network",
+        ),
+        (
+            APIError::Blacklisted("blocked".to_string()),
+            StatusCode::FORBIDDEN,
+            "blacklisted",
+        ),
+        (
+            APIError::RateLimited,
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+        ),
+        (
+            APIError::Maintenance,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "maintenance",
+        ),
+        (
+            APIError::InternalError,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "backend_error",
+        ),
+    ] {
+        use actix_web::ResponseError;
+
+        assert_eq!(err.status_code(), status, "{code}");
+        assert_eq!(err.code(), code);
+        assert_eq!(err.error_response().status(), status);
+    }
+}
+
+#[actix_web::test]
+async fn test_client_error_codes() {
+    use actix_web::{test, App};
+
+    let mut config = crate::config::test_config();
+    config.blacklisted_macs = vec!["00:11:22:33:44:55".to_string()];
+    let state = State::new(&config).await.unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(state.clone()))
+            .service(client_get)
+            .service(client_register),
+    )
+    .await;
+
+    for (req, status, code) in [
+        (
+            test::TestRequest::get().insert_header(("x-real-ip", "10.11.9.9")),
+            StatusCode::FORBIDDEN,
+            "not_on_network",
+        ),
+        (
+            test::TestRequest::post().insert_header(("x-real-ip", "10.11.2.10")),
+            StatusCode::FORBIDDEN,
+            "blacklisted",
+        ),
+    ] {
+        let resp = test::call_service(&app, req.uri("/api/v1/client").to_request()).await;
+        assert_eq!(resp.status(), status, "{code}");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], code);
+    }
+    assert_eq!(
+        state
+            .lock()
+            .await
+            .counters()
+            .snapshot()
+            .blacklisted_attempts
+            .get("00:11:22:33:44:55"),
+        Some(&1)
+    );
+}
+
+#[actix_web::test]
+async fn test_blacklisted_attempts_counted() {
+    use actix_web::{test, App};
+
+    let mut config = crate::config::test_config();
+    config.blacklisted_macs = vec!["00:11:22:33:44:55".to_string()];
+    let state = State::new(&config).await.unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(state.clone()))
+            .service(client_get)
+            .service(client_register),
+    )
+    .await;
+
+    // Status info may fail without ipset, but the attempt is counted before listing sets
+    for req in [test::TestRequest::get(), test::TestRequest::post()] {
+        let req = req
+            .insert_header(("x-real-ip", "10.11.2.10"))
+            .uri("/api/v1/client")
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let counters = state.lock().await.counters().snapshot();
+    assert_eq!(counters.blacklisted_attempts["00:11:22:33:44:55"], 2);
+    assert_eq!(counters.registrations, 0);
+}
+
+#[actix_web::test]
+async fn test_whitelist_ip_timeout() {
+    let (ipset, log) = crate::ipset::fake_ipset("whitelist", "exit 0");
+    let acl = crate::ipset::IPSet::with_command("acl", &ipset);
+    let no_shape = crate::ipset::IPSet::with_command("no_shape", &ipset);
+
+    assert_eq!(
+        whitelist_ip(
+            &acl,
+            &no_shape,
+            "10.11.2.10",
+            std::time::Duration::from_secs(600)
+        )
+        .await
+        .unwrap(),
+        600
+    );
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "-exist add acl 10.11.2.10 timeout 600\n-exist add no_shape 10.11.2.10 timeout 600\n"
+    );
+
+    for duration in [0, MAX_IPSET_TIMEOUT + 1] {
+        assert!(matches!(
+            whitelist_ip(
+                &acl,
+                &no_shape,
+                "10.11.2.10",
+                std::time::Duration::from_secs(duration)
+            )
+            .await,
+            Err(APIError::BadRequest(_))
+        ));
+    }
+}
+
+#[actix_web::test]
+async fn test_blacklist_message() {
+    use actix_web::{test, App};
+
+    let mut config = crate::config::test_config();
+    config.blacklisted_macs = vec!["00:11:22:33:44:55".to_string()];
+    config.blacklist_message = "Device is blocked, call {contact}".to_string();
+    config.support_contact = Some("+996 555 000 000".to_string());
+    let state = State::new(&config).await.unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(state))
+            .service(client_register),
+    )
+    .await;
 
-    Ok(metrics.join(""))
+    let req = test::TestRequest::post()
+        .uri("/api/v1/client")
+        .insert_header(("x-real-ip", "10.11.2.10"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["code"], "blacklisted");
+    assert_eq!(body["message"], "Device is blocked, call +996 555 000 000");
 }
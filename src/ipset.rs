@@ -1,30 +1,139 @@
 use anyhow::{anyhow, bail, Result};
-use serde::Serialize;
-use std::{collections::VecDeque, process::Stdio};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, sync::OnceLock};
+use tokio::sync::Semaphore;
 
-#[derive(Debug, Serialize)]
+/// Default number of `ipset` processes allowed to run at once
+pub const DEFAULT_CONCURRENCY_LIMIT: usize = 4;
+
+/// Bounds number of concurrently running `ipset` processes
+static CONCURRENCY_LIMIT: OnceLock<Semaphore> = OnceLock::new();
+
+/// Sets limit shared by all sets. Takes effect only if called before the first `ipset` run
+pub fn set_concurrency_limit(permits: usize) {
+    let _ = CONCURRENCY_LIMIT.set(Semaphore::new(permits));
+}
+
+fn concurrency_limit() -> &'static Semaphore {
+    CONCURRENCY_LIMIT.get_or_init(|| Semaphore::new(DEFAULT_CONCURRENCY_LIMIT))
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Entry {
+    /// IP address, or CIDR for `hash:net` sets
     pub ip: String,
     pub timeout: Option<std::time::Duration>,
     pub bytes: Option<usize>,
+    /// Entry is an exception from a wider network in the same set
+    pub nomatch: bool,
+}
+
+/// Parses a line of `ipset save` output. Returns `None` for lines without entries
+fn parse_line(line: &str) -> Result<Option<Entry>> {
+    let elts = line.split(' ').collect::<Vec<_>>();
+    let (ip, tail) = match elts.as_slice() {
+        ["add", _, ip, tail @ ..] => (ip.to_string(), tail),
+        ["create", ..] => return Ok(None),
+        [""] => return Ok(None),
+        _ => bail!("Unexpected line in ipset output: {}", line),
+    };
+
+    let mut tail = VecDeque::from(tail.to_vec());
+
+    let mut timeout = None;
+    let mut bytes = None;
+    let mut nomatch = false;
+
+    while let Some(name) = tail.pop_front() {
+        match name {
+            "timeout" => {
+                timeout = tail
+                    .pop_front()
+                    .and_then(|v| v.parse::<u64>().ok().map(std::time::Duration::from_secs))
+            }
+            "bytes" => bytes = tail.pop_front().and_then(|v| v.parse::<usize>().ok()),
+            "nomatch" => nomatch = true,
+            _ => continue,
+        }
+    }
+
+    Ok(Some(Entry {
+        ip,
+        timeout,
+        bytes,
+        nomatch,
+    }))
+}
+
+/// Entry of the named set
+#[derive(Debug, Serialize)]
+pub struct Membership {
+    pub set: String,
+    #[serde(flatten)]
+    pub entry: Entry,
 }
 
 pub struct IPSet {
     name: String,
+    command: std::path::PathBuf,
+    limit: &'static Semaphore,
 }
 
 impl IPSet {
     pub fn new(name: &str) -> Self {
+        Self::with_command(name, "ipset")
+    }
+
+    /// Same as `new`, but runs `command` instead of `ipset` from `PATH`
+    pub fn with_command(name: &str, command: impl Into<std::path::PathBuf>) -> Self {
         Self {
             name: name.to_string(),
+            command: command.into(),
+            limit: concurrency_limit(),
         }
     }
 
-    pub fn entries(&self) -> Result<Vec<Entry>> {
-        let output = std::process::Command::new("ipset")
-            .args(["save", &self.name])
-            .stdout(Stdio::piped())
-            .output()?;
+    #[cfg(test)]
+    fn with_limit(mut self, limit: &'static Semaphore) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Runs `ipset` once a concurrency permit is available
+    async fn run<I, S>(&self, args: I) -> std::io::Result<std::process::Output>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let _permit = self.limit.acquire().await.map_err(std::io::Error::other)?;
+        tokio::process::Command::new(&self.command)
+            .args(args)
+            .output()
+            .await
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Entries of all `sets`, e.g. IPv4 set and its IPv6 counterpart
+    pub async fn entries_of(sets: &[IPSet]) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for set in sets {
+            entries.extend(set.entries().await?);
+        }
+        Ok(entries)
+    }
+
+    pub async fn entries(&self) -> Result<Vec<Entry>> {
+        let output = self.run(["save", &self.name]).await?;
+
+        if !output.status.success() {
+            bail!(
+                "Got non-zero exit code: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        }
 
         let output = String::from_utf8(output.stdout)
             .map_err(|err| anyhow!("Decode command output: {}", err))?;
@@ -32,46 +141,44 @@ pub fn entries(&self) -> Result<Vec<Entry>> {
         let mut result = Vec::new();
 
         for line in output.split('\n') {
-            let elts = line.split(' ').collect::<Vec<_>>();
-            let (ip, tail) = match elts.as_slice() {
-                ["add", _, ip, tail @ ..] => (ip.to_string(), tail),
-                ["create", ..] => continue,
-                [""] => continue,
-                _ => bail!("Unexpected line in ipset output: {}", line),
-            };
-
-            let mut tail = VecDeque::from(tail.to_vec());
-
-            let mut timeout = None;
-            let mut bytes = None;
-
-            while tail.len() > 1 {
-                if let Some(name) = tail.pop_front() {
-                    match name {
-                        "timeout" => {
-                            timeout = tail.pop_front().and_then(|v| {
-                                v.parse::<u64>().ok().map(std::time::Duration::from_secs)
-                            })
-                        }
-                        "bytes" => bytes = tail.pop_front().and_then(|v| v.parse::<usize>().ok()),
-                        _ => continue,
-                    }
-                }
+            if let Some(entry) = parse_line(line)? {
+                result.push(entry)
             }
-
-            result.push(Entry { ip, timeout, bytes })
         }
 
         Ok(result)
     }
 
-    pub fn add(&self, entry: &str, timeout: Option<u64>) -> Result<()> {
+    /// Number of entries from `ipset list -terse` header, cheaper than listing the set
+    pub async fn count(&self) -> Result<usize> {
+        let output = self.run(["list", "-terse", &self.name]).await?;
+
+        if !output.status.success() {
+            bail!(
+                "Got non-zero exit code: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        }
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.strip_prefix("Number of entries:"))
+            .ok_or_else(|| anyhow!("No number of entries in ipset header"))?
+            .trim()
+            .parse()
+            .map_err(|err| anyhow!("Invalid number of entries: {err}"))
+    }
+
+    async fn run_add(&self, entry: &str, timeout: Option<u64>, exist: bool) -> Result<()> {
         let mut args = vec!["add".to_owned(), self.name.clone(), entry.to_owned()];
+        if exist {
+            args.insert(0, "-exist".to_owned());
+        }
         if let Some(timeout) = timeout {
             args.push("timeout".to_owned());
             args.push(format!("{}", timeout))
         }
-        let r = std::process::Command::new("ipset").args(args).output()?;
+        let r = self.run(args).await?;
 
         if !r.status.success() {
             bail!("Got non-zero exit code")
@@ -79,4 +186,417 @@ pub fn add(&self, entry: &str, timeout: Option<u64>) -> Result<()> {
 
         Ok(())
     }
+
+    pub async fn add(&self, entry: &str, timeout: Option<u64>) -> Result<()> {
+        self.run_add(entry, timeout, false).await
+    }
+
+    /// Adds entry or, if it is already in the set, resets its timeout to `timeout`.
+    /// `ipset add -exist` is idempotent: it never duplicates entries, but always restarts
+    /// the countdown.
+    pub async fn refresh(&self, entry: &str, timeout: Option<u64>) -> Result<()> {
+        self.run_add(entry, timeout, true).await
+    }
+
+    /// Creates the set. Does nothing if the set already exists
+    pub async fn create(&self, set_type: &str, options: &[&str]) -> Result<()> {
+        let r = self
+            .run(
+                ["-exist", "create", &self.name, set_type]
+                    .into_iter()
+                    .chain(options.iter().copied()),
+            )
+            .await?;
+
+        if !r.status.success() {
+            bail!(
+                "Got non-zero exit code: {}",
+                String::from_utf8_lossy(&r.stderr).trim()
+            )
+        }
+
+        Ok(())
+    }
+
+    /// Removes entry from the set. Returns `false` if entry was not in the set
+    pub async fn del(&self, entry: &str) -> Result<bool> {
+        let r = self.run(["del", &self.name, entry]).await?;
+
+        if r.status.success() {
+            return Ok(true);
+        }
+
+        let stderr = String::from_utf8_lossy(&r.stderr);
+        if stderr.contains("it's not added") {
+            return Ok(false);
+        }
+
+        bail!("Got non-zero exit code: {}", stderr.trim())
+    }
+
+    /// Removes all entries from the set
+    pub async fn flush(&self) -> Result<()> {
+        let r = self.run(["flush", &self.name]).await?;
+
+        if !r.status.success() {
+            bail!(
+                "Got non-zero exit code: {}",
+                String::from_utf8_lossy(&r.stderr).trim()
+            )
+        }
+
+        Ok(())
+    }
+
+    /// Checks if entry is in the set without listing the whole set
+    pub async fn test(&self, entry: &str) -> Result<bool> {
+        let r = self.run(["test", &self.name, entry]).await?;
+
+        if r.status.success() {
+            return Ok(true);
+        }
+
+        let stderr = String::from_utf8_lossy(&r.stderr);
+        if stderr.contains("is NOT in set") {
+            return Ok(false);
+        }
+
+        bail!("Got non-zero exit code: {}", stderr.trim())
+    }
+
+    /// Looks up entry in every set, returning its records in sets which contain it
+    pub async fn find_everywhere(sets: &[IPSet], entry: &str) -> Result<Vec<Membership>> {
+        let mut memberships = Vec::new();
+        for set in sets {
+            if let Some(found) = set.entries().await?.into_iter().find(|v| v.ip == entry) {
+                memberships.push(Membership {
+                    set: set.name.clone(),
+                    entry: found,
+                });
+            }
+        }
+        Ok(memberships)
+    }
+
+    /// Removes entry from every set, returning names of sets which contained it
+    pub async fn del_everywhere(sets: &[IPSet], entry: &str) -> Result<Vec<String>> {
+        let mut removed_from = Vec::new();
+        let mut last_err = None;
+        for set in sets {
+            match set.del(entry).await {
+                Ok(true) => removed_from.push(set.name.clone()),
+                Ok(false) => (),
+                Err(err) => {
+                    slog_scope::error!("Unable to remove {entry} from {} ipset: {err}", set.name);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok(removed_from),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SetSpec {
+    /// Set type, e.g. `hash:ip`
+    #[serde(rename = "type")]
+    pub set_type: String,
+    /// Create options, e.g. `[timeout, "0", counters]`
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// Sets created at startup if they don't exist yet
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AutoCreate {
+    #[serde(default)]
+    pub acl: Option<SetSpec>,
+    #[serde(default)]
+    pub shaper: Option<SetSpec>,
+    #[serde(default)]
+    pub no_shape: Option<SetSpec>,
+}
+
+impl AutoCreate {
+    pub async fn create(&self, config: &crate::config::Config) -> Result<()> {
+        self.create_with(config, IPSet::new).await
+    }
+
+    async fn create_with(
+        &self,
+        config: &crate::config::Config,
+        new_set: impl Fn(&str) -> IPSet,
+    ) -> Result<()> {
+        for (name, spec) in [
+            (&config.ipset_acl_name, &self.acl),
+            (&config.ipset_shaper_name, &self.shaper),
+            (&config.ipset_no_shape_name, &self.no_shape),
+        ] {
+            if let Some(spec) = spec {
+                slog_scope::info!("Creating ipset {name} of type {}", spec.set_type);
+                let options = spec.options.iter().map(String::as_str).collect::<Vec<_>>();
+                new_set(name)
+                    .create(&spec.set_type, &options)
+                    .await
+                    .map_err(|err| anyhow!("Unable to create ipset {name}: {err}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates a script which logs its arguments and acts as `ipset` with given shell `body`
+#[cfg(test)]
+pub fn fake_ipset(test_name: &str, body: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+    crate::util::fake_command(test_name, body)
+}
+
+#[actix_web::test]
+async fn test_concurrency_limit() {
+    let (ipset, log) = fake_ipset(
+        "concurrency-limit",
+        r#"running="$(dirname "$0")/running"
+mkdir -p "$running"
+touch "$running/$$"
+sleep 0.1
+ls "$running" | wc -l >> "$(dirname "$0")/concurrency.log"
+rm "$running/$$""#,
+    );
+    let concurrency_log = log.with_file_name("concurrency.log");
+    let _ = std::fs::remove_file(&concurrency_log);
+    let limit: &'static Semaphore = Box::leak(Box::new(Semaphore::new(2)));
+
+    let tasks = (0..8)
+        .map(|_| {
+            let set = IPSet::with_command("acl", &ipset).with_limit(limit);
+            actix_web::rt::spawn(async move { set.test("10.0.0.1").await })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+
+    let concurrency = std::fs::read_to_string(&concurrency_log)
+        .unwrap()
+        .lines()
+        .map(|v| v.trim().parse::<usize>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(concurrency.len(), 8);
+    assert!(
+        concurrency.iter().all(|v| (1..=2).contains(v)),
+        "{concurrency:?}"
+    );
+}
+
+#[actix_web::test]
+async fn test_count() {
+    let (ipset, log) = fake_ipset(
+        "count",
+        r#"[ "$3" = "missing" ] && { echo "ipset v7.15: The set with the given name does not exist" >&2; exit 1; }
+printf "Name: acl\nType: hash:ip\nRevision: 4\nHeader: family inet hashsize 1024 maxelem 65536 timeout 0\nSize in memory: 600\nReferences: 1\nNumber of entries: 3\n""#,
+    );
+    assert_eq!(IPSet::with_command("acl", &ipset).count().await.unwrap(), 3);
+    assert!(IPSet::with_command("missing", &ipset)
+        .count()
+        .await
+        .is_err());
+    assert_eq!(
+        std::fs::read_to_string(log).unwrap(),
+        "list -terse acl\nlist -terse missing\n"
+    );
+}
+
+#[actix_web::test]
+async fn test_entries_of() {
+    let (ipset, _) = fake_ipset(
+        "entries-of",
+        r#"case "$2" in
+acl) echo "add acl 10.11.2.10 timeout 3600" ;;
+acl6) echo "add acl6 2001:db8::10 timeout 3600" ;;
+esac"#,
+    );
+    let sets = ["acl", "acl6"].map(|name| IPSet::with_command(name, &ipset));
+    let entries = IPSet::entries_of(&sets).await.unwrap();
+    let ips = entries.iter().map(|v| v.ip.as_str()).collect::<Vec<_>>();
+    assert_eq!(ips, ["10.11.2.10", "2001:db8::10"]);
+}
+
+#[actix_web::test]
+async fn test_flush() {
+    let (ipset, log) = fake_ipset("flush", "exit 0");
+    IPSet::with_command("acl", &ipset).flush().await.unwrap();
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "flush acl\n");
+
+    let (ipset, _) = fake_ipset(
+        "flush-missing",
+        "echo 'The set with the given name does not exist' >&2; exit 1",
+    );
+    assert!(IPSet::with_command("acl", &ipset).flush().await.is_err());
+}
+
+#[actix_web::test]
+async fn test_refresh_resets_timeout() {
+    let (ipset, log) = fake_ipset("refresh", "exit 0");
+    let set = IPSet::with_command("acl", &ipset);
+
+    set.add("10.11.2.10", Some(300)).await.unwrap();
+    set.refresh("10.11.2.10", Some(600)).await.unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(log).unwrap(),
+        "add acl 10.11.2.10 timeout 300\n-exist add acl 10.11.2.10 timeout 600\n"
+    );
+}
+
+#[actix_web::test]
+async fn test_create_existing_set() {
+    let (ipset, log) = fake_ipset(
+        "create-existing",
+        r#"[ "$1" = "-exist" ] && exit 0
+echo "ipset v7.15: Set cannot be created: set with the same name already exists" >&2
+exit 1"#,
+    );
+    let set = IPSet::with_command("acl", &ipset);
+
+    set.create("hash:ip", &["timeout", "300"]).await.unwrap();
+    set.create("hash:ip", &["timeout", "300"]).await.unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(log).unwrap(),
+        "-exist create acl hash:ip timeout 300\n".repeat(2)
+    );
+}
+
+#[actix_web::test]
+async fn test_del_everywhere() {
+    let (ipset, log) = fake_ipset(
+        "del-everywhere",
+        r#"[ "$2" = "shaper" ] && exit 0
+echo "ipset v7.15: Element cannot be deleted from the set: it's not added" >&2
+exit 1"#,
+    );
+    let sets = ["acl", "shaper", "no_shape"].map(|name| IPSet::with_command(name, &ipset));
+
+    let removed_from = IPSet::del_everywhere(&sets, "10.11.2.10").await.unwrap();
+
+    assert_eq!(removed_from, ["shaper"]);
+    assert_eq!(
+        std::fs::read_to_string(log).unwrap(),
+        "del acl 10.11.2.10\ndel shaper 10.11.2.10\ndel no_shape 10.11.2.10\n"
+    );
+}
+
+#[actix_web::test]
+async fn test_auto_create() {
+    let (ipset, log) = fake_ipset("auto-create", "exit 0");
+    let config = crate::config::test_config();
+    let auto_create: AutoCreate = serde_yaml::from_str(
+        r#"
+acl:
+  type: hash:ip
+  options: [timeout, "0"]
+shaper:
+  type: hash:ip
+  options: [timeout, "0", counters]
+"#,
+    )
+    .unwrap();
+
+    auto_create
+        .create_with(&config, |name| IPSet::with_command(name, &ipset))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(log).unwrap(),
+        "-exist create acl hash:ip timeout 0\n-exist create shaper hash:ip timeout 0 counters\n"
+    );
+}
+
+#[test]
+fn test_parse_line_nomatch() {
+    let entry = parse_line("add set 10.0.0.0/24 nomatch").unwrap().unwrap();
+    assert_eq!(entry.ip, "10.0.0.0/24");
+    assert!(entry.nomatch);
+    assert_eq!(entry.timeout, None);
+
+    let entry = parse_line("add set 10.0.0.0/24 timeout 600 nomatch")
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.timeout, Some(std::time::Duration::from_secs(600)));
+    assert!(entry.nomatch);
+
+    let entry = parse_line("add set 10.0.1.0/28 nomatch bytes 42 timeout 60")
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.bytes, Some(42));
+    assert_eq!(entry.timeout, Some(std::time::Duration::from_secs(60)));
+
+    let entry = parse_line("add set 10.11.2.10 timeout 600")
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.ip, "10.11.2.10");
+    assert!(!entry.nomatch);
+
+    assert!(parse_line("create set hash:net family inet")
+        .unwrap()
+        .is_none());
+    assert!(parse_line("garbage").is_err());
+}
+
+#[actix_web::test]
+async fn test_find_everywhere() {
+    let (ipset, _) = fake_ipset(
+        "find-everywhere",
+        r#"case "$2" in
+acl) printf "add acl 10.11.2.9 timeout 100\nadd acl 10.11.2.10 timeout 600 bytes 42\n" ;;
+shaper) printf "add shaper 10.11.2.10 timeout 300\n" ;;
+esac"#,
+    );
+    let sets = ["acl", "shaper", "no_shape"].map(|name| IPSet::with_command(name, &ipset));
+
+    let memberships = IPSet::find_everywhere(&sets, "10.11.2.10").await.unwrap();
+
+    let found = memberships
+        .iter()
+        .map(|v| (v.set.as_str(), v.entry.timeout, v.entry.bytes))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        found,
+        [
+            ("acl", Some(std::time::Duration::from_secs(600)), Some(42)),
+            ("shaper", Some(std::time::Duration::from_secs(300)), None),
+        ]
+    );
+    assert!(IPSet::find_everywhere(&sets, "10.11.2.1")
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[actix_web::test]
+async fn test_test_membership() {
+    let (ipset, log) = fake_ipset(
+        "test-membership",
+        r#"[ "$2" = "missing" ] && { echo "ipset v7.15: The set with the given name does not exist" >&2; exit 1; }
+[ "$3" = "10.11.2.10" ] && { echo "10.11.2.10 is in set acl." >&2; exit 0; }
+echo "10.11.2.1 is NOT in set acl." >&2
+exit 1"#,
+    );
+    let acl = IPSet::with_command("acl", &ipset);
+
+    assert!(acl.test("10.11.2.10").await.unwrap());
+    assert!(!acl.test("10.11.2.1").await.unwrap());
+    assert!(IPSet::with_command("missing", &ipset)
+        .test("10.11.2.10")
+        .await
+        .is_err());
+    assert_eq!(
+        std::fs::read_to_string(log).unwrap(),
+        "test acl 10.11.2.10\ntest acl 10.11.2.1\ntest missing 10.11.2.10\n"
+    );
 }
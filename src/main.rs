@@ -2,17 +2,25 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use slog::{o, Drain};
-use slog_scope::error;
+use slog_scope::{error, warn};
 
+mod circuit_breaker;
+mod coalesce;
+mod command_log;
 mod config;
 mod dhcp;
 mod http;
 mod ipset;
+mod metrics;
+mod middleware;
 mod mobile_provider;
 mod persistent_state;
+mod readiness;
+mod response_cache;
 mod speedtest;
 mod state;
 mod telegram;
+mod util;
 
 const CONFIG_DEFAULT_PATH: &str = "/etc/ala-archa-http-backend.yaml";
 
@@ -27,10 +35,22 @@ enum GetCommand {
 // Example of subcommands
 #[derive(Subcommand)]
 enum CommandLine {
-    /// Dump parsed config file. Helps to find typos
+    /// Dump parsed config file with secrets redacted. Helps to find typos
     DumpConfig,
+    /// Print config in use, after defaults, environment and command line overrides, with
+    /// secrets redacted
+    EffectiveConfig,
+    /// Print Prometheus metrics
+    Metrics,
+    /// Upgrade persistent state file to the current format without starting the server
+    MigrateState,
     /// Run HTTP server
     Run,
+    /// Run scheduled job once and exit, for deployments scheduling jobs externally
+    RunJob {
+        #[arg(value_enum)]
+        job: state::Job,
+    },
     /// Update state
     #[command(subcommand)]
     Get(GetCommand),
@@ -40,14 +60,34 @@ enum CommandLine {
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Application {
-    /// Path to configuration file
+    /// Path to configuration file, `-` reads it from stdin
     #[clap(short, default_value = CONFIG_DEFAULT_PATH)]
     config_path: String,
+    /// Log to stderr instead of syslog, at configured log level unless RUST_LOG is set
+    #[clap(long, global = true)]
+    log_stderr: bool,
+    /// Override log_level from configuration file
+    #[clap(long, global = true, value_enum)]
+    log_level: Option<config::LogLevel>,
     /// Subcommand
     #[clap(subcommand)]
     command: CommandLine,
 }
 
+#[derive(Debug, PartialEq)]
+enum LoggerKind {
+    Env,
+    Syslog,
+}
+
+fn logger_kind(log_stderr: bool, rust_log_set: bool) -> LoggerKind {
+    if log_stderr || rust_log_set {
+        LoggerKind::Env
+    } else {
+        LoggerKind::Syslog
+    }
+}
+
 impl Application {
     fn init_syslog_logger(log_level: slog::Level) -> Result<slog_scope::GlobalLoggerGuard> {
         let logger = slog_syslog::SyslogBuilder::new()
@@ -64,39 +104,126 @@ fn init_env_logger() -> Result<slog_scope::GlobalLoggerGuard> {
         Ok(slog_envlogger::init()?)
     }
 
+    /// Applies command line options overriding configuration file
+    fn apply_overrides(&self, config: &mut config::Config) {
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+    }
+
     fn init_logger(&self, config: &config::Config) -> Result<slog_scope::GlobalLoggerGuard> {
-        if std::env::var("RUST_LOG").is_ok() {
-            Self::init_env_logger()
-        } else {
-            Self::init_syslog_logger(config.log_level.into())
+        let rust_log_set = std::env::var("RUST_LOG").is_ok();
+        match logger_kind(self.log_stderr, rust_log_set) {
+            LoggerKind::Env => {
+                if !rust_log_set {
+                    std::env::set_var("RUST_LOG", config.log_level.filter_directive());
+                }
+                Self::init_env_logger()
+            }
+            LoggerKind::Syslog => Self::init_syslog_logger(config.log_level.into()),
         }
     }
 
     async fn run_command(&self, config: config::Config) -> Result<()> {
         match &self.command {
             CommandLine::DumpConfig => {
-                let config =
-                    serde_yaml::to_string(&config).with_context(|| "Failed to dump config")?;
+                let config = serde_yaml::to_string(&config.redacted())
+                    .with_context(|| "Failed to dump config")?;
                 println!("{}", config);
                 Ok(())
             }
+            CommandLine::EffectiveConfig => {
+                let config = serde_yaml::to_string(&config.redacted())
+                    .with_context(|| "Failed to dump config")?;
+                print!("{}", config);
+                Ok(())
+            }
+            CommandLine::Metrics => {
+                let state = crate::state::State::new(&config).await?;
+                let state_guard = state.lock().await;
+                let metrics = crate::metrics::Metrics::collect(&state_guard).await?;
+                print!("{}", metrics.render());
+                Ok(())
+            }
+            CommandLine::MigrateState => {
+                let changes = crate::persistent_state::PersistentState::migrate_file(
+                    &config.persistent_state_path,
+                )
+                .with_context(|| {
+                    format!(
+                        "Failed to migrate persistent state {:?}",
+                        config.persistent_state_path
+                    )
+                })?;
+                if changes.is_empty() {
+                    println!("Persistent state is up to date");
+                }
+                for change in changes {
+                    println!("{}", change);
+                }
+                Ok(())
+            }
             CommandLine::Run => {
                 let http_listen = config.http_listen.clone();
+                let debug_log_bodies = config.debug_log_bodies;
+                let keep_alive = keep_alive(&config);
+                let client_timeout = config.http_client_timeout;
+                if let Some(auto_create_ipsets) = &config.auto_create_ipsets {
+                    if config.read_only {
+                        warn!("Skipping ipsets creation in read-only mode");
+                    } else {
+                        auto_create_ipsets.create(&config).await?;
+                    }
+                }
+                crate::persistent_state::check_writable(&config.persistent_state_path)
+                    .with_context(|| "Persistent state can't be saved")?;
                 let state = crate::state::State::new(&config).await?;
                 crate::state::State::init_cronjobs(state.clone()).await?;
-                actix_web::HttpServer::new(move || {
+                crate::state::State::reload_on_sighup(state.clone())?;
+                let http_metrics = state.lock().await.http_metrics();
+                let server = actix_web::HttpServer::new(move || {
                     actix_web::App::new()
+                        .wrap(actix_web::middleware::Condition::new(
+                            debug_log_bodies,
+                            actix_web::middleware::from_fn(middleware::log_bodies),
+                        ))
+                        .wrap(actix_web::middleware::from_fn(middleware::record_requests))
                         .app_data(web::Data::new(state.clone()))
+                        .app_data(web::Data::new(http_metrics.clone()))
                         .service(http::client_get)
                         .service(http::client_register)
+                        .service(http::client_deregister)
+                        .service(http::client_history)
+                        .service(http::maintenance)
+                        .service(http::reload)
+                        .service(http::debug_commands)
+                        .service(http::telegram_queue)
+                        .service(http::ipset_member)
+                        .service(http::ipset_flush)
+                        .service(http::whitelist)
                         .service(http::dhcp_leases)
+                        .service(http::dhcp_lease)
+                        .service(http::leases_summary)
+                        .service(http::service_status)
                         .service(http::prometheus_exporter)
+                        .service(http::readyz)
                 })
-                .bind(&http_listen)?
-                .run()
-                .await?;
+                .keep_alive(keep_alive)
+                .client_request_timeout(client_timeout);
+                let server = match listen_setup(&http_listen) {
+                    ListenSetup::Address(address) => server.bind(address),
+                    ListenSetup::DualStack(port) => {
+                        dual_stack_listener(port).and_then(|listener| server.listen(listener))
+                    }
+                }
+                .map_err(|err| bind_error(&http_listen, err))?;
+                server.run().await?;
                 Ok(())
             }
+            CommandLine::RunJob { job } => {
+                let state = crate::state::State::new(&config).await?;
+                crate::state::State::run_job(state, *job).await
+            }
             CommandLine::Get(GetCommand::Balance) => {
                 let state = crate::state::State::new(&config).await?;
                 let state_guard = state.lock().await;
@@ -128,17 +255,188 @@ async fn run_command(&self, config: config::Config) -> Result<()> {
         }
     }
 
-    pub async fn run(&self) {
-        let config = config::Config::read(&self.config_path).expect("Config");
+    pub async fn run(&self) -> std::process::ExitCode {
+        // dump-config shows config file as is, without environment overrides
+        let env = match self.command {
+            CommandLine::DumpConfig => Vec::new(),
+            _ => std::env::vars().collect(),
+        };
+        let mut config = config::Config::read_with_env(&self.config_path, env).expect("Config");
+        self.apply_overrides(&mut config);
         let _logger_guard = self.init_logger(&config).expect("Logger");
+        ipset::set_concurrency_limit(config.ipset_concurrency);
+
+        match self.run_command(config).await {
+            Ok(()) => std::process::ExitCode::SUCCESS,
+            Err(err) => {
+                error!("Failed with error: {:#}", err);
+                std::process::ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+/// Keep-alive of HTTP server, zero `http_keep_alive` disables it
+fn keep_alive(config: &config::Config) -> actix_web::http::KeepAlive {
+    if config.http_keep_alive.is_zero() {
+        actix_web::http::KeepAlive::Disabled
+    } else {
+        actix_web::http::KeepAlive::Timeout(config.http_keep_alive)
+    }
+}
+
+/// How HTTP server socket is set up for `http_listen`
+#[derive(Debug, PartialEq)]
+enum ListenSetup {
+    /// Socket of the address family, IPv4 addresses accept IPv4 clients only
+    Address(String),
+    /// `[::]` socket with IPV6_V6ONLY disabled, which accepts both IPv4 and IPv6 clients
+    DualStack(u16),
+}
 
-        if let Err(err) = self.run_command(config).await {
-            error!("Failed with error: {:#}", err);
+/// Bare port and `[::]:port` are bound dual-stack, regardless of `net.ipv6.bindv6only` sysctl
+fn listen_setup(http_listen: &str) -> ListenSetup {
+    if let Ok(port) = http_listen.parse::<u16>() {
+        return ListenSetup::DualStack(port);
+    }
+    match http_listen.parse::<std::net::SocketAddr>() {
+        Ok(std::net::SocketAddr::V6(address)) if address.ip().is_unspecified() => {
+            ListenSetup::DualStack(address.port())
         }
+        _ => ListenSetup::Address(http_listen.to_string()),
     }
 }
 
+/// Binds dual-stack socket, falling back to IPv4 one if IPv6 is disabled in kernel
+fn dual_stack_listener(port: u16) -> std::io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = match Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)) {
+        Ok(v) => v,
+        Err(err) => {
+            warn!("IPv6 is not available ({err}), listening on IPv4 only");
+            return std::net::TcpListener::bind(("0.0.0.0", port));
+        }
+    };
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    let address = std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Wraps HTTP bind failure with the listen address and a hint on the likely cause
+fn bind_error(http_listen: &str, err: std::io::Error) -> anyhow::Error {
+    let hint = match err.kind() {
+        std::io::ErrorKind::AddrInUse => "port is already in use, is another instance running?",
+        std::io::ErrorKind::PermissionDenied => {
+            "permission denied, ports below 1024 require elevated privileges"
+        }
+        std::io::ErrorKind::AddrNotAvailable => "address is not assigned to any local interface",
+        _ => "check that http_listen is a valid host:port pair",
+    };
+    anyhow::Error::new(err).context(format!(
+        "Failed to bind HTTP server to http_listen '{}': {}",
+        http_listen, hint
+    ))
+}
+
 #[actix_web::main]
-async fn main() {
-    Application::parse().run().await;
+async fn main() -> std::process::ExitCode {
+    Application::parse().run().await
+}
+
+#[test]
+fn test_bind_error_invalid_listen() {
+    use std::net::ToSocketAddrs;
+
+    let err = "not-an-address".to_socket_addrs().unwrap_err();
+    let message = format!("{:#}", bind_error("not-an-address", err));
+    assert!(message.contains("'not-an-address'"));
+    assert!(message.contains("valid host:port"));
+
+    let err = std::io::Error::from(std::io::ErrorKind::AddrInUse);
+    let message = format!("{:#}", bind_error("127.0.0.1:8888", err));
+    assert!(message.contains("already in use"));
+}
+
+#[test]
+fn test_logger_kind() {
+    assert_eq!(logger_kind(false, false), LoggerKind::Syslog);
+    assert_eq!(logger_kind(false, true), LoggerKind::Env);
+    assert_eq!(logger_kind(true, false), LoggerKind::Env);
+
+    let app =
+        Application::try_parse_from(["ala-archa-http-backend", "run", "--log-stderr"]).unwrap();
+    assert!(app.log_stderr);
+    let app = Application::try_parse_from(["ala-archa-http-backend", "run"]).unwrap();
+    assert!(!app.log_stderr);
+}
+
+#[test]
+fn test_log_level_override() {
+    let mut config = config::test_config();
+    assert!(matches!(config.log_level, config::LogLevel::Info));
+
+    let app = Application::try_parse_from(["ala-archa-http-backend", "run"]).unwrap();
+    app.apply_overrides(&mut config);
+    assert!(matches!(config.log_level, config::LogLevel::Info));
+
+    let app =
+        Application::try_parse_from(["ala-archa-http-backend", "--log-level", "debug", "run"])
+            .unwrap();
+    app.apply_overrides(&mut config);
+    assert!(matches!(config.log_level, config::LogLevel::Debug));
+
+    assert!(Application::try_parse_from([
+        "ala-archa-http-backend",
+        "--log-level",
+        "verbose",
+        "run"
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_keep_alive() {
+    let mut config = config::test_config();
+    assert_eq!(
+        keep_alive(&config),
+        actix_web::http::KeepAlive::Timeout(std::time::Duration::from_secs(5))
+    );
+    config.http_keep_alive = std::time::Duration::ZERO;
+    assert_eq!(keep_alive(&config), actix_web::http::KeepAlive::Disabled);
+}
+
+#[test]
+fn test_run_job_command() {
+    let app =
+        Application::try_parse_from(["ala-archa-http-backend", "run-job", "balance"]).unwrap();
+    assert!(matches!(
+        app.command,
+        CommandLine::RunJob {
+            job: state::Job::Balance
+        }
+    ));
+    assert!(Application::try_parse_from(["ala-archa-http-backend", "run-job", "backup"]).is_err());
+}
+
+#[test]
+fn test_listen_setup() {
+    for (http_listen, setup) in [
+        ("8888", ListenSetup::DualStack(8888)),
+        ("[::]:8888", ListenSetup::DualStack(8888)),
+        (
+            "0.0.0.0:8888",
+            ListenSetup::Address("0.0.0.0:8888".to_string()),
+        ),
+        ("[::1]:8888", ListenSetup::Address("[::1]:8888".to_string())),
+        (
+            "localhost:8888",
+            ListenSetup::Address("localhost:8888".to_string()),
+        ),
+    ] {
+        assert_eq!(listen_setup(http_listen), setup, "{http_listen}");
+    }
 }
@@ -0,0 +1,841 @@
+use anyhow::{anyhow, Result};
+use prometheus_exporter_base::prelude::*;
+use slog_scope::warn;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Upper bounds of `http_request_duration_seconds` buckets
+const HTTP_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Requests statistics of a single endpoint
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EndpointStats {
+    /// Number of requests by response status
+    pub statuses: BTreeMap<u16, u64>,
+    /// Number of requests in each of `HTTP_DURATION_BUCKETS`, not cumulative
+    pub buckets: Vec<u64>,
+    pub duration_sum: f64,
+    pub count: u64,
+}
+
+/// Registry of HTTP requests, keyed by route template
+#[derive(Default)]
+pub struct HttpMetrics {
+    endpoints: std::sync::Mutex<BTreeMap<String, EndpointStats>>,
+}
+
+impl HttpMetrics {
+    pub fn record(&self, endpoint: &str, status: u16, duration: std::time::Duration) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let stats = endpoints.entry(endpoint.to_string()).or_default();
+        *stats.statuses.entry(status).or_default() += 1;
+
+        let duration = duration.as_secs_f64();
+        if stats.buckets.is_empty() {
+            stats.buckets = vec![0; HTTP_DURATION_BUCKETS.len()];
+        }
+        if let Some(bucket) = HTTP_DURATION_BUCKETS.iter().position(|le| duration <= *le) {
+            stats.buckets[bucket] += 1;
+        }
+        stats.duration_sum += duration;
+        stats.count += 1;
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, EndpointStats> {
+        self.endpoints.lock().unwrap().clone()
+    }
+}
+
+/// Maximum number of distinct MACs in `blacklisted_attempts_total`, attempts of the rest are
+/// counted under `other` label
+const MAX_BLACKLISTED_MACS: usize = 64;
+
+/// Access attempts of blacklisted clients by MAC
+#[derive(Default)]
+struct BlacklistedAttempts {
+    attempts: std::sync::Mutex<BTreeMap<String, u64>>,
+}
+
+impl BlacklistedAttempts {
+    fn record(&self, mac: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        let mac = if attempts.contains_key(mac) || attempts.len() < MAX_BLACKLISTED_MACS {
+            mac
+        } else {
+            "other"
+        };
+        *attempts.entry(mac.to_string()).or_default() += 1;
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.attempts.lock().unwrap().clone()
+    }
+}
+
+/// Counters of events since start. Unlike persistent state they are kept in memory only,
+/// so they are cheap to update from request handlers
+#[derive(Default)]
+pub struct Counters {
+    registrations: AtomicU64,
+    deregistrations: AtomicU64,
+    ipset_errors: AtomicU64,
+    balance_decode_failures: AtomicU64,
+    telegram_sends: AtomicU64,
+    telegram_send_failures: AtomicU64,
+    blacklisted_attempts: BlacklistedAttempts,
+}
+
+/// Values of `Counters` at some moment
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CountersSnapshot {
+    pub registrations: u64,
+    pub deregistrations: u64,
+    pub ipset_errors: u64,
+    /// Number of operator responses in which no decoder found a balance
+    pub balance_decode_failures: u64,
+    /// Telegram messages sent and failed to send, including retries
+    pub telegram_sends: u64,
+    pub telegram_send_failures: u64,
+    pub blacklisted_attempts: BTreeMap<String, u64>,
+}
+
+impl Counters {
+    pub fn inc_registrations(&self) {
+        self.registrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_deregistrations(&self) {
+        self.deregistrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_ipset_errors(&self) {
+        self.ipset_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_balance_decode_failures(&self) {
+        self.balance_decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_telegram_sends(&self) {
+        self.telegram_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_telegram_send_failures(&self) {
+        self.telegram_send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_blacklisted_attempts(&self, mac: &str) {
+        self.blacklisted_attempts.record(mac);
+    }
+
+    pub fn snapshot(&self) -> CountersSnapshot {
+        CountersSnapshot {
+            registrations: self.registrations.load(Ordering::Relaxed),
+            deregistrations: self.deregistrations.load(Ordering::Relaxed),
+            ipset_errors: self.ipset_errors.load(Ordering::Relaxed),
+            balance_decode_failures: self.balance_decode_failures.load(Ordering::Relaxed),
+            telegram_sends: self.telegram_sends.load(Ordering::Relaxed),
+            telegram_send_failures: self.telegram_send_failures.load(Ordering::Relaxed),
+            blacklisted_attempts: self.blacklisted_attempts.snapshot(),
+        }
+    }
+}
+
+/// Data exported as Prometheus metrics
+pub struct Metrics {
+    /// Prepended to every metric name
+    pub prefix: String,
+    /// Added to every sample
+    pub labels: BTreeMap<String, String>,
+    pub persistent_state: crate::persistent_state::PersistentState,
+    /// In ACL sets of both address families, same for shaper
+    pub clients_in_acl: usize,
+    /// Of the fullest ACL set. Set only if `max_acl_size` is configured
+    pub acl_capacity_used_ratio: Option<f64>,
+    pub clients_in_shaper: usize,
+    pub extra_ipsets_members: Vec<(String, usize)>,
+    pub leases: crate::dhcp::LeasesSummary,
+    pub http_requests: BTreeMap<String, EndpointStats>,
+    pub counters: CountersSnapshot,
+    /// Set only if mobile provider is configured
+    pub balance_decode_failures: Option<u64>,
+    /// Set only if telegram is configured
+    pub telegram_sends: Option<crate::telegram::SendCounts>,
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Adds `labels` to every sample of rendered metrics, before labels the sample has
+fn add_labels(rendered: &str, labels: &BTreeMap<String, String>) -> String {
+    if labels.is_empty() {
+        return rendered.to_string();
+    }
+    let labels = labels
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape_label_value(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut result = String::with_capacity(rendered.len());
+    for line in rendered.split_inclusive('\n') {
+        if line.starts_with('#') || line.trim().is_empty() {
+            result.push_str(line);
+            continue;
+        }
+        let name_end = line
+            .find(|v: char| !(v.is_ascii_alphanumeric() || v == '_' || v == ':'))
+            .unwrap_or(line.len());
+        let (name, rest) = line.split_at(name_end);
+        result.push_str(name);
+        match rest.strip_prefix('{') {
+            Some(rest) if rest.starts_with('}') => result.push_str(&format!("{{{labels}{rest}")),
+            Some(rest) => result.push_str(&format!("{{{labels},{rest}")),
+            None => result.push_str(&format!("{{{labels}}}{rest}")),
+        }
+    }
+    result
+}
+
+/// Counts members of each set, skipping sets which can't be listed
+async fn count_ipsets_members(sets: &[crate::ipset::IPSet]) -> Vec<(String, usize)> {
+    let mut counts = Vec::new();
+    for set in sets {
+        match set.entries().await {
+            Ok(entries) => counts.push((set.name().to_string(), entries.len())),
+            Err(err) => warn!("Failed to get {} ipset entries: {}", set.name(), err),
+        }
+    }
+    counts
+}
+
+impl Metrics {
+    pub async fn collect(state: &crate::state::State) -> Result<Self> {
+        use dhcpd_parser::parser::LeasesMethods;
+
+        let config = state.config();
+        // Each ACL set has its own `maxelem`, so capacity is that of the fullest one
+        let mut acl_sizes = Vec::new();
+        for name in config.acl_ipset_names() {
+            let entries = crate::ipset::IPSet::new(name)
+                .entries()
+                .await
+                .map_err(|err| anyhow!("failed to get ACL entries: {}", err))?;
+            acl_sizes.push(entries.len());
+        }
+        let clients_in_acl = acl_sizes.iter().sum();
+        let largest_acl_size = acl_sizes.into_iter().max().unwrap_or_default();
+        let shaper_sets = config
+            .shaper_ipset_names()
+            .into_iter()
+            .map(crate::ipset::IPSet::new)
+            .collect::<Vec<_>>();
+        let clients_in_shaper = crate::ipset::IPSet::entries_of(&shaper_sets)
+            .await
+            .map_err(|err| anyhow!("failed to get shaper entries: {}", err))?
+            .len();
+        let extra_ipsets = config
+            .extra_metric_ipsets
+            .iter()
+            .map(|name| crate::ipset::IPSet::new(name))
+            .collect::<Vec<_>>();
+        let leases = crate::dhcp::Dhcp::read(&config.dhcpd_leases)?.all();
+
+        state.spawn_acl_size_check(largest_acl_size);
+
+        let counters = state.counters().snapshot();
+        Ok(Self {
+            prefix: config.metrics_prefix.clone(),
+            labels: config.metrics_labels.clone(),
+            persistent_state: state.persistent_state().await,
+            clients_in_acl,
+            acl_capacity_used_ratio: config.acl_capacity_used_ratio(largest_acl_size),
+            clients_in_shaper,
+            extra_ipsets_members: count_ipsets_members(&extra_ipsets).await,
+            leases: crate::dhcp::LeasesSummary::count(&leases, config.abandoned_as_free),
+            http_requests: state.http_metrics().snapshot(),
+            balance_decode_failures: config
+                .mobile_provider
+                .as_ref()
+                .map(|_| counters.balance_decode_failures),
+            telegram_sends: config
+                .telegram
+                .as_ref()
+                .map(|_| crate::telegram::SendCounts {
+                    sends: counters.telegram_sends,
+                    failures: counters.telegram_send_failures,
+                }),
+            counters,
+        })
+    }
+
+    fn name(&self, metric: &str) -> String {
+        format!("{}_{}", self.prefix, metric)
+    }
+
+    pub fn render(&self) -> String {
+        let mut metrics = Vec::new();
+        metrics.push(
+            PrometheusMetric::build()
+                .with_name(&self.name("internet_available"))
+                .with_metric_type(MetricType::Gauge)
+                .with_help("Flag of wide internet availability")
+                .build()
+                .render_and_append_instance(
+                    &PrometheusInstance::new().with_value(
+                        self.persistent_state
+                            .is_wide_network_available
+                            .unwrap_or(false) as i8,
+                    ),
+                )
+                .render(),
+        );
+
+        if let Some(rtt) = self.persistent_state.last_ping_rtt_ms {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("ping_rtt_milliseconds"))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("Round-trip time of the last successful wide network ping")
+                    .build()
+                    .render_and_append_instance(&PrometheusInstance::new().with_value(rtt))
+                    .render(),
+            );
+        }
+
+        if let Some(speedtest_result) = &self.persistent_state.speedtest {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("speedtest_download"))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("Speedtest download speed")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(speedtest_result.download),
+                    )
+                    .render(),
+            );
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("speedtest_upload"))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("Speedtest upload speed")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(speedtest_result.upload),
+                    )
+                    .render(),
+            );
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("speedtest_ping"))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("Speedtest ping speed")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(speedtest_result.ping),
+                    )
+                    .render(),
+            );
+        }
+
+        if let Some(balance) = self.persistent_state.balance {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("isp_balance"))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("ISP balance")
+                    .build()
+                    .render_and_append_instance(&PrometheusInstance::new().with_value(balance))
+                    .render(),
+            );
+        }
+
+        if let Some(balance_decode_failures) = self.balance_decode_failures {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("balance_decode_failures_total"))
+                    .with_metric_type(MetricType::Counter)
+                    .with_help("Number of operator responses without recognizable balance")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(balance_decode_failures),
+                    )
+                    .render(),
+            );
+        }
+
+        if let Some(telegram_sends) = self.telegram_sends {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("telegram_sends_total"))
+                    .with_metric_type(MetricType::Counter)
+                    .with_help("Number of messages sent to telegram, including retries")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(telegram_sends.sends),
+                    )
+                    .render(),
+            );
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("telegram_send_failures_total"))
+                    .with_metric_type(MetricType::Counter)
+                    .with_help("Number of failed attempts to send message to telegram")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(telegram_sends.failures),
+                    )
+                    .render(),
+            );
+        }
+
+        if let Some(last_successful_speedtest) = self.persistent_state.last_successful_speedtest {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("last_successful_speedtest_age_seconds"))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("Seconds since the last successful speedtest")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(
+                            (chrono::Utc::now() - last_successful_speedtest).num_seconds(),
+                        ),
+                    )
+                    .render(),
+            );
+        }
+
+        if let Some(last_tariff_update) = self.persistent_state.last_tariff_update {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("last_tariff_update"))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("Last tariff update")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new()
+                            .with_value((last_tariff_update - chrono::Utc::now()).num_seconds()),
+                    )
+                    .render(),
+            );
+        }
+
+        metrics.push(
+            PrometheusMetric::build()
+                .with_name(&self.name("clients_in_acl"))
+                .with_metric_type(MetricType::Gauge)
+                .with_help("Number of clients in ACL")
+                .build()
+                .render_and_append_instance(
+                    &PrometheusInstance::new().with_value(self.clients_in_acl),
+                )
+                .render(),
+        );
+        if let Some(acl_capacity_used_ratio) = self.acl_capacity_used_ratio {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name("acl_capacity_used_ratio"))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("Fraction of max_acl_size taken by ACL entries")
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(acl_capacity_used_ratio),
+                    )
+                    .render(),
+            );
+        }
+        metrics.push(
+            PrometheusMetric::build()
+                .with_name(&self.name("clients_in_shaper"))
+                .with_metric_type(MetricType::Gauge)
+                .with_help("Number of clients in shaper")
+                .build()
+                .render_and_append_instance(
+                    &PrometheusInstance::new().with_value(self.clients_in_shaper),
+                )
+                .render(),
+        );
+
+        if !self.extra_ipsets_members.is_empty() {
+            let name = self.name("ipset_members");
+            let mut metric = PrometheusMetric::build()
+                .with_name(&name)
+                .with_metric_type(MetricType::Gauge)
+                .with_help("Number of members in ipset")
+                .build();
+            for (name, count) in &self.extra_ipsets_members {
+                metric.render_and_append_instance(
+                    &PrometheusInstance::new()
+                        .with_label("set", name.as_str())
+                        .with_value(*count),
+                );
+            }
+            metrics.push(metric.render());
+        }
+
+        for (name, count) in [
+            ("free", self.leases.free),
+            ("active", self.leases.active),
+            ("abandoned", self.leases.abandoned),
+        ] {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name(&format!("dhcp_leases_{}", name)))
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help(&format!("Number of {} DHCP leases", name))
+                    .build()
+                    .render_and_append_instance(&PrometheusInstance::new().with_value(count))
+                    .render(),
+            )
+        }
+
+        for (name, help, value) in [
+            (
+                "registrations_total",
+                "Number of client registrations",
+                self.counters.registrations,
+            ),
+            (
+                "deregistrations_total",
+                "Number of client deregistrations",
+                self.counters.deregistrations,
+            ),
+            (
+                "ipset_errors_total",
+                "Number of failed ipset operations in client requests",
+                self.counters.ipset_errors,
+            ),
+        ] {
+            metrics.push(
+                PrometheusMetric::build()
+                    .with_name(&self.name(name))
+                    .with_metric_type(MetricType::Counter)
+                    .with_help(help)
+                    .build()
+                    .render_and_append_instance(&PrometheusInstance::new().with_value(value))
+                    .render(),
+            );
+        }
+
+        if !self.counters.blacklisted_attempts.is_empty() {
+            let name = self.name("blacklisted_attempts_total");
+            let mut metric = PrometheusMetric::build()
+                .with_name(&name)
+                .with_metric_type(MetricType::Counter)
+                .with_help("Number of requests from blacklisted clients")
+                .build();
+            for (mac, count) in &self.counters.blacklisted_attempts {
+                metric.render_and_append_instance(
+                    &PrometheusInstance::new()
+                        .with_label("mac", mac.as_str())
+                        .with_value(*count),
+                );
+            }
+            metrics.push(metric.render());
+        }
+
+        if !self.http_requests.is_empty() {
+            metrics.push(self.render_http_requests());
+        }
+
+        add_labels(&metrics.join(""), &self.labels)
+    }
+
+    fn render_http_requests(&self) -> String {
+        let name = self.name("http_requests_total");
+        let mut requests = PrometheusMetric::build()
+            .with_name(&name)
+            .with_metric_type(MetricType::Counter)
+            .with_help("Number of HTTP requests")
+            .build();
+        for (endpoint, stats) in &self.http_requests {
+            for (status, count) in &stats.statuses {
+                let status = status.to_string();
+                requests.render_and_append_instance(
+                    &PrometheusInstance::new()
+                        .with_label("endpoint", endpoint.as_str())
+                        .with_label("status", status.as_str())
+                        .with_value(*count),
+                );
+            }
+        }
+
+        // Histograms have several series per instance, which prometheus_exporter_base
+        // can't render, so they are written by hand
+        let name = self.name("http_request_duration_seconds");
+        let mut durations =
+            format!("# HELP {name} HTTP request duration in seconds\n# TYPE {name} histogram\n");
+        for (endpoint, stats) in &self.http_requests {
+            let mut cumulative = 0;
+            for (le, count) in HTTP_DURATION_BUCKETS.iter().zip(&stats.buckets) {
+                cumulative += count;
+                durations.push_str(&format!(
+                    "{name}_bucket{{endpoint=\"{endpoint}\",le=\"{le}\"}} {cumulative}\n"
+                ));
+            }
+            durations.push_str(&format!(
+                "{name}_bucket{{endpoint=\"{endpoint}\",le=\"+Inf\"}} {}\n",
+                stats.count
+            ));
+            durations.push_str(&format!(
+                "{name}_sum{{endpoint=\"{endpoint}\"}} {}\n",
+                stats.duration_sum
+            ));
+            durations.push_str(&format!(
+                "{name}_count{{endpoint=\"{endpoint}\"}} {}\n",
+                stats.count
+            ));
+        }
+
+        requests.render() + &durations
+    }
+}
+
+#[cfg(test)]
+fn samples(metrics: &str) -> Vec<&str> {
+    metrics.lines().filter(|v| !v.starts_with('#')).collect()
+}
+
+#[test]
+fn test_render() {
+    let metrics = Metrics {
+        prefix: "ratzek".to_string(),
+        labels: Default::default(),
+        persistent_state: crate::persistent_state::PersistentState {
+            is_wide_network_available: Some(true),
+            last_ping_rtt_ms: Some(23.5),
+            balance: Some(548.08),
+            ..Default::default()
+        },
+        clients_in_acl: 3,
+        acl_capacity_used_ratio: Some(0.3),
+        clients_in_shaper: 2,
+        extra_ipsets_members: vec![("guest".to_string(), 5)],
+        leases: crate::dhcp::LeasesSummary {
+            active: 2,
+            free: 1,
+            abandoned: 1,
+            total: 4,
+        },
+        http_requests: Default::default(),
+        counters: Default::default(),
+        balance_decode_failures: Some(2),
+        telegram_sends: Some(crate::telegram::SendCounts {
+            sends: 4,
+            failures: 1,
+        }),
+    };
+
+    assert_eq!(
+        samples(&metrics.render()),
+        [
+            "ratzek_internet_available 1",
+            "ratzek_ping_rtt_milliseconds 23.5",
+            "ratzek_isp_balance 548.08",
+            "ratzek_balance_decode_failures_total 2",
+            "ratzek_telegram_sends_total 4",
+            "ratzek_telegram_send_failures_total 1",
+            "ratzek_clients_in_acl 3",
+            "ratzek_acl_capacity_used_ratio 0.3",
+            "ratzek_clients_in_shaper 2",
+            "ratzek_ipset_members{set=\"guest\"} 5",
+            "ratzek_dhcp_leases_free 1",
+            "ratzek_dhcp_leases_active 2",
+            "ratzek_dhcp_leases_abandoned 1",
+            "ratzek_registrations_total 0",
+            "ratzek_deregistrations_total 0",
+            "ratzek_ipset_errors_total 0",
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn test_count_ipsets_members() {
+    let (ipset, _) = crate::ipset::fake_ipset(
+        "ipset-members-metric",
+        r#"case "$2" in
+guest) printf "create guest hash:ip\nadd guest 10.0.0.1\nadd guest 10.0.0.2\n" ;;
+vip) printf "add vip 10.0.1.1\n" ;;
+*) exit 1 ;;
+esac"#,
+    );
+    let sets =
+        ["guest", "broken", "vip"].map(|name| crate::ipset::IPSet::with_command(name, &ipset));
+
+    assert_eq!(
+        count_ipsets_members(&sets).await,
+        [("guest".to_string(), 2), ("vip".to_string(), 1)]
+    );
+}
+
+#[test]
+fn test_render_http_requests() {
+    let http_metrics = HttpMetrics::default();
+    http_metrics.record("/api/v1/client", 200, std::time::Duration::from_millis(20));
+    http_metrics.record("/api/v1/client", 200, std::time::Duration::from_millis(200));
+    http_metrics.record("/api/v1/client", 503, std::time::Duration::from_secs(20));
+
+    let metrics = Metrics {
+        prefix: "ratzek".to_string(),
+        labels: Default::default(),
+        persistent_state: Default::default(),
+        clients_in_acl: 0,
+        acl_capacity_used_ratio: None,
+        clients_in_shaper: 0,
+        extra_ipsets_members: vec![],
+        leases: Default::default(),
+        http_requests: http_metrics.snapshot(),
+        counters: Default::default(),
+        balance_decode_failures: None,
+        telegram_sends: None,
+    };
+    let rendered = metrics.render_http_requests();
+    let samples = samples(&rendered);
+
+    assert!(rendered.contains("# TYPE ratzek_http_request_duration_seconds histogram"));
+    for sample in [
+        "ratzek_http_requests_total{endpoint=\"/api/v1/client\",status=\"200\"} 2",
+        "ratzek_http_requests_total{endpoint=\"/api/v1/client\",status=\"503\"} 1",
+        "ratzek_http_request_duration_seconds_bucket{endpoint=\"/api/v1/client\",le=\"0.01\"} 0",
+        "ratzek_http_request_duration_seconds_bucket{endpoint=\"/api/v1/client\",le=\"0.025\"} 1",
+        "ratzek_http_request_duration_seconds_bucket{endpoint=\"/api/v1/client\",le=\"10\"} 2",
+        "ratzek_http_request_duration_seconds_bucket{endpoint=\"/api/v1/client\",le=\"+Inf\"} 3",
+        "ratzek_http_request_duration_seconds_count{endpoint=\"/api/v1/client\"} 3",
+    ] {
+        assert!(samples.contains(&sample), "{sample} not in {samples:?}");
+    }
+}
+
+#[test]
+fn test_render_custom_prefix() {
+    let http_metrics = HttpMetrics::default();
+    http_metrics.record("/api/v1/client", 200, std::time::Duration::from_millis(20));
+
+    let metrics = Metrics {
+        prefix: "ala_archa".to_string(),
+        labels: Default::default(),
+        persistent_state: crate::persistent_state::PersistentState {
+            is_wide_network_available: Some(true),
+            balance: Some(548.08),
+            last_successful_speedtest: Some(chrono::Utc::now()),
+            ..Default::default()
+        },
+        clients_in_acl: 3,
+        acl_capacity_used_ratio: None,
+        clients_in_shaper: 2,
+        extra_ipsets_members: vec![("guest".to_string(), 5)],
+        leases: Default::default(),
+        http_requests: http_metrics.snapshot(),
+        counters: Default::default(),
+        balance_decode_failures: Some(0),
+        telegram_sends: None,
+    };
+    let rendered = metrics.render();
+
+    assert!(!rendered.contains("ratzek"));
+    assert!(rendered.contains("ala_archa_last_successful_speedtest_age_seconds 0"));
+    for line in rendered.lines() {
+        let name = line
+            .strip_prefix("# HELP ")
+            .or_else(|| line.strip_prefix("# TYPE "))
+            .unwrap_or(line);
+        assert!(name.starts_with("ala_archa_"), "{line}");
+    }
+}
+
+#[test]
+fn test_render_static_labels() {
+    let http_metrics = HttpMetrics::default();
+    http_metrics.record("/api/v1/client", 200, std::time::Duration::from_millis(20));
+    let counters = Counters::default();
+    counters.inc_blacklisted_attempts("00:11:22:33:44:55");
+
+    let metrics = Metrics {
+        prefix: "ratzek".to_string(),
+        labels: [
+            ("site".to_string(), "archa-1".to_string()),
+            ("zone".to_string(), "say \"hi\"".to_string()),
+        ]
+        .into(),
+        persistent_state: crate::persistent_state::PersistentState {
+            is_wide_network_available: Some(true),
+            last_ping_rtt_ms: Some(23.5),
+            balance: Some(548.08),
+            last_successful_speedtest: Some(chrono::Utc::now()),
+            ..Default::default()
+        },
+        clients_in_acl: 3,
+        acl_capacity_used_ratio: Some(0.3),
+        clients_in_shaper: 2,
+        extra_ipsets_members: vec![("guest".to_string(), 5)],
+        leases: Default::default(),
+        http_requests: http_metrics.snapshot(),
+        counters: counters.snapshot(),
+        balance_decode_failures: Some(0),
+        telegram_sends: Some(Default::default()),
+    };
+    let rendered = metrics.render();
+    let samples = samples(&rendered);
+
+    assert!(samples.len() > 20);
+    for sample in &samples {
+        assert!(
+            sample.contains("{site=\"archa-1\",zone=\"say \\\"hi\\\"\"")
+                && sample.matches('{').count() == 1,
+            "{sample}"
+        );
+    }
+    for sample in [
+        "ratzek_internet_available{site=\"archa-1\",zone=\"say \\\"hi\\\"\"} 1",
+        "ratzek_ipset_members{site=\"archa-1\",zone=\"say \\\"hi\\\"\",set=\"guest\"} 5",
+    ] {
+        assert!(samples.contains(&sample), "{sample} not in {samples:?}");
+    }
+}
+
+#[test]
+fn test_blacklisted_attempts_cardinality() {
+    let attempts = BlacklistedAttempts::default();
+    for i in 0..MAX_BLACKLISTED_MACS + 2 {
+        attempts.record(&format!("mac{i}"));
+    }
+    attempts.record("mac0");
+
+    let snapshot = attempts.snapshot();
+    assert_eq!(snapshot.len(), MAX_BLACKLISTED_MACS + 1);
+    assert_eq!(snapshot["mac0"], 2);
+    assert_eq!(snapshot["other"], 2);
+}
+
+#[test]
+fn test_counters() {
+    let counters = Counters::default();
+    counters.inc_registrations();
+    counters.inc_registrations();
+    counters.inc_deregistrations();
+    counters.inc_ipset_errors();
+    counters.inc_balance_decode_failures();
+    counters.inc_telegram_sends();
+    counters.inc_telegram_send_failures();
+    counters.inc_telegram_send_failures();
+    counters.inc_blacklisted_attempts("00:11:22:33:44:55");
+
+    assert_eq!(
+        counters.snapshot(),
+        CountersSnapshot {
+            registrations: 2,
+            deregistrations: 1,
+            ipset_errors: 1,
+            balance_decode_failures: 1,
+            telegram_sends: 1,
+            telegram_send_failures: 2,
+            blacklisted_attempts: [("00:11:22:33:44:55".to_string(), 1)].into(),
+        }
+    );
+}
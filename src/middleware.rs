@@ -0,0 +1,190 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::HeaderMap,
+    middleware::Next,
+    web::{Bytes, Data},
+};
+use slog_scope::debug;
+
+/// Maximum number of logged bytes of a single body
+const MAX_LOGGED_BODY_SIZE: usize = 4096;
+
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+fn format_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<binary>")
+            };
+            format!("{name}: {value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_body(body: &[u8]) -> String {
+    if body.len() > MAX_LOGGED_BODY_SIZE {
+        format!(
+            "{}... ({} bytes truncated)",
+            String::from_utf8_lossy(&body[..MAX_LOGGED_BODY_SIZE]),
+            body.len() - MAX_LOGGED_BODY_SIZE
+        )
+    } else {
+        String::from_utf8_lossy(body).to_string()
+    }
+}
+
+/// Logs request and response bodies at debug level. Enabled by `debug_log_bodies` option
+pub async fn log_bodies(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<Bytes>, actix_web::Error> {
+    let body = req.extract::<Bytes>().await?;
+    debug!(
+        "Request {} {} [{}]: {}",
+        req.method(),
+        req.uri(),
+        format_headers(req.headers()),
+        format_body(&body)
+    );
+    let (_, mut payload) = actix_http::h1::Payload::create(true);
+    payload.unread_data(body);
+    req.set_payload(payload.into());
+
+    let res = next.call(req).await?;
+    let status = res.status();
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let body = actix_web::body::to_bytes(body)
+        .await
+        .map_err(|err| actix_web::error::ErrorInternalServerError(err.into().to_string()))?;
+    debug!(
+        "Response {} {} {}: {}",
+        req.method(),
+        req.uri(),
+        status,
+        format_body(&body)
+    );
+
+    Ok(ServiceResponse::new(req, res.set_body(body)))
+}
+
+/// Records requests count and duration into `HttpMetrics` from app data. Requests are
+/// labeled by route template, so that client-controlled paths don't blow up cardinality
+pub async fn record_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let http_metrics = req
+        .app_data::<Data<std::sync::Arc<crate::metrics::HttpMetrics>>>()
+        .cloned();
+    let started = std::time::Instant::now();
+
+    let res = next.call(req).await?;
+
+    if let Some(http_metrics) = http_metrics {
+        let endpoint = res
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        http_metrics.record(&endpoint, res.status().as_u16(), started.elapsed());
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+struct CapturingDrain(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+#[cfg(test)]
+impl slog::Drain for CapturingDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+#[actix_web::test]
+async fn test_log_bodies_only_when_enabled() {
+    use actix_web::{middleware::Condition, test, web, App};
+
+    let logs = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let guard =
+        slog_scope::set_global_logger(slog::Logger::root(CapturingDrain(logs.clone()), slog::o!()));
+
+    for enabled in [false, true] {
+        let app = test::init_service(
+            App::new()
+                .wrap(Condition::new(
+                    enabled,
+                    actix_web::middleware::from_fn(log_bodies),
+                ))
+                .route("/echo", web::post().to(|body: String| async move { body })),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header(("authorization", "Bearer secret-token"))
+            .set_payload(format!("body-enabled-{enabled}"))
+            .to_request();
+        let resp = test::call_and_read_body(&app, req).await;
+        assert_eq!(resp, format!("body-enabled-{enabled}"));
+    }
+
+    let logs = logs.lock().unwrap();
+    assert!(!logs.iter().any(|v| v.contains("body-enabled-false")));
+    assert!(logs
+        .iter()
+        .any(|v| v.starts_with("Request") && v.contains("body-enabled-true")));
+    assert!(logs
+        .iter()
+        .any(|v| v.starts_with("Response") && v.contains("body-enabled-true")));
+    assert!(!logs.iter().any(|v| v.contains("secret-token")));
+
+    // Dropped guard makes logging panic, which breaks tests running after this one
+    guard.cancel_reset();
+    slog_scope::set_global_logger(slog::Logger::root(slog::Discard, slog::o!())).cancel_reset();
+}
+
+#[test]
+fn test_format_body_truncates() {
+    let body = vec![b'a'; MAX_LOGGED_BODY_SIZE + 10];
+    assert!(format_body(&body).ends_with("... (10 bytes truncated)"));
+}
+
+#[actix_web::test]
+async fn test_record_requests() {
+    use actix_web::{test, web, App};
+
+    let http_metrics = std::sync::Arc::new(crate::metrics::HttpMetrics::default());
+    let app = test::init_service(
+        App::new()
+            .wrap(actix_web::middleware::from_fn(record_requests))
+            .app_data(Data::new(http_metrics.clone()))
+            .route("/item/{id}", web::get().to(|| async { "ok" })),
+    )
+    .await;
+
+    for uri in ["/item/1", "/item/2", "/missing"] {
+        test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+    }
+
+    let snapshot = http_metrics.snapshot();
+    assert_eq!(snapshot["/item/{id}"].statuses[&200], 2);
+    assert_eq!(snapshot["/item/{id}"].count, 2);
+    assert_eq!(snapshot["unmatched"].statuses[&404], 1);
+    assert_eq!(snapshot.len(), 2);
+}
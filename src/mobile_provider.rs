@@ -1,6 +1,46 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use slog_scope::{error, info};
+use slog_scope::{debug, error, info, warn};
+use std::sync::{Arc, Mutex};
+
+fn default_circuit_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown() -> std::time::Duration {
+    std::time::Duration::from_secs(600)
+}
+
+fn default_on_low_balance_interval() -> std::time::Duration {
+    std::time::Duration::from_secs(24 * 60 * 60)
+}
+
+pub fn default_shell() -> std::path::PathBuf {
+    "/bin/bash".into()
+}
+
+/// Arguments to spawn `command` with. Commands without quoting, redirects, variables and
+/// other shell syntax are executed directly, the rest are passed to `shell -c`. First word
+/// with `=` is a variable assignment, not a program
+pub fn command_argv(shell: &std::path::Path, command: &str) -> Vec<String> {
+    let command = command.trim();
+    let is_simple = command
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == ' ' || "-_./=:,@%+".contains(c))
+        && !command
+            .split_whitespace()
+            .next()
+            .is_some_and(|v| v.contains('='));
+    if is_simple && !command.is_empty() {
+        command.split_whitespace().map(str::to_string).collect()
+    } else {
+        vec![
+            shell.display().to_string(),
+            "-c".to_string(),
+            command.to_string(),
+        ]
+    }
+}
 
 fn decode_ucs2_in_hex(hex: &str) -> Result<String> {
     // Cut string to fit 4-byte chunks
@@ -30,6 +70,123 @@ fn decode_ucs2_in_hex(hex: &str) -> Result<String> {
         .map_err(|err| anyhow::anyhow!("Failed to convert UCS-2 to UTF-8: {err}"))
 }
 
+type Decoder = fn(&str) -> Result<String>;
+
+/// Decoders of USSD message, tried in order
+const BALANCE_DECODERS: &[(&str, Decoder)] =
+    &[("ucs2", decode_ucs2_in_hex), ("utf8", decode_utf8_in_hex)];
+
+/// Outcome of decoding operator balance message
+#[derive(Debug, PartialEq)]
+struct BalanceDecoding {
+    raw_hex: String,
+    decoder_used: &'static str,
+    decoded_text: String,
+    balance: f64,
+}
+
+/// Collects hex-encoded message from `+CUSD: 0,"..."` lines of modem output. Long messages
+/// are split across several lines, so all segments are concatenated. Status lines without
+/// message, like `+CUSD: 2`, are skipped
+fn extract_ussd_message(output: &str) -> Result<String> {
+    let segments = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("+CUSD: "))
+        // split line by double quotes and get second part
+        .filter_map(|line| line.split('"').nth(1))
+        .collect::<Vec<_>>();
+    if segments.is_empty() {
+        anyhow::bail!("Failed to find balance in output")
+    }
+    Ok(segments.concat())
+}
+
+/// Extracts balance from decoded message
+fn extract_balance(message: &str) -> Option<f64> {
+    // extract number from message which looks like: Баланс 548.08 с. ...
+    let balance = if message.starts_with("Баланс ") {
+        message.split_whitespace().nth(1)?
+        // extract number from message which looks like: You have 398.08 som.
+    } else if message.starts_with("You have ") {
+        message.split_whitespace().nth(2)?
+    } else {
+        return None;
+    };
+
+    balance.trim().parse().ok()
+}
+
+/// Finds first number like `548.08` or `548,08` in the text
+fn first_decimal_number(text: &str) -> Option<f64> {
+    let bytes = text.as_bytes();
+    let digits_end = |from: usize| {
+        from + bytes[from..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+
+    let mut start = 0;
+    while start < bytes.len() {
+        if !bytes[start].is_ascii_digit() {
+            start += 1;
+            continue;
+        }
+        let end = digits_end(start);
+        if matches!(bytes.get(end), Some(b'.' | b','))
+            && bytes.get(end + 1).is_some_and(u8::is_ascii_digit)
+        {
+            return text[start..digits_end(end + 1)]
+                .replace(',', ".")
+                .parse()
+                .ok();
+        }
+        start = end;
+    }
+
+    None
+}
+
+/// Tries every decoder on hex-encoded USSD message and picks the first one, which yields
+/// a balance. If no message has known format and `fallback_first_number` is set, first
+/// decimal number of any decoded message is taken as balance
+fn decode_balance(raw_hex: &str, fallback_first_number: bool) -> Result<BalanceDecoding> {
+    let decoded = BALANCE_DECODERS
+        .iter()
+        .filter_map(|(decoder_used, decoder)| match decoder(raw_hex) {
+            Ok(decoded_text) => Some((*decoder_used, decoded_text)),
+            Err(err) => {
+                debug!("Failed to decode message as {decoder_used}: {err}");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut extractors: Vec<fn(&str) -> Option<f64>> = vec![extract_balance];
+    if fallback_first_number {
+        extractors.push(first_decimal_number);
+    }
+
+    for extractor in extractors {
+        for (decoder_used, decoded_text) in &decoded {
+            if let Some(balance) = extractor(decoded_text) {
+                return Ok(BalanceDecoding {
+                    raw_hex: raw_hex.to_string(),
+                    decoder_used,
+                    decoded_text: decoded_text.clone(),
+                    balance,
+                });
+            }
+        }
+    }
+
+    for (decoder_used, decoded_text) in &decoded {
+        debug!("No balance in {decoder_used} decoded message: {decoded_text}");
+    }
+    anyhow::bail!("Unable to extract balance from operator response: {raw_hex}")
+}
+
 fn decode_utf8_in_hex(hex: &str) -> Result<String> {
     // Cut string to fit 2-byte chunks
     let hex = if hex.len() % 2 != 0 {
@@ -61,115 +218,187 @@ pub struct MobileProvider {
     pub low_download_speed_threshold: f64,
     #[serde(with = "humantime_serde")]
     pub min_update_tariff_interval: std::time::Duration,
-    pub telegram_chat_ids: Vec<String>,
+    pub telegram_chat_ids: Vec<crate::telegram::Chat>,
     pub phone_number: String,
     pub get_balance_retry_count: u8,
     #[serde(with = "humantime_serde")]
     pub get_balance_retry_interval: std::time::Duration,
     pub restart_lte_command: String,
+    /// Command reading modem output from stdin and printing balance, used instead of
+    /// built-in decoders
+    #[serde(default)]
+    pub balance_parse_command: Option<String>,
+    /// Shell used to run commands with shell syntax
+    #[serde(default = "default_shell")]
+    pub shell: std::path::PathBuf,
+    /// Number of consecutive command failures after which commands are skipped
+    #[serde(default = "default_circuit_breaker_threshold")]
+    pub circuit_breaker_threshold: u32,
+    /// How long commands are skipped before another attempt
+    #[serde(with = "humantime_serde", default = "default_circuit_breaker_cooldown")]
+    pub circuit_breaker_cooldown: std::time::Duration,
+    /// Scheduled balance checks more frequent than this are skipped, as each one restarts LTE
+    #[serde(default, with = "humantime_serde")]
+    pub min_balance_check_interval: Option<std::time::Duration>,
+    /// Take first decimal number of operator message as balance, if message format is
+    /// unknown. May pick up unrelated numbers, so it is disabled by default
+    #[serde(default)]
+    pub balance_fallback_first_number: bool,
+    /// Command run when balance is below `low_balance_threshold`, e.g. auto top-up script.
+    /// `{balance}` is replaced with the current balance
+    #[serde(default)]
+    pub on_low_balance_command: Option<String>,
+    /// Minimum interval between `on_low_balance_command` runs while balance stays low
+    #[serde(with = "humantime_serde", default = "default_on_low_balance_interval")]
+    pub on_low_balance_interval: std::time::Duration,
+    #[serde(skip)]
+    circuit_breaker: Arc<Mutex<crate::circuit_breaker::CircuitBreaker>>,
 }
 
 impl MobileProvider {
-    async fn get_balance_once(&self) -> Result<f64> {
-        let output = tokio::process::Command::new("bash")
-            .arg("-c")
-            .arg(&self.get_balance_command)
-            .output()
-            .await?;
-        let output = String::from_utf8(output.stdout)?;
+    /// Shares circuit breaker of `previous` config, so config reload doesn't close it
+    pub fn keep_runtime_state(&mut self, previous: &Self) {
+        self.circuit_breaker = previous.circuit_breaker.clone();
+    }
 
-        slog_scope::info!("Got balance output: {}", output);
+    /// Runs modem command unless circuit breaker is open
+    async fn run_command(
+        &self,
+        command: &str,
+        command_log: &crate::command_log::CommandLog,
+    ) -> Result<std::process::Output> {
+        if !self
+            .circuit_breaker
+            .lock()
+            .unwrap()
+            .try_acquire(self.circuit_breaker_cooldown, std::time::Instant::now())
+        {
+            warn!("Too many modem command failures, skipping command: {command}");
+            anyhow::bail!("Modem commands circuit breaker is open");
+        }
 
-        // get line in output, which starts with +CUSD: 0,"
-        let line = output
-            .lines()
-            .map(str::trim)
-            .find(|line| line.starts_with("+CUSD: "))
-            .ok_or_else(|| anyhow::anyhow!("Failed to find balance in output"))?;
-        // split line by double quotes and get second part
-        let message = line
-            .split("\"")
-            .nth(1)
-            .ok_or_else(|| anyhow::anyhow!("Failed to extract message from line"))?;
-        slog_scope::info!("Got encoded balance message: {}", message);
-        let message_variants = vec![decode_ucs2_in_hex(message), decode_utf8_in_hex(message)]
-            .into_iter()
-            .filter_map(|result| match result {
-                Ok(v) => Some(v),
-                Err(err) => {
-                    error!("Failed to decode message: {:?}", err);
-                    None
-                }
-            })
-            .collect::<Vec<String>>();
-        for message in message_variants {
-            slog_scope::info!("Got decoded balance message: {}", message);
-            // extract number from message which looks like: Баланс 548.08 с. ...
-            let balance = if message.starts_with("Баланс ") {
-                message
-                    .split_whitespace()
-                    .nth(1)
-                    .ok_or_else(|| anyhow::anyhow!("Failed to extract balance from message"))?
-                    .trim()
-                // extract number from message which looks like: You have 398.08 som.
-            } else if message.starts_with("You have ") {
-                message
-                    .split_whitespace()
-                    .nth(2)
-                    .ok_or_else(|| anyhow::anyhow!("Failed to extract balance from message"))?
-                    .trim()
+        let argv = command_argv(&self.shell, command);
+        let output = command_log
+            .run(
+                command,
+                tokio::process::Command::new(&argv[0]).args(&argv[1..]),
+            )
+            .await;
+        let output = output.map_err(anyhow::Error::from).and_then(|output| {
+            if output.status.success() {
+                Ok(output)
             } else {
-                error!("Failed to extract balance from message: unexpected message prefix");
-                continue;
-            };
-
-            slog_scope::info!("Got balance: {}", balance);
-
-            match balance
-                .parse()
-                .map_err(|err| anyhow::anyhow!("Failed to parse balance: {err}"))
-            {
-                Ok(v) => return Ok(v),
-                Err(err) => {
-                    error!("Failed to parse balance: {:?}", err);
-                }
+                Err(anyhow::anyhow!(
+                    "Command exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
             }
+        });
+
+        let mut circuit_breaker = self.circuit_breaker.lock().unwrap();
+        match output {
+            Ok(_) => circuit_breaker.record_success(),
+            Err(_) => circuit_breaker
+                .record_failure(self.circuit_breaker_threshold, std::time::Instant::now()),
         }
+        output
+    }
 
-        anyhow::bail!("Unable to extract balance from operator response")
+    /// Passes modem output to `command` stdin and reads balance from its stdout
+    async fn parse_balance_with_command(
+        &self,
+        command: &str,
+        output: &str,
+        command_log: &crate::command_log::CommandLog,
+    ) -> Result<f64> {
+        let argv = command_argv(&self.shell, command);
+        let parsed = command_log
+            .run_with_stdin(
+                command,
+                tokio::process::Command::new(&argv[0]).args(&argv[1..]),
+                output.as_bytes(),
+            )
+            .await;
+        let parsed = parsed?;
+
+        if !parsed.status.success() {
+            anyhow::bail!(
+                "Balance parse command exited with {}: {}",
+                parsed.status,
+                String::from_utf8_lossy(&parsed.stderr).trim()
+            );
+        }
+        let stdout = String::from_utf8_lossy(&parsed.stdout);
+        let balance = stdout
+            .trim()
+            .parse()
+            .map_err(|err| anyhow::anyhow!("Failed to parse balance {:?}: {err}", stdout.trim()))?;
+        info!("Got balance from parse command: {balance}");
+        Ok(balance)
     }
 
-    pub async fn get_balance(&self) -> Result<f64> {
-        let mut balance = None;
-        for _ in 0..self.get_balance_retry_count {
-            match self.get_balance_once().await {
-                Ok(v) => {
-                    balance = Some(v);
-                    break;
-                }
-                Err(err) => {
-                    error!("Failed to get balance: {:?}", err);
-                }
-            }
-            tokio::time::sleep(self.get_balance_retry_interval).await;
+    /// Operator responses without recognizable balance are counted in `counters`
+    async fn get_balance_once(
+        &self,
+        command_log: &crate::command_log::CommandLog,
+        counters: &crate::metrics::Counters,
+    ) -> Result<f64> {
+        let output = self
+            .run_command(&self.get_balance_command, command_log)
+            .await?;
+        let output = String::from_utf8(output.stdout)?;
+
+        slog_scope::info!("Got balance output: {}", output);
+
+        if let Some(command) = &self.balance_parse_command {
+            return self
+                .parse_balance_with_command(command, &output, command_log)
+                .await
+                .inspect_err(|_| counters.inc_balance_decode_failures());
         }
 
+        let message = extract_ussd_message(&output)?;
+        let decoding = decode_balance(&message, self.balance_fallback_first_number)
+            .inspect_err(|_| counters.inc_balance_decode_failures())?;
+        info!("Decoded balance message";
+            "raw_hex" => &decoding.raw_hex,
+            "decoder_used" => decoding.decoder_used,
+            "decoded_text" => &decoding.decoded_text,
+            "balance" => decoding.balance);
+
+        Ok(decoding.balance)
+    }
+
+    pub async fn get_balance(
+        &self,
+        command_log: &crate::command_log::CommandLog,
+        counters: &crate::metrics::Counters,
+    ) -> Result<f64> {
+        let balance = crate::util::retry(
+            self.get_balance_retry_count.into(),
+            self.get_balance_retry_interval,
+            1.0,
+            crate::util::classify_spawn_error,
+            || self.get_balance_once(command_log, counters),
+        )
+        .await;
+
         // restart LTE after getting balance
-        let output = tokio::process::Command::new("bash")
-            .arg("-c")
-            .arg(&self.restart_lte_command)
-            .output()
+        let output = self
+            .run_command(&self.restart_lte_command, command_log)
             .await;
         if let Err(err) = output {
             error!("Failed to restart LTE: {:?}", err);
         }
 
-        balance.ok_or_else(|| anyhow::anyhow!("Failed to get balance"))
+        balance.context("Failed to get balance")
     }
 
     async fn alert_balance(
         &self,
         persistent_state: &crate::persistent_state::PersistentStateGuard,
+        counters: &crate::metrics::Counters,
         telegram: &crate::telegram::Telegram,
         balance: f64,
     ) -> Result<()> {
@@ -178,7 +407,13 @@ async fn alert_balance(
             balance, self.phone_number, self.low_balance_threshold
         );
         telegram
-            .send_message(persistent_state, &self.telegram_chat_ids, &message)
+            .send_message(
+                persistent_state,
+                counters,
+                &self.telegram_chat_ids,
+                crate::telegram::Severity::Critical,
+                &message,
+            )
             .await;
 
         Ok(())
@@ -187,27 +422,127 @@ async fn alert_balance(
     async fn alert_update_tariff(
         &self,
         persistent_state: &crate::persistent_state::PersistentStateGuard,
+        counters: &crate::metrics::Counters,
         telegram: &crate::telegram::Telegram,
     ) -> Result<()> {
         let message = "Скорость интернета ниже порога. Обновление тарифа...";
         telegram
-            .send_message(persistent_state, &self.telegram_chat_ids, message)
+            .send_message(
+                persistent_state,
+                counters,
+                &self.telegram_chat_ids,
+                crate::telegram::Severity::Info,
+                message,
+            )
             .await;
 
         Ok(())
     }
 
+    pub fn is_balance_check_due(
+        &self,
+        last_check: Option<chrono::DateTime<chrono::Utc>>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        match (self.min_balance_check_interval, last_check) {
+            (Some(interval), Some(last_check)) => {
+                now - last_check
+                    >= chrono::TimeDelta::from_std(interval)
+                        .unwrap_or(chrono::TimeDelta::max_value())
+            }
+            _ => true,
+        }
+    }
+
+    fn is_low_balance_action_due(
+        &self,
+        last_action: Option<chrono::DateTime<chrono::Utc>>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        match last_action {
+            Some(last_action) => {
+                // Interval too large for TimeDelta means the action is never repeated
+                now - last_action
+                    >= chrono::TimeDelta::from_std(self.on_low_balance_interval)
+                        .unwrap_or(chrono::TimeDelta::max_value())
+            }
+            None => true,
+        }
+    }
+
+    /// Runs `on_low_balance_command` if balance is low and it wasn't run recently. Once
+    /// balance recovers, the next drop triggers the command right away
+    async fn on_low_balance(
+        &self,
+        persistent_state: &crate::persistent_state::PersistentStateGuard,
+        balance: f64,
+        command_log: &crate::command_log::CommandLog,
+    ) {
+        let Some(command) = &self.on_low_balance_command else {
+            return;
+        };
+        let last_action = persistent_state.get().await.last_low_balance_action;
+
+        if balance >= self.low_balance_threshold {
+            if last_action.is_some() {
+                if let Err(err) = persistent_state
+                    .update(|state| state.last_low_balance_action = None)
+                    .await
+                {
+                    error!("Unable to reset low balance action time: {err}");
+                }
+            }
+            return;
+        }
+
+        let now = chrono::Utc::now();
+        if !self.is_low_balance_action_due(last_action, now) {
+            info!("Low balance command was run recently, skipping");
+            return;
+        }
+
+        let command = command.replace("{balance}", &balance.to_string());
+        info!("Running low balance command: {command}");
+        let argv = command_argv(&self.shell, &command);
+        let output = command_log
+            .run(
+                &command,
+                tokio::process::Command::new(&argv[0]).args(&argv[1..]),
+            )
+            .await;
+        match output {
+            Ok(output) if !output.status.success() => error!(
+                "Low balance command exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Ok(_) => {}
+            Err(err) => error!("Failed to run low balance command: {err}"),
+        }
+
+        if let Err(err) = persistent_state
+            .update(|state| state.last_low_balance_action = Some(now))
+            .await
+        {
+            error!("Unable to record low balance action time: {err}");
+        }
+    }
+
     pub async fn get_and_alert_balance(
         &self,
         persistent_state: &crate::persistent_state::PersistentStateGuard,
         telegram: &Option<crate::telegram::Telegram>,
+        command_log: &crate::command_log::CommandLog,
+        counters: &crate::metrics::Counters,
     ) -> Result<f64> {
-        let balance = self.get_balance().await?;
+        let balance = self.get_balance(command_log, counters).await?;
 
+        self.on_low_balance(persistent_state, balance, command_log)
+            .await;
         if balance < self.low_balance_threshold {
             if let Some(telegram) = telegram {
                 if let Err(err) = self
-                    .alert_balance(persistent_state, telegram, balance)
+                    .alert_balance(persistent_state, counters, telegram, balance)
                     .await
                 {
                     error!("Failed to send balance alert: {:?}", err);
@@ -221,6 +556,8 @@ pub async fn update_tariff(
         &self,
         config: &crate::config::Config,
         persistent_state: &crate::persistent_state::PersistentStateGuard,
+        command_log: &crate::command_log::CommandLog,
+        counters: &crate::metrics::Counters,
     ) {
         let persistent_state_unwrapped = persistent_state.get().await;
         let speedtest = match persistent_state_unwrapped.speedtest {
@@ -245,10 +582,8 @@ pub async fn update_tariff(
             }
         }
 
-        let output = tokio::process::Command::new("bash")
-            .arg("-c")
-            .arg(&self.update_tariff_command)
-            .output()
+        let output = self
+            .run_command(&self.update_tariff_command, command_log)
             .await;
 
         if let Err(err) = output {
@@ -257,7 +592,10 @@ pub async fn update_tariff(
         }
 
         if let Some(telegram) = &config.telegram {
-            if let Err(err) = self.alert_update_tariff(persistent_state, telegram).await {
+            if let Err(err) = self
+                .alert_update_tariff(persistent_state, counters, telegram)
+                .await
+            {
                 error!("Failed to send tariff update alert: {:?}", err);
             }
         }
@@ -273,6 +611,41 @@ pub async fn update_tariff(
     }
 }
 
+#[cfg(test)]
+fn test_provider() -> MobileProvider {
+    serde_yaml::from_str(
+        r#"
+update_tariff_command: "true"
+get_balance_command: "true"
+low_balance_threshold: 100
+low_download_speed_threshold: 1000000
+min_update_tariff_interval: 1w
+telegram_chat_ids: []
+phone_number: "+996 000 000 000"
+get_balance_retry_count: 1
+get_balance_retry_interval: 1s
+restart_lte_command: "true"
+min_balance_check_interval: 1h
+"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_balance_check_skipped_if_too_recent() {
+    let mut provider = test_provider();
+    let now = chrono::Utc::now();
+
+    assert!(provider.is_balance_check_due(None, now));
+    assert!(!provider.is_balance_check_due(Some(now - chrono::TimeDelta::minutes(30)), now));
+    assert!(provider.is_balance_check_due(Some(now - chrono::TimeDelta::minutes(90)), now));
+    provider.min_balance_check_interval = Some(std::time::Duration::MAX);
+    assert!(!provider.is_balance_check_due(Some(now - chrono::TimeDelta::weeks(1000)), now));
+
+    provider.min_balance_check_interval = None;
+    assert!(provider.is_balance_check_due(Some(now), now));
+}
+
 #[test]
 fn test_ucs2_decoder() {
     let input = "04110430043b0430043d04410020003500340038002e0030003800200441002e002000310030003000300020043f044104380445043e043b043e04330438044704350441043a0438044500200442043504410442043e04320020002a00330034003100230020003500200441043e043c00200432002004340435043d044c";
@@ -320,3 +693,207 @@ fn test_ucs2_decoder_truncated_string() {
     let output = decode_ucs2_in_hex(input).unwrap();
     assert_eq!(output, expected,);
 }
+
+#[test]
+fn test_decode_balance_reports_decoder() {
+    let ucs2 = "04110430043b0430043d04410020003500340038002e0030003800200441002e";
+    let decoding = decode_balance(ucs2, false).unwrap();
+    assert_eq!(decoding.decoder_used, "ucs2");
+    assert_eq!(decoding.decoded_text, "Баланс 548.08 с.");
+    assert_eq!(decoding.balance, 548.08);
+    assert_eq!(decoding.raw_hex, ucs2);
+
+    let utf8 = "596f752068617665203339382e303820736f6d2e";
+    let decoding = decode_balance(utf8, false).unwrap();
+    assert_eq!(decoding.decoder_used, "utf8");
+    assert_eq!(decoding.decoded_text, "You have 398.08 som.");
+    assert_eq!(decoding.balance, 398.08);
+
+    assert!(decode_balance("48656c6c6f", false).is_err());
+}
+
+#[test]
+fn test_extract_ussd_message_multipart() {
+    let output = r#"OK
++CREG: 1, 1ca4, ca8b8, 2
++CUSD: 1,"04110430043b0430043d04410020003500340038",15
++CEREG: 0
++CUSD: 0,"002e0030003800200441002e",15
++CUSD: 2
+"#;
+    let message = extract_ussd_message(output).unwrap();
+    assert_eq!(decode_ucs2_in_hex(&message).unwrap(), "Баланс 548.08 с.");
+    assert_eq!(decode_balance(&message, false).unwrap().balance, 548.08);
+
+    assert!(extract_ussd_message("OK\n+CEREG: 0\n").is_err());
+    assert!(extract_ussd_message("OK\n+CUSD: 4\n").is_err());
+}
+
+#[test]
+fn test_balance_fallback_first_number() {
+    // "Ostatok: 12,50 som, bonus 3" in UTF-8
+    let unknown = "4f737461746f6b3a2031322c353020736f6d2c20626f6e75732033";
+    assert!(decode_balance(unknown, false).is_err());
+    let decoding = decode_balance(unknown, true).unwrap();
+    assert_eq!(decoding.balance, 12.5);
+    assert_eq!(decoding.decoder_used, "utf8");
+
+    // known prefix wins over the fallback
+    let decoding = decode_balance("596f752068617665203339382e303820736f6d2e", true).unwrap();
+    assert_eq!(decoding.balance, 398.08);
+
+    assert_eq!(
+        first_decimal_number("Tariff 5 som, balance 7.25"),
+        Some(7.25)
+    );
+    assert_eq!(first_decimal_number("Call 1.. or 2, 3"), None);
+    assert_eq!(first_decimal_number("no numbers"), None);
+}
+
+#[actix_web::test]
+async fn test_balance_decode_failures_counted() {
+    let mut provider = test_provider();
+    provider.get_balance_command = r#"echo '+CUSD: 0,"48656c6c6f",15'"#.to_string();
+    let command_log = crate::command_log::CommandLog::default();
+    let counters = crate::metrics::Counters::default();
+
+    assert!(provider
+        .get_balance_once(&command_log, &counters)
+        .await
+        .is_err());
+    assert_eq!(counters.snapshot().balance_decode_failures, 1);
+
+    provider.get_balance_command =
+        r#"echo '+CUSD: 0,"596f752068617665203339382e303820736f6d2e",15'"#.to_string();
+    assert_eq!(
+        provider
+            .get_balance_once(&command_log, &counters)
+            .await
+            .unwrap(),
+        398.08
+    );
+    assert_eq!(counters.snapshot().balance_decode_failures, 1);
+}
+
+#[test]
+fn test_command_argv() {
+    let shell = std::path::Path::new("/bin/sh");
+    assert_eq!(
+        command_argv(shell, "/usr/bin/modem-ctl --restart lte1\n"),
+        ["/usr/bin/modem-ctl", "--restart", "lte1"]
+    );
+    assert_eq!(
+        command_argv(shell, r#"ssh router '/interface disable lte1'"#),
+        ["/bin/sh", "-c", "ssh router '/interface disable lte1'"]
+    );
+    assert_eq!(
+        command_argv(shell, "modem-ctl > /dev/null"),
+        ["/bin/sh", "-c", "modem-ctl > /dev/null"]
+    );
+    assert_eq!(
+        command_argv(shell, "LANG=C modem-ctl --status"),
+        ["/bin/sh", "-c", "LANG=C modem-ctl --status"]
+    );
+    assert_eq!(
+        command_argv(shell, "modem-ctl --apn=internet"),
+        ["modem-ctl", "--apn=internet"]
+    );
+}
+
+#[actix_web::test]
+async fn test_balance_parse_command() {
+    let mut provider = test_provider();
+    provider.get_balance_command = "printf 'OK\\nBALANCE=12.5\\n'".to_string();
+    provider.balance_parse_command = Some("sed -n 's/^BALANCE=//p'".to_string());
+    let command_log = crate::command_log::CommandLog::default();
+    let counters = crate::metrics::Counters::default();
+
+    assert_eq!(
+        provider
+            .get_balance_once(&command_log, &counters)
+            .await
+            .unwrap(),
+        12.5
+    );
+
+    provider.balance_parse_command = Some("cat".to_string());
+    assert!(provider
+        .get_balance_once(&command_log, &counters)
+        .await
+        .is_err());
+    assert_eq!(counters.snapshot().balance_decode_failures, 1);
+}
+
+#[actix_web::test]
+async fn test_spawn_failure_not_retried() {
+    let mut provider = test_provider();
+    provider.get_balance_command = "/nonexistent/get-balance".to_string();
+    provider.get_balance_retry_count = 3;
+    let command_log = crate::command_log::CommandLog::new(10);
+
+    assert!(provider
+        .get_balance(&command_log, &Default::default())
+        .await
+        .is_err());
+    let commands = command_log
+        .records()
+        .into_iter()
+        .map(|v| v.command)
+        .collect::<Vec<_>>();
+    assert_eq!(commands, ["/nonexistent/get-balance", "true"]);
+}
+
+#[actix_web::test]
+async fn test_low_balance_command_debounced() {
+    let path = crate::persistent_state::TempStatePath::new("low-balance");
+    let persistent_state = crate::persistent_state::PersistentStateGuard::load_from_yaml(&path);
+    let mut provider = test_provider();
+    provider.on_low_balance_command = Some("top-up --amount 500 --balance {balance}".to_string());
+    let command_log = crate::command_log::CommandLog::new(10);
+    let commands = || {
+        command_log
+            .records()
+            .into_iter()
+            .map(|v| v.command)
+            .collect::<Vec<_>>()
+    };
+
+    provider
+        .on_low_balance(&persistent_state, 50.5, &command_log)
+        .await;
+    provider
+        .on_low_balance(&persistent_state, 40.0, &command_log)
+        .await;
+    assert_eq!(commands(), ["top-up --amount 500 --balance 50.5"]);
+    assert!(persistent_state
+        .get()
+        .await
+        .last_low_balance_action
+        .is_some());
+
+    // Balance recovered, next drop triggers command again
+    provider
+        .on_low_balance(&persistent_state, 150.0, &command_log)
+        .await;
+    assert!(persistent_state
+        .get()
+        .await
+        .last_low_balance_action
+        .is_none());
+    provider
+        .on_low_balance(&persistent_state, 30.0, &command_log)
+        .await;
+    assert_eq!(
+        commands(),
+        [
+            "top-up --amount 500 --balance 50.5",
+            "top-up --amount 500 --balance 30"
+        ]
+    );
+
+    let now = chrono::Utc::now();
+    assert!(!provider.is_low_balance_action_due(Some(now - chrono::TimeDelta::hours(1)), now));
+    assert!(provider.is_low_balance_action_due(Some(now - chrono::TimeDelta::hours(25)), now));
+    provider.on_low_balance_interval = std::time::Duration::MAX;
+    assert!(!provider.is_low_balance_action_due(Some(now - chrono::TimeDelta::weeks(1000)), now));
+}
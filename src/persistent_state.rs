@@ -1,7 +1,8 @@
 use crate::speedtest::SpeedTest;
+use anyhow::bail;
 use serde::{Deserialize, Serialize};
 use slog_scope::error;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 use tokio::sync::Mutex;
 
 #[derive(Serialize, Deserialize, Default, Clone)]
@@ -11,39 +12,362 @@ pub struct TelegramMessage {
     pub timestamp: chrono::DateTime<chrono::Local>,
 }
 
+/// Version of persistent state format, bumped on incompatible changes
+pub const PERSISTENT_STATE_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct PersistentState {
+    /// Format version, 0 for files written before versioning
+    #[serde(default)]
+    pub version: u32,
     pub is_wide_network_available: Option<bool>,
+    /// Number of consecutive ping runs disagreeing with `is_wide_network_available`
+    #[serde(default)]
+    pub ping_streak: u32,
+    /// Round-trip time of the last ping, absent if it failed or network was checked over HTTP
+    #[serde(default)]
+    pub last_ping_rtt_ms: Option<f64>,
     pub speedtest: Option<SpeedTest>,
+    /// Time of the last speedtest run which yielded results
+    #[serde(default)]
+    pub last_successful_speedtest: Option<chrono::DateTime<chrono::Utc>>,
     pub last_tariff_update: Option<chrono::DateTime<chrono::Utc>>,
     pub balance: Option<f64>,
     #[serde(default)]
+    pub last_balance_check: Option<chrono::DateTime<chrono::Utc>>,
+    /// Last run of `on_low_balance_command`, reset once balance recovers
+    #[serde(default)]
+    pub last_low_balance_action: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
     pub telegram_queue: Vec<TelegramMessage>,
+    /// New registrations are refused while set
+    #[serde(default)]
+    pub maintenance: bool,
+    /// ACL size alert was sent, reset once the set shrinks below the threshold
+    #[serde(default)]
+    pub acl_size_alerted: bool,
+    #[serde(default)]
+    pub client_sessions: HashMap<String, Vec<chrono::DateTime<chrono::Utc>>>,
+    /// Time client deregistered itself, so missing ACL entry isn't taken for expired one
+    #[serde(default)]
+    pub client_deregistrations: HashMap<String, chrono::DateTime<chrono::Utc>>,
+}
+
+/// Limits of persistent state collections, enforced by maintenance job
+pub struct Retention {
+    pub client_history_limit: usize,
+    pub client_history_max_age: Option<std::time::Duration>,
+    pub telegram_message_timeout: Option<std::time::Duration>,
 }
 
 impl PersistentState {
+    /// Drops records past retention and clients without sessions left. Returns number of
+    /// removed records
+    pub fn prune(&mut self, now: chrono::DateTime<chrono::Utc>, retention: &Retention) -> usize {
+        let is_expired = |time: chrono::DateTime<chrono::Utc>,
+                          max_age: Option<std::time::Duration>| {
+            max_age.is_some_and(|max_age| (now - time).to_std().is_ok_and(|age| age > max_age))
+        };
+        let mut removed = 0;
+
+        for sessions in self.client_sessions.values_mut() {
+            let len = sessions.len();
+            sessions.retain(|v| !is_expired(*v, retention.client_history_max_age));
+            if sessions.len() > retention.client_history_limit {
+                sessions.drain(..sessions.len() - retention.client_history_limit);
+            }
+            removed += len - sessions.len();
+        }
+        self.client_sessions.retain(|_, v| !v.is_empty());
+
+        // Deregistration matters only while there is registration it follows
+        let len = self.client_deregistrations.len();
+        let sessions = &self.client_sessions;
+        self.client_deregistrations
+            .retain(|mac, _| sessions.contains_key(mac));
+        removed += len - self.client_deregistrations.len();
+
+        let len = self.telegram_queue.len();
+        self.telegram_queue.retain(|v| {
+            !is_expired(
+                v.timestamp.with_timezone(&chrono::Utc),
+                retention.telegram_message_timeout,
+            )
+        });
+        removed += len - self.telegram_queue.len();
+
+        removed
+    }
+
+    /// Accounts ping run result. Availability flips only after `failure_threshold`
+    /// consecutive failures or `success_threshold` consecutive successes, the first result
+    /// is taken as is
+    pub fn record_ping(&mut self, success: bool, failure_threshold: u32, success_threshold: u32) {
+        match self.is_wide_network_available {
+            None => self.is_wide_network_available = Some(success),
+            Some(available) if available == success => self.ping_streak = 0,
+            Some(_) => {
+                self.ping_streak += 1;
+                let threshold = if success {
+                    success_threshold
+                } else {
+                    failure_threshold
+                };
+                if self.ping_streak >= threshold {
+                    self.is_wide_network_available = Some(success);
+                    self.ping_streak = 0;
+                }
+            }
+        }
+    }
+
+    /// Appends registration time to client's history keeping only `limit` most recent records
+    pub fn record_client_session(
+        &mut self,
+        mac: &str,
+        time: chrono::DateTime<chrono::Utc>,
+        limit: usize,
+    ) {
+        let sessions = self.client_sessions.entry(mac.to_lowercase()).or_default();
+        sessions.push(time);
+        if sessions.len() > limit {
+            sessions.drain(..sessions.len() - limit);
+        }
+    }
+
+    pub fn record_client_deregistration(&mut self, mac: &str, time: chrono::DateTime<chrono::Utc>) {
+        self.client_deregistrations.insert(mac.to_lowercase(), time);
+    }
+
+    /// Time of the last registration, unless client deregistered after it
+    pub fn active_registration(&self, mac: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+        let mac = mac.to_lowercase();
+        let registered = *self.client_sessions.get(&mac)?.last()?;
+        match self.client_deregistrations.get(&mac) {
+            Some(deregistered) if *deregistered >= registered => None,
+            _ => Some(registered),
+        }
+    }
+
+    pub fn client_history(&self, mac: &str) -> Vec<chrono::DateTime<chrono::Utc>> {
+        self.client_sessions
+            .get(&mac.to_lowercase())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Empty state of the current format version
+    fn new() -> Self {
+        Self {
+            version: PERSISTENT_STATE_VERSION,
+            ..Default::default()
+        }
+    }
+
+    /// Upgrades state to the current format version. Returns upgraded state and list of
+    /// changes
+    pub fn migrate(content: &str) -> anyhow::Result<(Self, Vec<String>)> {
+        let original: serde_yaml::Value = serde_yaml::from_str(content)?;
+        let mut state: Self = serde_yaml::from_value(original.clone())?;
+        let mut changes = Vec::new();
+
+        if state.version > PERSISTENT_STATE_VERSION {
+            bail!(
+                "State version {} is newer than supported {}",
+                state.version,
+                PERSISTENT_STATE_VERSION
+            )
+        }
+        if state.version < PERSISTENT_STATE_VERSION {
+            changes.push(format!(
+                "upgraded version {} to {}",
+                state.version, PERSISTENT_STATE_VERSION
+            ));
+            state.version = PERSISTENT_STATE_VERSION;
+        }
+
+        let migrated = serde_yaml::to_value(&state)?;
+        if let (Some(original), Some(migrated)) = (original.as_mapping(), migrated.as_mapping()) {
+            for key in migrated.keys().filter(|key| !original.contains_key(*key)) {
+                if key.as_str() != Some("version") {
+                    changes.push(format!("added field {}", key.as_str().unwrap_or("?")));
+                }
+            }
+            for key in original.keys().filter(|key| !migrated.contains_key(*key)) {
+                changes.push(format!(
+                    "dropped unknown field {}",
+                    key.as_str().unwrap_or("?")
+                ));
+            }
+        }
+
+        Ok((state, changes))
+    }
+
+    /// Migrates state file in place. File is rewritten atomically, only if anything changed.
+    /// Compressed files are kept compressed
+    pub fn migrate_file(path: &std::path::Path) -> anyhow::Result<Vec<String>> {
+        let (content, compressed) = read_state_file(path)?;
+        let (state, changes) = Self::migrate(&content)?;
+        if !changes.is_empty() {
+            write_state_file(path, &state, compressed)?;
+        }
+        Ok(changes)
+    }
+
     pub fn load_from_yaml(path: &std::path::Path) -> Self {
-        let content = match std::fs::read_to_string(path) {
+        let content = match read_state_file(path).map(|(content, _)| content) {
             Ok(content) => content,
             Err(err) => {
                 error!("Unable to read persistent state: {err}");
-                return Self::default();
+                return Self::new();
             }
         };
         match serde_yaml::from_str(&content) {
             Ok(state) => state,
             Err(err) => {
                 error!("Unable to parse persistent state: {err}");
-                Self::default()
+                Self::new()
             }
         }
     }
 }
 
+/// Magic bytes starting gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reads state file, decompressing it if it is gzipped. Returns whether it was compressed
+fn read_state_file(path: &std::path::Path) -> anyhow::Result<(String, bool)> {
+    use std::io::Read;
+
+    let content = std::fs::read(path)?;
+    if !content.starts_with(&GZIP_MAGIC) {
+        return Ok((String::from_utf8(content)?, false));
+    }
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(content.as_slice()).read_to_string(&mut decompressed)?;
+    Ok((decompressed, true))
+}
+
+/// Writes state into temporary file and renames it over `path`, so readers never see
+/// partially written state
+fn write_state_file(
+    path: &std::path::Path,
+    state: &PersistentState,
+    compress: bool,
+) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let content = serde_yaml::to_string(state)?;
+    let content = if compress {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content.as_bytes())?;
+        encoder.finish()?
+    } else {
+        content.into_bytes()
+    };
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Checks that state file can be written by creating and removing a probe file next to it
+pub fn check_writable(path: &std::path::Path) -> anyhow::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let probe = dir.join(format!(
+        ".{}.probe-{}",
+        path.file_name()
+            .and_then(|v| v.to_str())
+            .unwrap_or("persistent-state"),
+        std::process::id()
+    ));
+    std::fs::write(&probe, b"")
+        .map_err(|err| anyhow::anyhow!("Directory {:?} is not writable: {err}", dir))?;
+    std::fs::remove_file(&probe)?;
+    Ok(())
+}
+
+/// Timestamped copies of the state file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Backups {
+    pub dir: std::path::PathBuf,
+    /// Number of most recent backups to keep
+    pub keep: usize,
+}
+
+impl Backups {
+    /// Copies state file into backups directory and removes all but `keep` most recent
+    /// backups of it
+    pub fn rotate(
+        &self,
+        state_path: &std::path::Path,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<()> {
+        let file_name = state_path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid state file path {:?}", state_path))?;
+        let prefix = format!("{file_name}.");
+
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::copy(
+            state_path,
+            self.dir
+                .join(format!("{prefix}{}", now.format("%Y%m%dT%H%M%S%.6fZ"))),
+        )?;
+
+        let mut backups = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|v| v.to_str())
+                    .is_some_and(|v| v.starts_with(&prefix))
+            })
+            .collect::<Vec<_>>();
+        // Timestamps have fixed width, so names sort chronologically
+        backups.sort();
+        for path in &backups[..backups.len().saturating_sub(self.keep)] {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Version of state file on disk. Stamps are only compared for equality, not ordered
+/// against current time, so clock moves don't hide changes or fake them
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FileStamp {
+    modified: std::time::SystemTime,
+    len: u64,
+    /// Changes on every write, as the file is replaced by rename
+    inode: u64,
+}
+
+impl FileStamp {
+    fn read(path: &std::path::Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(Self {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+            inode: metadata.ino(),
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct PersistentStateGuard {
     persistent_state_path: std::path::PathBuf,
-    last_read_time: Arc<Mutex<chrono::DateTime<chrono::Utc>>>,
+    backups: Option<Backups>,
+    compress: bool,
+    /// Stamp of the file when it was last read or written by us
+    last_seen: Arc<Mutex<Option<FileStamp>>>,
     state: Arc<Mutex<PersistentState>>,
 }
 
@@ -51,30 +375,37 @@ impl PersistentStateGuard {
     pub fn load_from_yaml(path: &std::path::Path) -> Self {
         Self {
             persistent_state_path: path.to_path_buf(),
-            last_read_time: Arc::new(Mutex::new(chrono::Utc::now())),
+            backups: None,
+            compress: false,
+            last_seen: Arc::new(Mutex::new(FileStamp::read(path))),
             state: Arc::new(Mutex::new(PersistentState::load_from_yaml(path))),
         }
     }
 
-    async fn is_changed_on_disk(&self) -> bool {
-        let metadata = match std::fs::metadata(&self.persistent_state_path) {
-            Ok(metadata) => metadata,
-            Err(_) => return false,
-        };
-        let last_modified = match metadata.modified() {
-            Ok(last_modified) => last_modified,
-            Err(_) => return false,
-        };
-        let last_read_time = self.last_read_time.lock().await;
-        chrono::DateTime::<chrono::Utc>::from(last_modified) > *last_read_time
+    pub fn with_backups(mut self, backups: Option<Backups>) -> Self {
+        self.backups = backups;
+        self
+    }
+
+    /// Write state gzipped. Either format is read regardless
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Returns current stamp of the file if it differs from the last seen one. Removed file
+    /// is not a change, the state in memory is kept
+    async fn changed_on_disk(&self) -> Option<FileStamp> {
+        let stamp = FileStamp::read(&self.persistent_state_path)?;
+        (*self.last_seen.lock().await != Some(stamp)).then_some(stamp)
     }
 
     async fn reload(&self) {
-        if self.is_changed_on_disk().await {
+        if let Some(stamp) = self.changed_on_disk().await {
             let state = PersistentState::load_from_yaml(&self.persistent_state_path);
             let mut state_guard = self.state.lock().await;
             *state_guard = state;
-            (*self.last_read_time.lock().await) = chrono::Utc::now();
+            (*self.last_seen.lock().await) = Some(stamp);
         }
     }
 
@@ -85,8 +416,13 @@ pub async fn update<F, R>(&self, f: F) -> anyhow::Result<R>
         self.reload().await;
         let mut state = self.state.lock().await;
         let r = f(&mut state);
-        let content = serde_yaml::to_string(&*state)?;
-        std::fs::write(&self.persistent_state_path, content)?;
+        write_state_file(&self.persistent_state_path, &state, self.compress)?;
+        (*self.last_seen.lock().await) = FileStamp::read(&self.persistent_state_path);
+        if let Some(backups) = &self.backups {
+            if let Err(err) = backups.rotate(&self.persistent_state_path, chrono::Utc::now()) {
+                error!("Unable to backup persistent state: {err}");
+            }
+        }
         Ok(r)
     }
 
@@ -95,3 +431,254 @@ pub async fn get(&self) -> PersistentState {
         self.state.lock().await.clone()
     }
 }
+
+/// File path in temp dir unique to the test process, removed before use and when dropped
+#[cfg(test)]
+pub struct TempStatePath(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempStatePath {
+    pub fn new(test_name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "ala-archa-http-backend-{}-{}.yaml",
+            test_name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempStatePath {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempStatePath {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempStatePath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn test_record_ping() {
+    let mut state = PersistentState::default();
+    state.record_ping(true, 3, 2);
+    assert_eq!(state.is_wide_network_available, Some(true));
+
+    // Failures have to be consecutive
+    state.record_ping(false, 3, 2);
+    state.record_ping(false, 3, 2);
+    state.record_ping(true, 3, 2);
+    state.record_ping(false, 3, 2);
+    state.record_ping(false, 3, 2);
+    assert_eq!(state.is_wide_network_available, Some(true));
+    state.record_ping(false, 3, 2);
+    assert_eq!(state.is_wide_network_available, Some(false));
+
+    state.record_ping(true, 3, 2);
+    assert_eq!(state.is_wide_network_available, Some(false));
+    state.record_ping(false, 3, 2);
+    state.record_ping(true, 3, 2);
+    assert_eq!(state.is_wide_network_available, Some(false));
+    state.record_ping(true, 3, 2);
+    assert_eq!(state.is_wide_network_available, Some(true));
+
+    // Default thresholds follow every result
+    state.record_ping(false, 1, 1);
+    assert_eq!(state.is_wide_network_available, Some(false));
+    state.record_ping(true, 1, 1);
+    assert_eq!(state.is_wide_network_available, Some(true));
+}
+
+#[test]
+fn test_prune() {
+    let now = chrono::Utc::now();
+    let hours_ago = |hours| now - chrono::TimeDelta::hours(hours);
+    let mut state = PersistentState::default();
+    for hours in [50, 30, 3, 2, 1] {
+        state.record_client_session("aa:bb:cc:dd:ee:ff", hours_ago(hours), 10);
+    }
+    state.record_client_session("11:22:33:44:55:66", hours_ago(100), 10);
+    for mac in ["aa:bb:cc:dd:ee:ff", "11:22:33:44:55:66"] {
+        state.record_client_deregistration(mac, hours_ago(1));
+    }
+    for hours in [30, 1] {
+        state.telegram_queue.push(TelegramMessage {
+            chat_id: "123".to_string(),
+            text: format!("{hours} hours ago"),
+            timestamp: hours_ago(hours).with_timezone(&chrono::Local),
+        });
+    }
+
+    let retention = Retention {
+        client_history_limit: 2,
+        client_history_max_age: Some(std::time::Duration::from_secs(24 * 60 * 60)),
+        telegram_message_timeout: Some(std::time::Duration::from_secs(60 * 60 * 2)),
+    };
+    assert_eq!(state.prune(now, &retention), 6);
+    assert_eq!(
+        state.client_history("aa:bb:cc:dd:ee:ff"),
+        [hours_ago(2), hours_ago(1)]
+    );
+    assert!(!state.client_sessions.contains_key("11:22:33:44:55:66"));
+    assert!(!state
+        .client_deregistrations
+        .contains_key("11:22:33:44:55:66"));
+    assert_eq!(state.telegram_queue.len(), 1);
+    assert_eq!(state.telegram_queue[0].text, "1 hours ago");
+
+    assert_eq!(state.prune(now, &retention), 0);
+}
+
+#[test]
+fn test_client_sessions_history() {
+    let mut state = PersistentState::default();
+    let now = chrono::Utc::now();
+    for i in 0..5 {
+        state.record_client_session("AA:BB:CC:DD:EE:FF", now + chrono::TimeDelta::seconds(i), 3);
+    }
+    state.record_client_session("11:22:33:44:55:66", now, 3);
+
+    assert_eq!(
+        state.client_history("aa:bb:cc:dd:ee:ff"),
+        (2..5)
+            .map(|i| now + chrono::TimeDelta::seconds(i))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(state.client_history("11:22:33:44:55:66"), vec![now]);
+    assert!(state.client_history("00:00:00:00:00:00").is_empty());
+}
+
+#[test]
+fn test_migrate_file() {
+    let path = TempStatePath::new("migrate-state");
+    std::fs::copy("tests/fixtures/persistent_state_v0.yaml", &path).unwrap();
+
+    let changes = PersistentState::migrate_file(&path).unwrap();
+    assert_eq!(changes[0], "upgraded version 0 to 1");
+    assert!(changes.contains(&"added field maintenance".to_string()));
+    assert!(changes.contains(&"added field client_sessions".to_string()));
+
+    let state = PersistentState::load_from_yaml(&path);
+    assert_eq!(state.version, PERSISTENT_STATE_VERSION);
+    assert_eq!(state.balance, Some(548.08));
+    assert_eq!(state.speedtest.unwrap().download, 25000000.0);
+
+    assert!(PersistentState::migrate_file(&path).unwrap().is_empty());
+
+    assert!(PersistentState::migrate("version: 1000").is_err());
+}
+
+#[test]
+fn test_backups_rotation() {
+    let state_path = TempStatePath::new("state-backups");
+    let dir = state_path.with_extension("backups");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::write(&state_path, "balance: 1.0").unwrap();
+
+    let backups = Backups {
+        dir: dir.clone(),
+        keep: 3,
+    };
+    let start = chrono::DateTime::parse_from_rfc3339("2024-01-04T12:00:00Z")
+        .unwrap()
+        .to_utc();
+    for i in 0..5 {
+        backups
+            .rotate(&state_path, start + chrono::TimeDelta::minutes(i))
+            .unwrap();
+    }
+
+    let mut names = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        ["120200", "120300", "120400"].map(|time| format!(
+            "{}.20240104T{time}.000000Z",
+            state_path.file_name().unwrap().to_string_lossy()
+        ))
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_check_writable() {
+    let path = TempStatePath::new("writable");
+    assert!(check_writable(&path).is_ok());
+    let probe_prefix = format!(".{}.probe", path.file_name().unwrap().to_string_lossy());
+    assert!(std::fs::read_dir(std::env::temp_dir())
+        .unwrap()
+        .all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&probe_prefix)));
+
+    // Regular file in place of directory can't be written to even by root
+    let not_dir = TempStatePath::new("not-a-directory");
+    std::fs::write(&not_dir, "").unwrap();
+    let err = check_writable(&not_dir.join("state")).unwrap_err();
+    assert!(err.to_string().contains("is not writable"));
+}
+
+#[actix_web::test]
+async fn test_compressed_round_trip() {
+    for compress in [false, true] {
+        let path = TempStatePath::new(&format!("compress-{compress}"));
+
+        let guard = PersistentStateGuard::load_from_yaml(&path).with_compression(compress);
+        guard
+            .update(|state| state.balance = Some(548.08))
+            .await
+            .unwrap();
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(content.starts_with(&GZIP_MAGIC), compress);
+        assert!(!path.with_extension("tmp").exists());
+
+        // Format is detected on read regardless of the option
+        let state = PersistentState::load_from_yaml(&path);
+        assert_eq!(state.balance, Some(548.08));
+        assert!(PersistentState::migrate_file(&path).unwrap().is_empty());
+    }
+}
+
+#[actix_web::test]
+async fn test_reload_after_clock_moved_back() {
+    let path = TempStatePath::new("clock-skew");
+    let guard = PersistentStateGuard::load_from_yaml(&path);
+    guard
+        .update(|state| state.balance = Some(100.0))
+        .await
+        .unwrap();
+    assert!(guard.changed_on_disk().await.is_none());
+
+    // Another process writes the file after clock was set an hour back
+    let mut state = guard.get().await;
+    state.balance = Some(200.0);
+    write_state_file(&path, &state, false).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(3600))
+        .unwrap();
+    assert_eq!(guard.get().await.balance, Some(200.0));
+    assert!(guard.changed_on_disk().await.is_none());
+}
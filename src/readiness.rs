@@ -0,0 +1,170 @@
+use serde::Serialize;
+use slog_scope::warn;
+
+/// How long result of telegram reachability check is reused by probes
+const TELEGRAM_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    /// Failed optional check doesn't make service unready
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, required: bool, result: anyhow::Result<()>) -> Self {
+        Self {
+            name,
+            required,
+            ok: result.is_ok(),
+            error: result.err().map(|err| format!("{err:#}")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Readiness {
+    fn from_checks(checks: Vec<Check>) -> Self {
+        Self {
+            ready: checks.iter().all(|v| v.ok || !v.required),
+            checks,
+        }
+    }
+
+    pub async fn check(
+        config: &crate::config::Config,
+        telegram_reachability: &TelegramReachability,
+    ) -> Self {
+        let mut checks = Vec::new();
+        for name in [
+            &config.ipset_acl_name,
+            &config.ipset_shaper_name,
+            &config.ipset_no_shape_name,
+        ] {
+            checks.push(Check::new(
+                "ipset",
+                true,
+                crate::ipset::IPSet::new(name)
+                    .entries()
+                    .await
+                    .map(|_| ())
+                    .map_err(|err| anyhow::anyhow!("{name}: {err}")),
+            ));
+        }
+        checks.push(Check::new(
+            "dhcp",
+            true,
+            crate::dhcp::Dhcp::read(&config.dhcpd_leases).map(|_| ()),
+        ));
+        checks.push(Check::new(
+            "speedtest",
+            true,
+            check_executable(&config.speedtest.speedtest_cli_path),
+        ));
+        if let Some(telegram) = &config.telegram {
+            checks.push(Check::new(
+                "telegram",
+                false,
+                telegram_reachability.check(telegram).await,
+            ));
+        }
+        Self::from_checks(checks)
+    }
+}
+
+/// Caches result of the last telegram reachability check, so frequent probes don't query
+/// Telegram API each time. Error details are only logged: they may contain the bot token
+#[derive(Default)]
+pub struct TelegramReachability {
+    last: tokio::sync::Mutex<Option<(std::time::Instant, bool)>>,
+}
+
+impl TelegramReachability {
+    pub async fn check(&self, telegram: &crate::telegram::Telegram) -> anyhow::Result<()> {
+        let mut last = self.last.lock().await;
+        let is_reachable = match *last {
+            Some((checked, is_reachable)) if checked.elapsed() < TELEGRAM_CHECK_INTERVAL => {
+                is_reachable
+            }
+            _ => {
+                let r = telegram.check_reachable().await;
+                if let Err(err) = &r {
+                    warn!("Telegram is unreachable: {err:#}");
+                }
+                *last = Some((std::time::Instant::now(), r.is_ok()));
+                r.is_ok()
+            }
+        };
+        if !is_reachable {
+            anyhow::bail!("telegram unreachable");
+        }
+        Ok(())
+    }
+}
+
+fn check_executable(path: &std::path::Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata =
+        std::fs::metadata(path).map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        anyhow::bail!("{} is not an executable file", path.display());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_readiness_with_failing_subsystem() {
+    let readiness = Readiness::from_checks(vec![
+        Check::new("ipset", true, Ok(())),
+        Check::new("dhcp", true, Err(anyhow::anyhow!("no such file"))),
+        Check::new("telegram", false, Ok(())),
+    ]);
+    assert!(!readiness.ready);
+    assert_eq!(readiness.checks[1].error.as_deref(), Some("no such file"));
+
+    let readiness = Readiness::from_checks(vec![
+        Check::new("ipset", true, Ok(())),
+        Check::new("telegram", false, Err(anyhow::anyhow!("timeout"))),
+    ]);
+    assert!(readiness.ready);
+}
+
+#[actix_web::test]
+async fn test_telegram_check_cached() {
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = std::sync::Arc::new(AtomicUsize::new(0));
+    let server_requests = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            server_requests.fetch_add(1, Ordering::SeqCst);
+            let _ = stream.read(&mut [0; 1024]);
+            let _ = stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n");
+        }
+    });
+
+    let telegram: crate::telegram::Telegram = serde_yaml::from_str(&format!(
+        "bot_token: 123456:TOKEN\nmessage_timeout: 1h\nretry_crontab: '* * * * * *'\n\
+         api_url: http://127.0.0.1:{port}"
+    ))
+    .unwrap();
+    let reachability = TelegramReachability::default();
+    for _ in 0..2 {
+        let err = reachability.check(&telegram).await.unwrap_err();
+        assert_eq!(format!("{err:#}"), "telegram unreachable");
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
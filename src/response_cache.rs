@@ -0,0 +1,129 @@
+use actix_web::{
+    http::header::{self, ContentType},
+    HttpRequest, HttpResponse,
+};
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+};
+
+/// Distinct requests whose ETags are kept. Cache is cleared once it is full, so arbitrary
+/// query strings don't grow it without bound
+const MAX_CACHED_RESPONSES: usize = 64;
+
+struct CachedResponse {
+    etag: String,
+    etag_data: String,
+}
+
+/// ETag of the last response per request, e.g. endpoint with its query
+#[derive(Default)]
+pub struct ResponseCache {
+    responses: HashMap<String, CachedResponse>,
+}
+
+fn compute_etag(data: &str) -> String {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    data.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+fn is_etag_matched(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .any(|v| v == "*" || v.trim_start_matches("W/") == etag)
+        })
+        .unwrap_or(false)
+}
+
+impl ResponseCache {
+    fn etag(&mut self, key: String, etag_data: String) -> String {
+        if let Some(cached) = self.responses.get(&key) {
+            if cached.etag_data == etag_data {
+                return cached.etag.clone();
+            }
+        }
+        if self.responses.len() >= MAX_CACHED_RESPONSES && !self.responses.contains_key(&key) {
+            self.responses.clear();
+        }
+        let etag = compute_etag(&etag_data);
+        self.responses.insert(
+            key,
+            CachedResponse {
+                etag: etag.clone(),
+                etag_data,
+            },
+        );
+        etag
+    }
+
+    /// Responds with 304 if client already has the same data, otherwise with `body` and its
+    /// ETag. ETag is computed from `etag_data`, which is the body without values changing
+    /// on every request, like remaining time
+    pub fn respond(
+        &mut self,
+        key: String,
+        req: &HttpRequest,
+        etag_data: String,
+        body: String,
+    ) -> HttpResponse {
+        let etag = self.etag(key, etag_data);
+        if is_etag_matched(req, &etag) {
+            return HttpResponse::NotModified()
+                .insert_header((header::ETAG, etag))
+                .finish();
+        }
+
+        HttpResponse::Ok()
+            .insert_header(ContentType::plaintext())
+            .insert_header((header::ETAG, etag))
+            .body(body)
+    }
+}
+
+#[test]
+fn test_etag_not_modified_flow() {
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    let mut cache = ResponseCache::default();
+    let respond = |cache: &mut ResponseCache, req: &HttpRequest, data: &str, body: &str| {
+        cache.respond("dhcp".to_string(), req, data.to_string(), body.to_string())
+    };
+
+    let resp = respond(
+        &mut cache,
+        &TestRequest::default().to_http_request(),
+        "data",
+        "data 10s",
+    );
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers().get(header::ETAG).unwrap().clone();
+
+    // Body differs only in time-dependent values
+    let req = TestRequest::default()
+        .insert_header((header::IF_NONE_MATCH, etag.clone()))
+        .to_http_request();
+    let resp = respond(&mut cache, &req, "data", "data 9s");
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers().get(header::ETAG), Some(&etag));
+
+    let resp = respond(&mut cache, &req, "changed data", "changed data 8s");
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers().get(header::ETAG), Some(&etag));
+}
+
+#[test]
+fn test_cache_size_limited() {
+    use actix_web::test::TestRequest;
+
+    let mut cache = ResponseCache::default();
+    let req = TestRequest::default().to_http_request();
+    for i in 0..MAX_CACHED_RESPONSES * 2 {
+        cache.respond(format!("dhcp?{i}"), &req, String::new(), String::new());
+        assert!(cache.responses.len() <= MAX_CACHED_RESPONSES);
+    }
+}
@@ -1,29 +1,271 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use slog_scope::info;
+use slog_scope::{info, warn};
 
 #[derive(Deserialize, Serialize, Default, Debug, Clone)]
 pub struct SpeedTest {
     pub download: f64,
     pub upload: f64,
     pub ping: f64,
+    /// CLI which produced results, set by backend. Unknown for results stored by older
+    /// versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<std::path::PathBuf>,
+}
+
+/// Speedtest results for API responses: raw bits/s values along with Mbps
+#[derive(Serialize, Debug)]
+pub struct SpeedTestReport {
+    #[serde(flatten)]
+    pub raw: SpeedTest,
+    pub download_mbps: f64,
+    pub upload_mbps: f64,
+}
+
+/// Converts bits/s to Mbps rounded to two decimals
+fn to_mbps(bits_per_second: f64) -> f64 {
+    (bits_per_second / 10_000.0).round() / 100.0
+}
+
+impl From<SpeedTest> for SpeedTestReport {
+    fn from(raw: SpeedTest) -> Self {
+        Self {
+            download_mbps: to_mbps(raw.download),
+            upload_mbps: to_mbps(raw.upload),
+            raw,
+        }
+    }
+}
+
+/// Checks that file owner and mode don't let other users replace the file. Like ssh
+/// StrictModes, files of root and of the user the backend runs as are trusted
+fn check_cli_permissions(owner: u32, mode: u32, euid: u32) -> anyhow::Result<()> {
+    if owner != 0 && owner != euid {
+        anyhow::bail!("owned by uid {owner}, which is neither root nor current user");
+    }
+    if mode & 0o002 != 0 {
+        anyhow::bail!("writable by any user, mode {:o}", mode & 0o7777);
+    }
+    Ok(())
+}
+
+/// Refuses speedtest CLI which could have been planted by another user
+fn check_cli_path(path: &std::path::Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if !path.is_absolute() {
+        anyhow::bail!("speedtest_cli_path {:?} is not absolute", path);
+    }
+    let metadata = std::fs::metadata(path).with_context(|| format!("Failed to stat {:?}", path))?;
+    // Owner of /proc/self is effective user of the process
+    let euid = std::fs::metadata("/proc/self")
+        .with_context(|| "Failed to get current user")?
+        .uid();
+    check_cli_permissions(metadata.uid(), metadata.mode(), euid)
+        .with_context(|| format!("speedtest_cli_path {:?} is unsafe", path))
 }
 
 impl SpeedTest {
-    pub async fn run(config: &crate::config::SpeedTest) -> anyhow::Result<Self> {
-        info!("Running speed test");
-        let r = tokio::process::Command::new(&config.speedtest_cli_path)
-            .arg("--json")
-            .output()
-            .await?;
+    fn args(config: &crate::config::SpeedTest) -> Vec<String> {
+        let mut args = vec!["--json".to_string()];
+        if let Some(server_id) = &config.server_id {
+            args.push("--server".to_string());
+            args.push(server_id.clone());
+        }
+        if let Some(source_ip) = &config.source_ip {
+            args.push("--source".to_string());
+            args.push(source_ip.to_string());
+        }
+        args
+    }
+
+    /// Runs `speedtest_cli_path`, falling back to `fallback_speedtest_cli_path` if it fails
+    pub async fn run(
+        config: &crate::config::SpeedTest,
+        command_log: &crate::command_log::CommandLog,
+    ) -> anyhow::Result<Self> {
+        let r = Self::run_cli(config, &config.speedtest_cli_path, command_log).await;
+        match (r, &config.fallback_speedtest_cli_path) {
+            (Err(err), Some(fallback)) => {
+                warn!("Speedtest failed, trying fallback {:?}: {err:#}", fallback);
+                Self::run_cli(config, fallback, command_log).await
+            }
+            (r, _) => r,
+        }
+    }
+
+    async fn run_cli(
+        config: &crate::config::SpeedTest,
+        cli: &std::path::Path,
+        command_log: &crate::command_log::CommandLog,
+    ) -> anyhow::Result<Self> {
+        info!("Running speed test with {:?}", cli);
+        if let Err(err) = check_cli_path(cli) {
+            warn!("Security check failed, refusing to run speedtest: {err:#}");
+            return Err(err);
+        }
+        let speed_test = crate::util::retry(
+            config.retry_count,
+            config.retry_interval,
+            2.0,
+            crate::util::classify_spawn_error,
+            || Self::run_once(config, cli, command_log),
+        )
+        .await?;
+        Ok(Self {
+            tool: Some(cli.to_path_buf()),
+            ..speed_test
+        })
+    }
+
+    async fn run_once(
+        config: &crate::config::SpeedTest,
+        cli: &std::path::Path,
+        command_log: &crate::command_log::CommandLog,
+    ) -> anyhow::Result<Self> {
+        let args = Self::args(config);
+        let r = command_log
+            .run(
+                &format!("{} {}", cli.display(), args.join(" ")),
+                tokio::process::Command::new(cli).args(&args),
+            )
+            .await;
+        let r = r?;
 
         let stdout = String::from_utf8_lossy(&r.stdout);
         let stderr = String::from_utf8_lossy(&r.stderr);
         slog_scope::info!("Speed test STDOUT: {}", stdout);
         slog_scope::info!("Speed test STDERR: {}", stderr);
-        let speed_test: SpeedTest = serde_json::from_str(&stdout)?;
+        if stdout.trim().is_empty() {
+            anyhow::bail!(
+                "speedtest produced no output (exit status {}); stderr: {}",
+                r.status,
+                stderr.trim()
+            );
+        }
+        let speed_test: SpeedTest = serde_json::from_str(&stdout)
+            .with_context(|| format!("Failed to parse speedtest output {:?}", stdout))?;
 
         slog_scope::info!("Speed test results: {:?}", speed_test);
 
         Ok(speed_test)
     }
 }
+
+#[test]
+fn test_args_with_server_id() {
+    let mut config = crate::config::test_config().speedtest;
+    assert_eq!(SpeedTest::args(&config), ["--json"]);
+
+    config.server_id = Some("12345".to_string());
+    assert_eq!(SpeedTest::args(&config), ["--json", "--server", "12345"]);
+
+    config.source_ip = Some("10.0.0.2".parse().unwrap());
+    assert_eq!(
+        SpeedTest::args(&config),
+        ["--json", "--server", "12345", "--source", "10.0.0.2"]
+    );
+
+    // speedtest-cli has no option to bind to interface, only to source address
+    let known = ["--json", "--server", "--source"];
+    assert!(SpeedTest::args(&config)
+        .iter()
+        .filter(|v| v.starts_with('-'))
+        .all(|v| known.contains(&v.as_str())));
+}
+
+#[test]
+fn test_mbps_conversion() {
+    let report = SpeedTestReport::from(SpeedTest {
+        download: 12_345_678.9,
+        upload: 999.0,
+        ping: 20.5,
+        tool: None,
+    });
+    assert_eq!(report.download_mbps, 12.35);
+    assert_eq!(report.upload_mbps, 0.0);
+    assert_eq!(report.raw.download, 12_345_678.9);
+}
+
+#[actix_web::test]
+async fn test_run_empty_output() {
+    let (cli, _) = crate::util::fake_command(
+        "speedtest-empty-output",
+        "echo 'Cannot retrieve speedtest configuration' >&2",
+    );
+    let mut config = crate::config::test_config().speedtest;
+    config.speedtest_cli_path = cli;
+
+    let err = SpeedTest::run(&config, &Default::default())
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(err.starts_with("speedtest produced no output"), "{err}");
+    assert!(
+        err.ends_with("stderr: Cannot retrieve speedtest configuration"),
+        "{err}"
+    );
+}
+
+#[actix_web::test]
+async fn test_run_retried() {
+    let (cli, log) = crate::util::fake_command(
+        "speedtest-retry",
+        r#"marker="$(dirname "$0")/failed-once"
+if [ -e "$marker" ]; then echo '{"download": 1, "upload": 2, "ping": 3}'; else touch "$marker"; fi"#,
+    );
+    let _ = std::fs::remove_file(cli.with_file_name("failed-once"));
+    let mut config = crate::config::test_config().speedtest;
+    config.speedtest_cli_path = cli;
+    config.retry_count = 3;
+    config.retry_interval = std::time::Duration::from_millis(1);
+
+    let speed_test = SpeedTest::run(&config, &Default::default()).await.unwrap();
+    assert_eq!(speed_test.upload, 2.0);
+    assert_eq!(std::fs::read_to_string(log).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn test_check_cli_permissions() {
+    assert!(check_cli_permissions(0, 0o100755, 1000).is_ok());
+    assert!(check_cli_permissions(1000, 0o100775, 1000).is_ok());
+    assert!(check_cli_permissions(1001, 0o100755, 1000).is_err());
+    assert!(check_cli_permissions(0, 0o100777, 1000).is_err());
+    assert!(check_cli_permissions(0, 0o100757, 0).is_err());
+}
+
+#[test]
+fn test_check_cli_path() {
+    use std::os::unix::fs::PermissionsExt;
+
+    assert!(check_cli_path(std::path::Path::new("speedtest")).is_err());
+    assert!(check_cli_path(std::path::Path::new("/nonexistent/speedtest")).is_err());
+
+    let (cli, _) = crate::util::fake_command("speedtest-permissions", "exit 0");
+    assert!(check_cli_path(&cli).is_ok());
+    std::fs::set_permissions(&cli, std::fs::Permissions::from_mode(0o777)).unwrap();
+    let err = format!("{:#}", check_cli_path(&cli).unwrap_err());
+    assert!(err.contains("writable by any user"), "{err}");
+}
+
+#[actix_web::test]
+async fn test_run_fallback() {
+    let (primary, _) = crate::util::fake_command("speedtest-primary", "exit 1");
+    let (fallback, _) = crate::util::fake_command(
+        "speedtest-fallback",
+        r#"echo '{"download": 1, "upload": 2, "ping": 3}'"#,
+    );
+    let mut config = crate::config::test_config().speedtest;
+    config.speedtest_cli_path = primary.clone();
+    assert!(SpeedTest::run(&config, &Default::default()).await.is_err());
+
+    config.fallback_speedtest_cli_path = Some(fallback.clone());
+    let speed_test = SpeedTest::run(&config, &Default::default()).await.unwrap();
+    assert_eq!(speed_test.download, 1.0);
+    assert_eq!(speed_test.tool, Some(fallback));
+
+    config.speedtest_cli_path = config.fallback_speedtest_cli_path.take().unwrap();
+    config.fallback_speedtest_cli_path = Some(primary);
+    let speed_test = SpeedTest::run(&config, &Default::default()).await.unwrap();
+    assert_eq!(speed_test.tool.as_ref(), Some(&config.speedtest_cli_path));
+}
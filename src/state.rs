@@ -1,177 +1,510 @@
 use crate::speedtest::SpeedTest;
-use anyhow::bail;
-use slog_scope::{error, info};
+use anyhow::{anyhow, bail, Context};
+use slog_scope::{crit, error, info, warn};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-async fn check_is_wide_internet_available(config: &crate::config::Ping) -> bool {
+/// Builds ICMP client config for pinging `target`, ICMPv6 is used for IPv6 targets
+fn ping_client_config(
+    config: &crate::config::Ping,
+    target: std::net::IpAddr,
+) -> surge_ping::Config {
+    let kind = match target {
+        std::net::IpAddr::V4(_) => surge_ping::ICMP::V4,
+        std::net::IpAddr::V6(_) => surge_ping::ICMP::V6,
+    };
+    let mut builder = surge_ping::Config::builder().kind(kind);
+    if let Some(source_ip) = config.source_ip {
+        builder = builder.bind(std::net::SocketAddr::new(source_ip, 0));
+    }
+    if let Some(interface) = &config.bind_interface {
+        builder = builder.interface(interface);
+    }
+    builder.build()
+}
+
+/// ICMP sockets are raw ones, so creating them requires CAP_NET_RAW
+fn is_permission_error(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::PermissionDenied
+}
+
+async fn check_http_connectivity(url: &str) -> bool {
+    info!("Checking wide network availability with HTTP request to {url}");
+    let client = match reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+    {
+        Ok(v) => v,
+        Err(err) => {
+            error!("Unable to initialize HTTP client: {err}");
+            return false;
+        }
+    };
+    match client.get(url).send().await {
+        Ok(response) => response.status().is_success(),
+        Err(err) => {
+            info!("HTTP connectivity check failed: {err}");
+            false
+        }
+    }
+}
+
+/// Result of wide network check
+#[derive(Clone, Copy)]
+struct Connectivity {
+    available: bool,
+    /// Round-trip time of successful ICMP ping, unknown for HTTP check
+    rtt: Option<std::time::Duration>,
+}
+
+impl Connectivity {
+    fn without_rtt(available: bool) -> Self {
+        Self {
+            available,
+            rtt: None,
+        }
+    }
+}
+
+async fn check_is_wide_internet_available(config: &crate::config::Ping) -> Connectivity {
     info!("Checking if wide network is available");
-    let ping_client = match surge_ping::Client::new(&surge_ping::Config::new()) {
+    let ping_client = match surge_ping::Client::new(&ping_client_config(config, config.server)) {
         Ok(v) => v,
+        Err(err) if is_permission_error(&err) => {
+            crit!(
+                "Not permitted to create ICMP socket: {err}. Grant CAP_NET_RAW to the binary \
+                 (setcap cap_net_raw+ep) or run it as root"
+            );
+            return Connectivity::without_rtt(match &config.http_fallback_url {
+                Some(url) => check_http_connectivity(url).await,
+                None => false,
+            });
+        }
         Err(err) => {
             error!("Unable to initialize pinger: {err}");
-            return false;
+            return Connectivity::without_rtt(false);
         }
     };
     let mut pinger = ping_client
         .pinger(config.server, surge_ping::PingIdentifier::from(1))
         .await;
     pinger.timeout(std::time::Duration::from_secs(10));
-    let mut success = false;
+    let mut rtt = None;
     for seq in 0..3 {
-        if pinger
+        match pinger
             .ping(surge_ping::PingSequence::from(seq), &[1, 2, 3])
             .await
-            .is_ok()
         {
-            success = true;
-            break;
-        } else {
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            Ok((_, duration)) => {
+                rtt = Some(duration);
+                break;
+            }
+            Err(_) => tokio::time::sleep(std::time::Duration::from_secs(1)).await,
+        }
+    }
+
+    info!(
+        "is_wide_network_available = {}, rtt = {rtt:?}",
+        rtt.is_some()
+    );
+
+    Connectivity {
+        available: rtt.is_some(),
+        rtt,
+    }
+}
+
+/// Records ping result with its RTT and runs `on_internet_down_command` once network becomes
+/// unavailable. Further failed checks don't run it again until network is back
+async fn record_ping_result(
+    config: &crate::config::Ping,
+    shell: &std::path::Path,
+    persistent_state: &crate::persistent_state::PersistentStateGuard,
+    command_log: &crate::command_log::CommandLog,
+    connectivity: Connectivity,
+) -> anyhow::Result<()> {
+    let (was_available, is_available) = persistent_state
+        .update(|persistent_state| {
+            let was_available = persistent_state.is_wide_network_available;
+            persistent_state.last_ping_rtt_ms = connectivity.rtt.map(|v| v.as_secs_f64() * 1000.0);
+            persistent_state.record_ping(
+                connectivity.available,
+                config.failure_threshold,
+                config.success_threshold,
+            );
+            (was_available, persistent_state.is_wide_network_available)
+        })
+        .await
+        .context("Unable to update persistent state")?;
+
+    if is_available != Some(false) || was_available == Some(false) {
+        return Ok(());
+    }
+    let Some(command) = &config.on_internet_down_command else {
+        return Ok(());
+    };
+    info!("Wide network became unavailable, running {command}");
+    let argv = crate::mobile_provider::command_argv(shell, command);
+    let mut process = tokio::process::Command::new(&argv[0]);
+    // Killed once its future is dropped on timeout
+    process.args(&argv[1..]).kill_on_drop(true);
+    let output = tokio::time::timeout(
+        config.on_internet_down_timeout,
+        command_log.run(command, &mut process),
+    )
+    .await;
+    match output {
+        Err(_) => error!(
+            "Internet down command timed out after {:?}, killed",
+            config.on_internet_down_timeout
+        ),
+        Ok(Ok(output)) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if output.status.success() {
+                info!("Internet down command finished: {}", stdout.trim());
+            } else {
+                error!(
+                    "Internet down command exited with {}: {} {}",
+                    output.status,
+                    stdout.trim(),
+                    stderr.trim()
+                );
+            }
+        }
+        Ok(Err(err)) => error!("Failed to run internet down command: {err}"),
+    }
+    Ok(())
+}
+
+/// Warns once ACL takes `acl_size_alert_ratio` of `max_acl_size`, alerting telegram chats
+/// of mobile provider if configured. Returns whether the alert was raised
+pub async fn check_acl_size(
+    config: &crate::config::Config,
+    persistent_state: &crate::persistent_state::PersistentStateGuard,
+    counters: &crate::metrics::Counters,
+    acl_size: usize,
+) -> anyhow::Result<bool> {
+    let Some(ratio) = config.acl_capacity_used_ratio(acl_size) else {
+        return Ok(false);
+    };
+    let is_over = ratio >= config.acl_size_alert_ratio;
+    // Cheap check first, so unchanged state isn't rewritten on every call
+    if persistent_state.get().await.acl_size_alerted == is_over {
+        return Ok(false);
+    }
+    // Concurrent checks may have passed the one above, only one of them flips the flag
+    let is_flipped = persistent_state
+        .update(|state| std::mem::replace(&mut state.acl_size_alerted, is_over) != is_over)
+        .await?;
+    if !is_flipped {
+        return Ok(false);
+    }
+    if !is_over {
+        info!("ACL size is back below alert threshold: {acl_size} entries");
+        return Ok(false);
+    }
+
+    let message = format!(
+        "ACL ipset is {:.0}% full: {acl_size} of {} entries. New clients can't register \
+         once it is full",
+        ratio * 100.0,
+        config.max_acl_size.unwrap_or_default()
+    );
+    warn!("{message}");
+    if let (Some(telegram), Some(mobile_provider)) = (&config.telegram, &config.mobile_provider) {
+        telegram
+            .send_message(
+                persistent_state,
+                counters,
+                &mobile_provider.telegram_chat_ids,
+                crate::telegram::Severity::Warning,
+                &message,
+            )
+            .await;
+    }
+    Ok(true)
+}
+
+/// Runs speedtest and stores its results. Failed runs keep previous results and
+/// `last_successful_speedtest` untouched
+async fn run_speedtest(
+    config: &crate::config::SpeedTest,
+    command_log: &crate::command_log::CommandLog,
+    persistent_state: &crate::persistent_state::PersistentStateGuard,
+) -> anyhow::Result<SpeedTest> {
+    let speedtest = SpeedTest::run(config, command_log).await?;
+    let speedtest1 = speedtest.clone();
+    let r = persistent_state
+        .update(|persistent_state| {
+            persistent_state.speedtest = Some(speedtest1);
+            persistent_state.last_successful_speedtest = Some(chrono::Utc::now());
+        })
+        .await;
+    if let Err(err) = r {
+        error!("Unable to update persistent state: {err}");
+    }
+
+    Ok(speedtest)
+}
+
+/// Refreshes `no_shaping_ips` entries in ACL and no_shape sets, `ipset` builds set by name.
+/// Every IP is tried even if some fail
+async fn refresh_whitelist(
+    config: &crate::config::Config,
+    ipset: impl Fn(&str) -> crate::ipset::IPSet,
+) -> anyhow::Result<()> {
+    let mut ips = config.no_shaping_ips.iter().collect::<Vec<_>>();
+    ips.sort();
+    let mut failed = 0;
+    for ip in ips {
+        let names = config.ipset_names(ip);
+        for set in [ipset(names.acl), ipset(names.no_shape)] {
+            if let Err(err) = set.refresh(ip, Some(config.no_shaping_timeout)).await {
+                error!("Unable to refresh {ip} in {} ipset: {err}", set.name());
+                failed += 1;
+            }
         }
     }
+    if failed > 0 {
+        bail!("{failed} whitelist entries failed to refresh");
+    }
+    Ok(())
+}
 
-    info!("is_wide_network_available = {success}");
+/// Substitutes `{ip}` and `{mac}` in `command`. Both are made of hex digits, `.` and `:`
+/// only, so they can't add shell syntax to the command
+fn on_register_command(command: &str, ip: &str, mac: &str) -> String {
+    command.replace("{ip}", ip).replace("{mac}", mac)
+}
 
-    success
+/// Scheduled job, which may also be run once from command line
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Job {
+    Ping,
+    Speedtest,
+    Balance,
+    Telegram,
+    Whitelist,
+    Maintenance,
 }
 
 pub struct State {
     config: crate::config::Config,
     scheduler: tokio_cron_scheduler::JobScheduler,
     persistent_state: crate::persistent_state::PersistentStateGuard,
+    response_cache: crate::response_cache::ResponseCache,
+    http_metrics: Arc<crate::metrics::HttpMetrics>,
+    counters: Arc<crate::metrics::Counters>,
+    registrations: Arc<crate::http::Registrations>,
+    command_log: crate::command_log::CommandLog,
+    telegram_reachability: Arc<crate::readiness::TelegramReachability>,
 }
 
 impl State {
+    /// Checks wide network availability and records it in persistent state
+    pub async fn ping_job(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        let (config, shell, persistent_state, command_log) = {
+            let state = state.lock().await;
+            (
+                state.config.ping.clone(),
+                state.config.shell(),
+                state.persistent_state.clone(),
+                state.command_log.clone(),
+            )
+        };
+        let connectivity = check_is_wide_internet_available(&config).await;
+        record_ping_result(
+            &config,
+            &shell,
+            &persistent_state,
+            &command_log,
+            connectivity,
+        )
+        .await
+    }
+
+    /// Runs speedtest and updates tariff if download speed is too low
+    pub async fn speedtest_job(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        let (config, command_log, persistent_state) = {
+            let state = state.lock().await;
+            (
+                state.config.speedtest.clone(),
+                state.command_log.clone(),
+                state.persistent_state.clone(),
+            )
+        };
+        run_speedtest(&config, &command_log, &persistent_state)
+            .await
+            .context("Unable to run speedtest")?;
+
+        let state = state.lock().await;
+        if let Some(mobile_provider) = &state.config.mobile_provider {
+            mobile_provider
+                .update_tariff(
+                    &state.config,
+                    &state.persistent_state,
+                    &state.command_log,
+                    &state.counters,
+                )
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Gets balance, alerting if it is low. Skipped if the last check was too recent
+    pub async fn balance_job(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        let (config, persistent_state, command_log, counters) = {
+            let state = state.lock().await;
+            (
+                state.config.clone(),
+                state.persistent_state.clone(),
+                state.command_log.clone(),
+                state.counters.clone(),
+            )
+        };
+        let Some(provider) = &config.mobile_provider else {
+            bail!("Section mobile_provider is not defined in configuration");
+        };
+
+        let now = chrono::Utc::now();
+        let last_check = persistent_state.get().await.last_balance_check;
+        if !provider.is_balance_check_due(last_check, now) {
+            info!("Last balance check was too recent, skipping");
+            return Ok(());
+        }
+        let r = persistent_state
+            .update(|state| state.last_balance_check = Some(now))
+            .await;
+        if let Err(err) = r {
+            error!("Unable to update persistent state: {err}");
+        }
+
+        let balance = provider
+            .get_and_alert_balance(&persistent_state, &config.telegram, &command_log, &counters)
+            .await
+            .context("Unable to get balance")?;
+        persistent_state
+            .update(|state| {
+                state.balance = Some(balance);
+            })
+            .await
+            .context("Unable to update balance in persistent storage")
+    }
+
+    /// Sends queued telegram messages, dropping expired ones
+    pub async fn telegram_job(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        let (telegram, timezone, persistent_state, counters) = {
+            let state = state.lock().await;
+            (
+                state.config.telegram.clone(),
+                state.config.timezone,
+                state.persistent_state.clone(),
+                state.counters.clone(),
+            )
+        };
+        let Some(telegram) = telegram else {
+            bail!("Section telegram is not defined in configuration");
+        };
+        telegram
+            .process_queue(&persistent_state, &counters, timezone)
+            .await
+            .context("Unable to process telegram queue")
+    }
+
+    /// Re-adds `no_shaping_ips` to ipsets, so their entries don't expire
+    pub async fn whitelist_job(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        let config = { state.lock().await.config.clone() };
+        if config.read_only {
+            info!("Skipping whitelist refresh in read-only mode");
+            return Ok(());
+        }
+        info!("Refreshing whitelisted IPs in ipsets");
+        refresh_whitelist(&config, crate::ipset::IPSet::new).await
+    }
+
+    /// Drops persistent state records past configured retention
+    pub async fn maintenance_job(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        state.lock().await.prune_persistent_state().await?;
+        Ok(())
+    }
+
+    pub async fn run_job(state: Arc<Mutex<Self>>, job: Job) -> anyhow::Result<()> {
+        match job {
+            Job::Ping => Self::ping_job(state).await,
+            Job::Speedtest => Self::speedtest_job(state).await,
+            Job::Balance => Self::balance_job(state).await,
+            Job::Telegram => Self::telegram_job(state).await,
+            Job::Whitelist => Self::whitelist_job(state).await,
+            Job::Maintenance => Self::maintenance_job(state).await,
+        }
+    }
+
+    /// Adds `job` to scheduler, logging its failures
+    async fn schedule_job(
+        &self,
+        state: Arc<Mutex<Self>>,
+        crontab: &str,
+        job: Job,
+    ) -> anyhow::Result<()> {
+        use tokio_cron_scheduler::Job as CronJob;
+
+        self.scheduler
+            .add(CronJob::new_async(crontab, move |_uuid, _l| {
+                let state = state.clone();
+                Box::pin(async move {
+                    if let Err(err) = Self::run_job(state, job).await {
+                        error!("Job {job:?} failed: {err:#}");
+                    }
+                })
+            })?)
+            .await?;
+        Ok(())
+    }
+
     pub async fn init_cronjobs(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
-        use tokio_cron_scheduler::Job;
-        let state1 = state.clone();
         let state_guard = state.lock().await;
         info!("Starting ping scheduled processor");
         state_guard
-            .scheduler
-            .add(Job::new_async(
-                &state_guard.config.ping.crontab,
-                move |_uuid, _l| {
-                    let state1 = state1.clone();
-                    Box::pin(async move {
-                        let config = { state1.lock().await.config.ping.clone() };
-                        let is_wide_network_available =
-                            check_is_wide_internet_available(&config).await;
-                        let state = state1.lock().await;
-                        let r = state
-                            .persistent_state
-                            .update(|persistent_state| {
-                                persistent_state.is_wide_network_available =
-                                    Some(is_wide_network_available)
-                            })
-                            .await;
-                        if let Err(err) = r {
-                            error!("Unable to update persistent state: {err}");
-                        }
-                    })
-                },
-            )?)
+            .schedule_job(state.clone(), &state_guard.config.ping.crontab, Job::Ping)
             .await?;
 
-        let state1 = state.clone();
         info!("Starting speedtest scheduled processor");
         state_guard
-            .scheduler
-            .add(Job::new_async(
+            .schedule_job(
+                state.clone(),
                 &state_guard.config.speedtest.crontab,
-                move |_uuid, _l| {
-                    let state1 = state1.clone();
-                    Box::pin(async move {
-                        let config = { state1.lock().await.config.speedtest.clone() };
-                        match SpeedTest::run(&config).await {
-                            Ok(speedtest) => {
-                                let state = state1.lock().await;
-                                let r = state
-                                    .persistent_state
-                                    .update(|persistent_state| {
-                                        persistent_state.speedtest = Some(speedtest)
-                                    })
-                                    .await;
-                                if let Err(err) = r {
-                                    error!("Unable to update persistent state: {err}");
-                                }
-
-                                if let Some(mobile_provider) = &state.config.mobile_provider {
-                                    mobile_provider
-                                        .update_tariff(&state.config, &state.persistent_state)
-                                        .await;
-                                }
-                            }
-                            Err(err) => {
-                                error!("Unable to run speedtest: {err}");
-                            }
-                        }
-                    })
-                },
-            )?)
+                Job::Speedtest,
+            )
             .await?;
 
         if let Some(provider) = &state_guard.config.mobile_provider {
             if let Some(crontab) = &provider.get_balance_crontab {
-                let state1 = state.clone();
-                let provider1 = provider.clone();
-                let persistent_state = state_guard.persistent_state.clone();
                 info!("Starting balance scheduled processor");
                 state_guard
-                    .scheduler
-                    .add(Job::new_async(crontab, move |_uuid, _l| {
-                        let state1 = state1.clone();
-                        let provider1 = provider1.clone();
-                        let persistent_state = persistent_state.clone();
-                        Box::pin(async move {
-                            let config = { state1.lock().await.config.clone() };
-                            let balance = match provider1
-                                .get_and_alert_balance(&persistent_state, &config.telegram)
-                                .await
-                            {
-                                Ok(balance) => balance,
-                                Err(err) => {
-                                    error!("Unable to get balance: {err}");
-                                    return;
-                                }
-                            };
-                            let r = state1
-                                .lock()
-                                .await
-                                .persistent_state
-                                .update(|state| {
-                                    state.balance = Some(balance);
-                                })
-                                .await;
-
-                            if let Err(err) = r {
-                                error!("Unable to update balance in persistent storage: {err}")
-                            }
-                        })
-                    })?)
+                    .schedule_job(state.clone(), crontab, Job::Balance)
                     .await?;
             }
         }
 
         if let Some(telegram) = &state_guard.config.telegram {
-            let persistent_state = state_guard.persistent_state.clone();
-            let telegram1 = telegram.clone();
             info!("Starting telegram queue scheduled processor");
             state_guard
-                .scheduler
-                .add(Job::new_async(
-                    &telegram.retry_crontab,
-                    move |_uuid, _l| {
-                        let persistent_state = persistent_state.clone();
-                        let telegram = telegram1.clone();
-                        Box::pin(async move {
-                            if let Err(err) = telegram.process_queue(&persistent_state).await {
-                                error!("Unable to process telegram queue: {err}");
-                            }
-                        })
-                    },
-                )?)
+                .schedule_job(state.clone(), &telegram.retry_crontab, Job::Telegram)
+                .await?;
+        }
+
+        if let Some(crontab) = &state_guard.config.whitelist_refresh_crontab {
+            info!("Starting whitelist refresh scheduled processor");
+            state_guard
+                .schedule_job(state.clone(), crontab, Job::Whitelist)
+                .await?;
+        }
+
+        if let Some(crontab) = &state_guard.config.maintenance_crontab {
+            info!("Starting persistent state maintenance scheduled processor");
+            state_guard
+                .schedule_job(state.clone(), crontab, Job::Maintenance)
                 .await?;
         }
 
@@ -183,7 +516,11 @@ pub async fn init_cronjobs(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
     pub async fn get_balance(&self) -> anyhow::Result<f64> {
         let config = self.config.clone();
         let balance = match config.mobile_provider {
-            Some(ref provider) => provider.get_balance().await?,
+            Some(ref provider) => {
+                provider
+                    .get_balance(&self.command_log, &self.counters)
+                    .await?
+            }
             None => bail!("Section mobile_provider is not defined in configuration"),
         };
         let r = self
@@ -200,20 +537,12 @@ pub async fn get_balance(&self) -> anyhow::Result<f64> {
     }
 
     pub async fn get_speedtest(&self) -> anyhow::Result<crate::speedtest::SpeedTest> {
-        let config = self.config.clone();
-        let speedtest = SpeedTest::run(&config.speedtest).await?;
-        let speedtest1 = speedtest.clone();
-        let r = self
-            .persistent_state
-            .update(|persistent_state| {
-                persistent_state.speedtest = Some(speedtest1);
-            })
-            .await;
-        if let Err(err) = r {
-            error!("Unable to update persistent state: {err}");
-        }
-
-        Ok(speedtest)
+        run_speedtest(
+            &self.config.speedtest,
+            &self.command_log,
+            &self.persistent_state,
+        )
+        .await
     }
 
     pub async fn new(config: &crate::config::Config) -> anyhow::Result<Arc<Mutex<Self>>> {
@@ -223,13 +552,157 @@ pub async fn new(config: &crate::config::Config) -> anyhow::Result<Arc<Mutex<Sel
             config: config.clone(),
             persistent_state: crate::persistent_state::PersistentStateGuard::load_from_yaml(
                 &config.persistent_state_path,
-            ),
+            )
+            .with_backups(config.persistent_state_backups.clone())
+            .with_compression(config.persistent_state_compress),
             scheduler: JobScheduler::new().await?,
+            response_cache: Default::default(),
+            http_metrics: Default::default(),
+            counters: Default::default(),
+            registrations: Arc::new(crate::coalesce::Coalescer::new(
+                crate::http::REGISTRATION_DEDUP_WINDOW,
+            )),
+            command_log: crate::command_log::CommandLog::new(config.command_log_size)
+                .with_max_captured_output(config.max_command_output)
+                .with_allowlist(config.command_allowlist.as_deref())?,
+            telegram_reachability: Default::default(),
         }));
 
         Ok(state)
     }
 
+    /// Re-reads config from the file it was loaded from. Current config is kept if the new
+    /// one is invalid. Cron schedules, `http_listen` and `persistent_state_path` are applied
+    /// only on restart. Jobs already running keep command log settings they started with
+    pub fn reload_config(&mut self) -> anyhow::Result<()> {
+        let path = self
+            .config
+            .config_path
+            .clone()
+            .ok_or_else(|| anyhow!("Config was not loaded from file"))?;
+        let mut config = crate::config::Config::read(&path)?;
+        if let (Some(provider), Some(previous)) =
+            (&mut config.mobile_provider, &self.config.mobile_provider)
+        {
+            provider.keep_runtime_state(previous);
+        }
+        self.command_log = self
+            .command_log
+            .clone()
+            .with_capacity(config.command_log_size)
+            .with_max_captured_output(config.max_command_output)
+            .with_allowlist(config.command_allowlist.as_deref())?;
+        self.config = config;
+        info!("Config reloaded from {path}");
+        Ok(())
+    }
+
+    /// Reloads config on every SIGHUP
+    pub fn reload_on_sighup(state: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Got SIGHUP, reloading config");
+                if let Err(err) = state.lock().await.reload_config() {
+                    error!("Failed to reload config: {err:#}");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub async fn record_client_session(&self, mac: &str) -> anyhow::Result<()> {
+        let limit = self.config.client_history_limit;
+        self.persistent_state
+            .update(|persistent_state| {
+                persistent_state.record_client_session(mac, chrono::Utc::now(), limit)
+            })
+            .await
+    }
+
+    pub async fn record_client_deregistration(&self, mac: &str) -> anyhow::Result<()> {
+        self.persistent_state
+            .update(|persistent_state| {
+                persistent_state.record_client_deregistration(mac, chrono::Utc::now())
+            })
+            .await
+    }
+
+    /// Drops persistent state records past configured retention
+    pub async fn prune_persistent_state(&self) -> anyhow::Result<usize> {
+        let retention = self.config.retention();
+        let removed = self
+            .persistent_state
+            .update(|persistent_state| persistent_state.prune(chrono::Utc::now(), &retention))
+            .await?;
+        info!("Pruned {removed} persistent state records");
+        Ok(removed)
+    }
+
+    /// Runs `check_acl_size` in background, so registration doesn't wait for alert sending
+    pub fn spawn_acl_size_check(&self, acl_size: usize) -> Option<tokio::task::JoinHandle<()>> {
+        self.config.max_acl_size?;
+        let config = self.config.clone();
+        let persistent_state = self.persistent_state.clone();
+        let counters = self.counters.clone();
+        Some(tokio::spawn(async move {
+            if let Err(err) = check_acl_size(&config, &persistent_state, &counters, acl_size).await
+            {
+                error!("Unable to check ACL size: {err:#}");
+            }
+        }))
+    }
+
+    /// Runs `on_register_command` in background, logging its output. `mac` is empty for
+    /// clients without one
+    pub fn spawn_on_register(&self, ip: &str, mac: &str) -> Option<tokio::task::JoinHandle<()>> {
+        let command = on_register_command(self.config.on_register_command.as_ref()?, ip, mac);
+        if command.trim().is_empty() {
+            return None;
+        }
+        let argv = crate::mobile_provider::command_argv(&self.config.shell(), &command);
+        let command_log = self.command_log.clone();
+        Some(tokio::spawn(async move {
+            let output = command_log
+                .run(
+                    &command,
+                    tokio::process::Command::new(&argv[0]).args(&argv[1..]),
+                )
+                .await;
+            match output {
+                Ok(output) => {
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    if output.status.success() {
+                        info!("On register command succeeded, stdout: {stdout}, stderr: {stderr}");
+                    } else {
+                        error!(
+                            "On register command exited with {}, stdout: {stdout}, stderr: {stderr}",
+                            output.status
+                        );
+                    }
+                }
+                Err(err) => error!("Failed to run on register command: {err}"),
+            }
+        }))
+    }
+
+    pub async fn set_maintenance(&self, enabled: bool) -> anyhow::Result<()> {
+        self.persistent_state
+            .update(|persistent_state| persistent_state.maintenance = enabled)
+            .await
+    }
+
+    /// Removes client from ACL, shaper and no_shape ipsets, returning names of sets which
+    /// contained it
+    pub async fn remove_client_everywhere(&self, ip: &str) -> anyhow::Result<Vec<String>> {
+        let names = self.config.ipset_names(ip);
+        let sets = [names.acl, names.shaper, names.no_shape].map(crate::ipset::IPSet::new);
+        crate::ipset::IPSet::del_everywhere(&sets, ip).await
+    }
+
     pub async fn persistent_state(&self) -> crate::persistent_state::PersistentState {
         self.persistent_state.get().await
     }
@@ -237,4 +710,472 @@ pub async fn persistent_state(&self) -> crate::persistent_state::PersistentState
     pub fn config(&self) -> &crate::config::Config {
         &self.config
     }
+
+    pub fn command_log(&self) -> &crate::command_log::CommandLog {
+        &self.command_log
+    }
+
+    pub fn http_metrics(&self) -> Arc<crate::metrics::HttpMetrics> {
+        self.http_metrics.clone()
+    }
+
+    pub fn counters(&self) -> &crate::metrics::Counters {
+        &self.counters
+    }
+
+    /// In-flight and recent registrations, shared outside of state lock so concurrent
+    /// requests can wait for each other
+    pub fn registrations(&self) -> Arc<crate::http::Registrations> {
+        self.registrations.clone()
+    }
+
+    pub fn telegram_reachability(&self) -> Arc<crate::readiness::TelegramReachability> {
+        self.telegram_reachability.clone()
+    }
+
+    pub fn response_cache(&mut self) -> &mut crate::response_cache::ResponseCache {
+        &mut self.response_cache
+    }
+}
+
+#[test]
+fn test_ping_client_config() {
+    let mut config = crate::config::test_config().ping;
+    let client_config = ping_client_config(&config, config.server);
+    assert!(client_config.bind.is_none());
+    assert!(client_config.interface.is_none());
+
+    config.source_ip = Some("10.0.0.2".parse().unwrap());
+    config.bind_interface = Some("lte1".to_string());
+    let client_config = ping_client_config(&config, config.server);
+    assert!(client_config.bind.is_some());
+    assert_eq!(client_config.interface.as_deref(), Some("lte1"));
+}
+
+#[test]
+fn test_ping_client_config_ipv6() {
+    let config = crate::config::test_config().ping;
+    assert!(matches!(
+        ping_client_config(&config, config.server).kind,
+        surge_ping::ICMP::V4
+    ));
+    assert!(matches!(
+        ping_client_config(&config, "2606:4700:4700::1111".parse().unwrap()).kind,
+        surge_ping::ICMP::V6
+    ));
+}
+
+#[actix_web::test]
+async fn test_reload_config() {
+    let path = crate::persistent_state::TempStatePath::new("reload-config");
+    let path_str = path.to_str().unwrap();
+    let mut config = crate::config::test_config();
+    std::fs::write(&path, serde_yaml::to_string(&config).unwrap()).unwrap();
+    config.config_path = Some(path_str.to_string());
+
+    let state = State::new(&config).await.unwrap();
+    let mut state = state.lock().await;
+
+    config.ipset_acl_name = "acl2".to_string();
+    std::fs::write(&path, serde_yaml::to_string(&config).unwrap()).unwrap();
+    state.reload_config().unwrap();
+    assert_eq!(state.config().ipset_acl_name, "acl2");
+
+    config.command_allowlist = Some(vec!["true".to_string()]);
+    std::fs::write(&path, serde_yaml::to_string(&config).unwrap()).unwrap();
+    state.reload_config().unwrap();
+    let r = state
+        .command_log()
+        .run("false", &mut tokio::process::Command::new("false"))
+        .await;
+    assert_eq!(r.unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+
+    std::fs::write(&path, "log_level: [").unwrap();
+    assert!(state.reload_config().is_err());
+    assert_eq!(state.config().ipset_acl_name, "acl2");
+}
+
+#[test]
+fn test_is_permission_error() {
+    assert!(is_permission_error(&std::io::Error::from_raw_os_error(1)));
+    assert!(is_permission_error(&std::io::Error::from_raw_os_error(13)));
+    assert!(!is_permission_error(&std::io::Error::from(
+        std::io::ErrorKind::AddrNotAvailable
+    )));
+}
+
+#[actix_web::test]
+async fn test_failed_speedtest_keeps_last_success() {
+    let path = crate::persistent_state::TempStatePath::new("last-speedtest");
+    let persistent_state = crate::persistent_state::PersistentStateGuard::load_from_yaml(&path);
+    let command_log = crate::command_log::CommandLog::default();
+    let mut config = crate::config::test_config().speedtest;
+
+    let (cli, _) = crate::util::fake_command(
+        "speedtest-success",
+        r#"echo '{"download": 1000000.0, "upload": 500000.0, "ping": 20.0}'"#,
+    );
+    config.speedtest_cli_path = cli;
+    run_speedtest(&config, &command_log, &persistent_state)
+        .await
+        .unwrap();
+    let last_success = persistent_state.get().await.last_successful_speedtest;
+    assert!(last_success.is_some());
+
+    let (cli, _) = crate::util::fake_command("speedtest-failure", "exit 1");
+    config.speedtest_cli_path = cli;
+    assert!(run_speedtest(&config, &command_log, &persistent_state)
+        .await
+        .is_err());
+    let persistent_state = persistent_state.get().await;
+    assert_eq!(persistent_state.last_successful_speedtest, last_success);
+    assert_eq!(persistent_state.speedtest.unwrap().download, 1000000.0);
+}
+
+#[actix_web::test]
+async fn test_prune_persistent_state() {
+    let path = crate::persistent_state::TempStatePath::new("prune");
+    let mut persistent_state = crate::persistent_state::PersistentState::default();
+    let now = chrono::Utc::now();
+    persistent_state.record_client_session(
+        "aa:bb:cc:dd:ee:ff",
+        now - chrono::TimeDelta::days(10),
+        5,
+    );
+    persistent_state.record_client_session("aa:bb:cc:dd:ee:ff", now, 5);
+    std::fs::write(&path, serde_yaml::to_string(&persistent_state).unwrap()).unwrap();
+
+    let mut config = crate::config::test_config();
+    config.persistent_state_path = path.to_path_buf();
+    config.client_history_max_age = Some(std::time::Duration::from_secs(7 * 24 * 60 * 60));
+    let state = State::new(&config).await.unwrap();
+    let state = state.lock().await;
+
+    assert_eq!(state.prune_persistent_state().await.unwrap(), 1);
+    assert_eq!(
+        state
+            .persistent_state()
+            .await
+            .client_history("aa:bb:cc:dd:ee:ff"),
+        [now]
+    );
+}
+
+#[actix_web::test]
+async fn test_on_register_command() {
+    let (command, log) = crate::util::fake_command("on-register", "exit 0");
+    let mut config = crate::config::test_config();
+    config.on_register_command = Some(format!("{} {{ip}} --mac={{mac}}", command.display()));
+    let state = State::new(&config).await.unwrap();
+    let state = state.lock().await;
+
+    state
+        .spawn_on_register("10.11.2.10", "00:11:22:33:44:55")
+        .unwrap()
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "10.11.2.10 --mac=00:11:22:33:44:55\n"
+    );
+
+    config.on_register_command = Some(format!(
+        "{} {{ip}} 'client {{mac}} registered'",
+        command.display()
+    ));
+    std::fs::remove_file(&log).unwrap();
+    let state = State::new(&config).await.unwrap();
+    state
+        .lock()
+        .await
+        .spawn_on_register("10.11.2.10", "00:11:22:33:44:55")
+        .unwrap()
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "10.11.2.10 client 00:11:22:33:44:55 registered\n"
+    );
+
+    config.on_register_command = None;
+    let state = State::new(&config).await.unwrap();
+    assert!(state
+        .lock()
+        .await
+        .spawn_on_register("10.11.2.10", "")
+        .is_none());
+}
+
+#[cfg(test)]
+fn job_test_config(
+    test_name: &str,
+) -> (
+    crate::config::Config,
+    crate::persistent_state::TempStatePath,
+) {
+    let path = crate::persistent_state::TempStatePath::new(&format!("job-{test_name}"));
+    let mut config = crate::config::test_config();
+    config.persistent_state_path = path.to_path_buf();
+    (config, path)
+}
+
+#[actix_web::test]
+async fn test_ping_job() {
+    let (mut config, _path) = job_test_config("ping");
+    config.ping.server = "127.0.0.1".parse().unwrap();
+    let state = State::new(&config).await.unwrap();
+
+    State::run_job(state.clone(), Job::Ping).await.unwrap();
+    let persistent_state = state.lock().await.persistent_state().await;
+    assert!(persistent_state.is_wide_network_available.is_some());
+}
+
+#[actix_web::test]
+async fn test_speedtest_job() {
+    let (mut config, _path) = job_test_config("speedtest");
+    let (cli, _) = crate::util::fake_command(
+        "speedtest-job",
+        r#"echo '{"download": 1000000.0, "upload": 500000.0, "ping": 20.0}'"#,
+    );
+    config.speedtest.speedtest_cli_path = cli;
+    let state = State::new(&config).await.unwrap();
+
+    State::run_job(state.clone(), Job::Speedtest).await.unwrap();
+    let persistent_state = state.lock().await.persistent_state().await;
+    assert_eq!(persistent_state.speedtest.unwrap().upload, 500000.0);
+
+    let (cli, _) = crate::util::fake_command("speedtest-job-failure", "exit 1");
+    config.speedtest.speedtest_cli_path = cli;
+    let state = State::new(&config).await.unwrap();
+    assert!(State::run_job(state, Job::Speedtest).await.is_err());
+}
+
+#[actix_web::test]
+async fn test_balance_job() {
+    let (mut config, _path) = job_test_config("balance");
+    let state = State::new(&config).await.unwrap();
+    assert!(State::run_job(state, Job::Balance).await.is_err());
+
+    let provider: crate::mobile_provider::MobileProvider = serde_yaml::from_str(
+        r#"
+update_tariff_command: "true"
+get_balance_command: "printf 'BALANCE=1500\n'"
+balance_parse_command: "sed -n 's/^BALANCE=//p'"
+low_balance_threshold: 100
+low_download_speed_threshold: 1000000
+min_update_tariff_interval: 1w
+telegram_chat_ids: []
+phone_number: "+996 000 000 000"
+get_balance_retry_count: 1
+get_balance_retry_interval: 1s
+restart_lte_command: "true"
+min_balance_check_interval: 1h
+"#,
+    )
+    .unwrap();
+    config.mobile_provider = Some(provider);
+    let state = State::new(&config).await.unwrap();
+
+    State::run_job(state.clone(), Job::Balance).await.unwrap();
+    let persistent_state = state.lock().await.persistent_state().await;
+    assert_eq!(persistent_state.balance, Some(1500.0));
+    assert!(persistent_state.last_balance_check.is_some());
+}
+
+#[actix_web::test]
+async fn test_telegram_job() {
+    let (mut config, _path) = job_test_config("telegram");
+    let state = State::new(&config).await.unwrap();
+    assert!(State::run_job(state, Job::Telegram).await.is_err());
+
+    config.telegram = Some(
+        serde_yaml::from_str(
+            "bot_token: 123456:TOKEN\nmessage_timeout: 1h\nretry_crontab: '* * * * * *'",
+        )
+        .unwrap(),
+    );
+    let state = State::new(&config).await.unwrap();
+    state
+        .lock()
+        .await
+        .persistent_state
+        .update(|persistent_state| {
+            persistent_state
+                .telegram_queue
+                .push(crate::persistent_state::TelegramMessage {
+                    chat_id: "123".to_string(),
+                    text: "expired".to_string(),
+                    timestamp: chrono::Local::now() - chrono::TimeDelta::hours(2),
+                })
+        })
+        .await
+        .unwrap();
+
+    State::run_job(state.clone(), Job::Telegram).await.unwrap();
+    let persistent_state = state.lock().await.persistent_state().await;
+    assert!(persistent_state.telegram_queue.is_empty());
+}
+
+#[actix_web::test]
+async fn test_maintenance_job() {
+    let (mut config, _path) = job_test_config("maintenance");
+    config.client_history_max_age = Some(std::time::Duration::from_secs(60 * 60));
+    let state = State::new(&config).await.unwrap();
+    state
+        .lock()
+        .await
+        .persistent_state
+        .update(|persistent_state| {
+            persistent_state.record_client_session(
+                "aa:bb:cc:dd:ee:ff",
+                chrono::Utc::now() - chrono::TimeDelta::days(1),
+                5,
+            )
+        })
+        .await
+        .unwrap();
+
+    State::run_job(state.clone(), Job::Maintenance)
+        .await
+        .unwrap();
+    let persistent_state = state.lock().await.persistent_state().await;
+    assert!(persistent_state.client_sessions.is_empty());
+}
+
+#[actix_web::test]
+async fn test_refresh_whitelist() {
+    let (ipset, log) = crate::ipset::fake_ipset("whitelist-refresh", "exit 0");
+    let mut config = crate::config::test_config();
+    config.no_shaping_ips = ["10.11.2.5", "10.11.2.4"].map(String::from).into();
+
+    refresh_whitelist(&config, |name| {
+        crate::ipset::IPSet::with_command(name, &ipset)
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "-exist add acl 10.11.2.4 timeout 3600\n-exist add no_shape 10.11.2.4 timeout 3600\n\
+         -exist add acl 10.11.2.5 timeout 3600\n-exist add no_shape 10.11.2.5 timeout 3600\n"
+    );
+
+    let (ipset, _) = crate::ipset::fake_ipset("whitelist-refresh-failure", "exit 1");
+    assert!(refresh_whitelist(&config, |name| {
+        crate::ipset::IPSet::with_command(name, &ipset)
+    })
+    .await
+    .is_err());
+}
+
+#[actix_web::test]
+async fn test_internet_down_command_on_transition() {
+    let path = crate::persistent_state::TempStatePath::new("internet-down");
+    let persistent_state = crate::persistent_state::PersistentStateGuard::load_from_yaml(&path);
+    let command_log = crate::command_log::CommandLog::default();
+    let (command, log) = crate::util::fake_command("internet-down", "exit 0");
+    let mut config = crate::config::test_config().ping;
+    config.on_internet_down_command = Some(format!("{} down", command.display()));
+    let shell = std::path::Path::new("/bin/sh");
+
+    for success in [true, false, false, false, true, false] {
+        let connectivity = Connectivity::without_rtt(success);
+        record_ping_result(
+            &config,
+            shell,
+            &persistent_state,
+            &command_log,
+            connectivity,
+        )
+        .await
+        .unwrap();
+    }
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "down\ndown\n");
+
+    let (command, _) = crate::util::fake_command("internet-down-timeout", "sleep 5");
+    config.on_internet_down_command = Some(format!("{} down", command.display()));
+    config.on_internet_down_timeout = std::time::Duration::from_millis(100);
+    let started = std::time::Instant::now();
+    for success in [true, false] {
+        let connectivity = Connectivity::without_rtt(success);
+        record_ping_result(
+            &config,
+            shell,
+            &persistent_state,
+            &command_log,
+            connectivity,
+        )
+        .await
+        .unwrap();
+    }
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[actix_web::test]
+async fn test_ping_rtt_recorded() {
+    let path = crate::persistent_state::TempStatePath::new("ping-rtt");
+    let persistent_state = crate::persistent_state::PersistentStateGuard::load_from_yaml(&path);
+    let command_log = crate::command_log::CommandLog::default();
+    let config = crate::config::test_config().ping;
+    let shell = std::path::Path::new("/bin/sh");
+
+    let connectivity = Connectivity {
+        available: true,
+        rtt: Some(std::time::Duration::from_micros(23500)),
+    };
+    record_ping_result(
+        &config,
+        shell,
+        &persistent_state,
+        &command_log,
+        connectivity,
+    )
+    .await
+    .unwrap();
+    assert_eq!(persistent_state.get().await.last_ping_rtt_ms, Some(23.5));
+
+    let connectivity = Connectivity::without_rtt(false);
+    record_ping_result(
+        &config,
+        shell,
+        &persistent_state,
+        &command_log,
+        connectivity,
+    )
+    .await
+    .unwrap();
+    assert_eq!(persistent_state.get().await.last_ping_rtt_ms, None);
+}
+
+#[actix_web::test]
+async fn test_acl_size_alert() {
+    let (mut config, _path) = job_test_config("acl-size");
+    let persistent_state = crate::persistent_state::PersistentStateGuard::load_from_yaml(
+        &config.persistent_state_path,
+    );
+    let counters = crate::metrics::Counters::default();
+    assert!(!check_acl_size(&config, &persistent_state, &counters, 1000)
+        .await
+        .unwrap());
+
+    config.max_acl_size = Some(10);
+    config.acl_size_alert_ratio = 0.8;
+    let mut alerts = Vec::new();
+    for acl_size in [7, 8, 9, 10, 5, 8] {
+        alerts.push(
+            check_acl_size(&config, &persistent_state, &counters, acl_size)
+                .await
+                .unwrap(),
+        );
+    }
+    assert_eq!(alerts, [false, true, false, false, false, true]);
+    assert!(persistent_state.get().await.acl_size_alerted);
+
+    check_acl_size(&config, &persistent_state, &counters, 5)
+        .await
+        .unwrap();
+    let (first, second) = tokio::join!(
+        check_acl_size(&config, &persistent_state, &counters, 9),
+        check_acl_size(&config, &persistent_state, &counters, 9)
+    );
+    assert!(first.unwrap() ^ second.unwrap());
 }
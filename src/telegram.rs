@@ -2,19 +2,171 @@
 use serde::{Deserialize, Serialize};
 use slog_scope::info;
 
+fn default_api_url() -> String {
+    "https://api.telegram.org".to_string()
+}
+
+fn default_send_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(10)
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Chat receiving alerts. Plain chat ID receives all alerts, otherwise only ones with
+/// severity not lower than `min_severity`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Chat {
+    Plain(String),
+    Tagged {
+        id: String,
+        #[serde(default)]
+        min_severity: Severity,
+    },
+}
+
+impl Chat {
+    fn id(&self) -> &str {
+        match self {
+            Self::Plain(id) => id,
+            Self::Tagged { id, .. } => id,
+        }
+    }
+
+    /// Strips whitespace around chat ID, which quoted YAML strings may keep
+    pub fn normalize(&mut self) {
+        let id = match self {
+            Self::Plain(id) => id,
+            Self::Tagged { id, .. } => id,
+        };
+        *id = id.trim().to_string();
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_chat_id(self.id())
+    }
+
+    fn accepts(&self, severity: Severity) -> bool {
+        match self {
+            Self::Plain(_) => true,
+            Self::Tagged { min_severity, .. } => severity >= *min_severity,
+        }
+    }
+}
+
+/// Checks that chat ID is numeric, negative for groups and channels, or `@channelusername`
+fn validate_chat_id(id: &str) -> Result<()> {
+    if let Some(username) = id.strip_prefix('@') {
+        let is_valid = (5..=32).contains(&username.len())
+            && username.starts_with(|v: char| v.is_ascii_alphabetic())
+            && username
+                .chars()
+                .all(|v| v.is_ascii_alphanumeric() || v == '_');
+        if !is_valid {
+            bail!("Invalid telegram channel username {:?}", id);
+        }
+        return Ok(());
+    }
+
+    let digits = id.strip_prefix('-').unwrap_or(id);
+    if digits.is_empty() || !digits.chars().all(|v| v.is_ascii_digit()) || digits.starts_with('0') {
+        bail!(
+            "Telegram chat ID must be a number or @channelusername, got {:?}",
+            id
+        );
+    }
+    // Supergroup and channel IDs are -100 followed by 10 digits
+    if !id.starts_with('-') && digits.starts_with("100") && digits.len() == 13 {
+        bail!(
+            "Telegram chat ID {:?} looks like supergroup one without leading minus",
+            id
+        );
+    }
+    Ok(())
+}
+
+/// Chat IDs which should receive alert of given severity
+fn recipients(chats: &[Chat], severity: Severity) -> Vec<&str> {
+    chats
+        .iter()
+        .filter(|chat| chat.accepts(severity))
+        .map(Chat::id)
+        .collect()
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Telegram {
+    #[serde(default)]
     pub bot_token: String,
+    /// File with bot token. Takes precedence over `bot_token`
+    #[serde(default)]
+    pub bot_token_file: Option<std::path::PathBuf>,
     #[serde(with = "humantime_serde")]
     pub message_timeout: std::time::Duration,
     pub retry_crontab: String,
+    /// Timeout of message sending request. Timed out messages are queued for retry
+    #[serde(with = "humantime_serde", default = "default_send_timeout")]
+    pub send_timeout: std::time::Duration,
+    /// Bot API server, e.g. self-hosted one
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+    /// Prepended to every message to tell which box sent it. System hostname if not set
+    #[serde(default)]
+    pub site_name: Option<String>,
+}
+
+/// Number of messages sent and failed to send, including retries
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SendCounts {
+    pub sends: u64,
+    pub failures: u64,
+}
+
+fn system_hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
 }
 
 impl Telegram {
-    async fn try_send_message(&self, chat_id: &str, text: &str) -> Result<()> {
+    pub fn load_secrets(&mut self) -> Result<()> {
+        if let Some(path) = &self.bot_token_file {
+            self.bot_token = crate::config::read_secret_file(path)?;
+        }
+        if self.bot_token.is_empty() {
+            bail!("Either telegram.bot_token or telegram.bot_token_file must be set");
+        }
+        Ok(())
+    }
+
+    async fn try_send_message(
+        &self,
+        counters: &crate::metrics::Counters,
+        chat_id: &str,
+        text: &str,
+    ) -> Result<()> {
+        let r = self.post_message(chat_id, text).await;
+        if r.is_ok() {
+            counters.inc_telegram_sends();
+        } else {
+            counters.inc_telegram_send_failures();
+        }
+        r
+    }
+
+    async fn post_message(&self, chat_id: &str, text: &str) -> Result<()> {
         slog_scope::info!("Sending message to telegram chat {}: {}", chat_id, text);
-        let url = format!("https://api.telegram.org/bot{}/sendMessage", self.bot_token);
-        let client = reqwest::Client::new();
+        let url = format!("{}/bot{}/sendMessage", self.api_url, self.bot_token);
+        let client = reqwest::Client::builder()
+            .timeout(self.send_timeout)
+            .build()?;
         let r = client
             .post(&url)
             .json(&serde_json::json!({
@@ -26,6 +178,13 @@ async fn try_send_message(&self, chat_id: &str, text: &str) -> Result<()> {
 
         let r = match r {
             Ok(r) => r,
+            Err(err) if err.is_timeout() => {
+                slog_scope::error!(
+                    "Telegram didn't respond in {:?}, message will be retried",
+                    self.send_timeout
+                );
+                return Err(err.into());
+            }
             Err(err) => {
                 slog_scope::error!("Failed to send message to telegram: {}", err);
                 return Err(err.into());
@@ -41,14 +200,44 @@ async fn try_send_message(&self, chat_id: &str, text: &str) -> Result<()> {
         Ok(())
     }
 
+    fn site_name(&self) -> Option<String> {
+        self.site_name.clone().or_else(system_hostname)
+    }
+
+    fn format_message(&self, text: &str) -> String {
+        match self.site_name() {
+            Some(site_name) => format!("[{site_name}] {text}"),
+            None => text.to_string(),
+        }
+    }
+
+    /// Checks that Telegram API is reachable and accepts the bot token
+    pub async fn check_reachable(&self) -> Result<()> {
+        let url = format!("{}/bot{}/getMe", self.api_url, self.bot_token);
+        let r = reqwest::Client::new()
+            .get(&url)
+            .timeout(std::time::Duration::from_secs(5))
+            .send()
+            .await
+            // URL contains the bot token
+            .map_err(|err| err.without_url())?;
+        if !r.status().is_success() {
+            bail!("Telegram API responded with {}", r.status());
+        }
+        Ok(())
+    }
+
     pub async fn send_message(
         &self,
         persistent_state: &crate::persistent_state::PersistentStateGuard,
-        chat_ids: &[String],
+        counters: &crate::metrics::Counters,
+        chats: &[Chat],
+        severity: Severity,
         text: &str,
     ) {
-        for chat_id in chat_ids {
-            let r = self.try_send_message(chat_id, text).await;
+        let text = &self.format_message(text);
+        for chat_id in recipients(chats, severity) {
+            let r = self.try_send_message(counters, chat_id, text).await;
             if r.is_err() {
                 let r = persistent_state
                     .update(|persistent_state| {
@@ -71,6 +260,8 @@ pub async fn send_message(
     pub async fn process_queue(
         &self,
         persistent_state: &crate::persistent_state::PersistentStateGuard,
+        counters: &crate::metrics::Counters,
+        timezone: Option<chrono_tz::Tz>,
     ) -> Result<()> {
         info!("Processing telegram queue");
         let mut queue = persistent_state
@@ -91,9 +282,12 @@ pub async fn process_queue(
             let text = format!(
                 "{}\n\nЭто сообщение было отправлено в {}.",
                 message.text,
-                message.timestamp.format("%Y-%m-%d %H:%M:%S")
+                crate::config::to_local_time(&message.timestamp, timezone)
+                    .format("%Y-%m-%d %H:%M:%S")
             );
-            let r = self.try_send_message(&message.chat_id, &text).await;
+            let r = self
+                .try_send_message(counters, &message.chat_id, &text)
+                .await;
             if r.is_err() {
                 new_queue.push(message);
             }
@@ -106,3 +300,187 @@ pub async fn process_queue(
         Ok(())
     }
 }
+
+#[test]
+fn test_bot_token_file_precedence() {
+    let path = crate::persistent_state::TempStatePath::new("bot-token");
+    std::fs::write(&path, "123456:FROM-FILE\n").unwrap();
+
+    let mut telegram: Telegram = serde_yaml::from_str(&format!(
+        "bot_token: 123456:INLINE\nbot_token_file: {}\nmessage_timeout: 1h\nretry_crontab: '* * * * * *'",
+        path.display()
+    ))
+    .unwrap();
+    telegram.load_secrets().unwrap();
+    assert_eq!(telegram.bot_token, "123456:FROM-FILE");
+
+    let mut telegram: Telegram = serde_yaml::from_str(
+        "bot_token: 123456:INLINE\nmessage_timeout: 1h\nretry_crontab: '* * * * * *'",
+    )
+    .unwrap();
+    telegram.load_secrets().unwrap();
+    assert_eq!(telegram.bot_token, "123456:INLINE");
+
+    let mut telegram: Telegram =
+        serde_yaml::from_str("message_timeout: 1h\nretry_crontab: '* * * * * *'").unwrap();
+    assert!(telegram.load_secrets().is_err());
+}
+
+#[test]
+fn test_recipients_by_severity() {
+    let chats: Vec<Chat> = serde_yaml::from_str(
+        r#"
+- "-100general"
+- id: "-100oncall"
+  min_severity: Critical
+- id: "-100ops"
+  min_severity: Warning
+"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        recipients(&chats, Severity::Critical),
+        ["-100general", "-100oncall", "-100ops"]
+    );
+    assert_eq!(
+        recipients(&chats, Severity::Warning),
+        ["-100general", "-100ops"]
+    );
+    assert_eq!(recipients(&chats, Severity::Info), ["-100general"]);
+}
+
+#[test]
+fn test_site_name_prefix() {
+    let mut telegram: Telegram = serde_yaml::from_str(
+        "bot_token: 123456:TOKEN\nmessage_timeout: 1h\nretry_crontab: '* * * * * *'\nsite_name: ratzek",
+    )
+    .unwrap();
+    assert_eq!(
+        telegram.format_message("Низкий остаток"),
+        "[ratzek] Низкий остаток"
+    );
+
+    telegram.site_name = None;
+    assert_eq!(telegram.site_name(), system_hostname());
+}
+
+#[actix_web::test]
+async fn test_send_timeout_requeues() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // Accepts connection and never responds
+    std::thread::spawn(move || {
+        let _stream = listener.accept().unwrap();
+        std::thread::sleep(std::time::Duration::from_secs(5));
+    });
+
+    let telegram: Telegram = serde_yaml::from_str(&format!(
+        "bot_token: 123456:TOKEN\nmessage_timeout: 1h\nretry_crontab: '* * * * * *'\n\
+         send_timeout: 200ms\napi_url: http://127.0.0.1:{port}"
+    ))
+    .unwrap();
+    let path = crate::persistent_state::TempStatePath::new("telegram-timeout");
+    let persistent_state = crate::persistent_state::PersistentStateGuard::load_from_yaml(&path);
+    persistent_state
+        .update(|persistent_state| {
+            persistent_state
+                .telegram_queue
+                .push(crate::persistent_state::TelegramMessage {
+                    chat_id: "123".to_string(),
+                    text: "hello".to_string(),
+                    timestamp: chrono::Local::now(),
+                })
+        })
+        .await
+        .unwrap();
+
+    let started = std::time::Instant::now();
+    telegram
+        .process_queue(&persistent_state, &Default::default(), None)
+        .await
+        .unwrap();
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    let queue = persistent_state.get().await.telegram_queue;
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].text, "hello");
+}
+
+#[test]
+fn test_validate_chat_id() {
+    for id in [
+        "123456789",
+        "-987654321",
+        "-1001234567890",
+        "@ratzek_alerts",
+    ] {
+        assert!(validate_chat_id(id).is_ok(), "{id}");
+    }
+    for id in [
+        "",
+        "-",
+        "12a45",
+        "0123",
+        "+123456",
+        "1001234567890",
+        "@",
+        "@abc",
+        "@1channel",
+        "@bad-name",
+    ] {
+        assert!(validate_chat_id(id).is_err(), "{id}");
+    }
+
+    let mut chat = Chat::Plain(" 123456789 ".to_string());
+    chat.normalize();
+    assert_eq!(chat.id(), "123456789");
+    assert!(chat.validate().is_ok());
+}
+
+#[actix_web::test]
+async fn test_send_counts() {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // Accepts the first message and rejects the second one
+    std::thread::spawn(move || {
+        for status in ["200 OK", "400 Bad Request"] {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = r#"{"ok":false}"#;
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        }
+    });
+
+    let telegram: Telegram = serde_yaml::from_str(&format!(
+        "bot_token: 123456:TOKEN\nmessage_timeout: 1h\nretry_crontab: '* * * * * *'\n\
+         api_url: http://127.0.0.1:{port}"
+    ))
+    .unwrap();
+    let counters = crate::metrics::Counters::default();
+
+    telegram
+        .try_send_message(&counters, "123", "first")
+        .await
+        .unwrap();
+    assert!(telegram
+        .try_send_message(&counters, "123", "second")
+        .await
+        .is_err());
+    let counters = counters.snapshot();
+    assert_eq!(
+        (counters.telegram_sends, counters.telegram_send_failures),
+        (1, 1)
+    );
+}
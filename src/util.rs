@@ -0,0 +1,175 @@
+use slog_scope::warn;
+
+/// Whether failed attempt is worth repeating
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retry {
+    Retryable,
+    Permanent,
+}
+
+/// Missing or non-executable programs won't appear between attempts, other failures may be
+/// transient
+pub fn classify_spawn_error(err: &anyhow::Error) -> Retry {
+    let is_permanent = err.chain().any(|v| {
+        v.downcast_ref::<std::io::Error>().is_some_and(|v| {
+            matches!(
+                v.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+            )
+        })
+    });
+    if is_permanent {
+        Retry::Permanent
+    } else {
+        Retry::Retryable
+    }
+}
+
+/// Runs `f` up to `count` times, but at least once, until it succeeds or fails with error
+/// `classify` deems permanent. Waits `interval` between attempts, multiplying it by `backoff`
+/// after each one. Returns the last error if all attempts fail
+pub async fn retry<T, F, Fut>(
+    count: u32,
+    interval: std::time::Duration,
+    backoff: f64,
+    classify: impl Fn(&anyhow::Error) -> Retry,
+    mut f: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let count = count.max(1);
+    let mut interval = interval;
+    let mut attempt = 1;
+    loop {
+        let err = match f().await {
+            Ok(v) => return Ok(v),
+            Err(err) => err,
+        };
+        if classify(&err) == Retry::Permanent {
+            warn!("Attempt {attempt} of {count} failed permanently, not retrying: {err:#}");
+            return Err(err);
+        }
+        if attempt >= count {
+            return Err(err);
+        }
+        warn!("Attempt {attempt} of {count} failed, retrying in {interval:?}: {err:#}");
+        tokio::time::sleep(interval).await;
+        interval = interval.mul_f64(backoff);
+        attempt += 1;
+    }
+}
+
+/// Compares secrets in time which doesn't depend on position of the first difference, so
+/// they can't be guessed byte by byte. Only length may leak
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(0u8, |diff, (a, b)| std::hint::black_box(diff | (a ^ b)));
+    diff == 0
+}
+
+/// Creates a script which logs its arguments and runs given shell `body`. Returns paths of
+/// the script and of the log
+#[cfg(test)]
+pub fn fake_command(test_name: &str, body: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!(
+        "ala-archa-http-backend-{}-{}",
+        test_name,
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("calls.log");
+    let _ = std::fs::remove_file(&log);
+    let script = dir.join("command");
+    std::fs::write(
+        &script,
+        format!("#!/bin/sh\necho \"$@\" >> {}\n{}\n", log.display(), body),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    (script, log)
+}
+
+#[cfg(test)]
+async fn run_retry(count: u32, results: Vec<anyhow::Result<u32>>) -> (anyhow::Result<u32>, usize) {
+    let results = std::cell::RefCell::new(results.into_iter());
+    let attempts = std::cell::Cell::new(0);
+    let r = retry(
+        count,
+        std::time::Duration::from_millis(1),
+        2.0,
+        classify_spawn_error,
+        || {
+            attempts.set(attempts.get() + 1);
+            let result = results.borrow_mut().next().unwrap();
+            async move { result }
+        },
+    )
+    .await;
+    (r, attempts.get())
+}
+
+#[actix_web::test]
+async fn test_retry_success() {
+    let (r, attempts) = run_retry(3, vec![Ok(1)]).await;
+    assert_eq!(r.unwrap(), 1);
+    assert_eq!(attempts, 1);
+
+    let (r, attempts) = run_retry(0, vec![Ok(1)]).await;
+    assert_eq!(r.unwrap(), 1);
+    assert_eq!(attempts, 1);
+}
+
+#[actix_web::test]
+async fn test_retry_retryable() {
+    let (r, attempts) = run_retry(
+        3,
+        vec![
+            Err(anyhow::anyhow!("busy")),
+            Err(anyhow::anyhow!("busy")),
+            Ok(2),
+        ],
+    )
+    .await;
+    assert_eq!(r.unwrap(), 2);
+    assert_eq!(attempts, 3);
+
+    let (r, attempts) = run_retry(
+        2,
+        vec![Err(anyhow::anyhow!("first")), Err(anyhow::anyhow!("last"))],
+    )
+    .await;
+    assert_eq!(r.unwrap_err().to_string(), "last");
+    assert_eq!(attempts, 2);
+}
+
+#[actix_web::test]
+async fn test_retry_permanent() {
+    let not_found = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound))
+        .context("Failed to spawn");
+    assert_eq!(classify_spawn_error(&not_found), Retry::Permanent);
+    assert_eq!(
+        classify_spawn_error(&anyhow::anyhow!("exit code 1")),
+        Retry::Retryable
+    );
+
+    let (r, attempts) = run_retry(3, vec![Err(not_found), Ok(1)]).await;
+    assert!(r.is_err());
+    assert_eq!(attempts, 1);
+}
+
+#[test]
+fn test_constant_time_eq() {
+    assert!(constant_time_eq(b"secret", b"secret"));
+    assert!(!constant_time_eq(b"secret", b"secreT"));
+    assert!(!constant_time_eq(b"secret", b"secret2"));
+    assert!(constant_time_eq(b"", b""));
+}